@@ -0,0 +1,604 @@
+use std::{cell::Cell, collections::BTreeMap, fmt::Write as _, fs, path::PathBuf, str::FromStr};
+
+use bevy::utils::Instant;
+
+use crate::{
+    cli::Flags,
+    headless,
+    replay::{self, Dataset, Driver, Pair, ReplayErrors, Sample, DATASET_FLAGS},
+    scenario::Scenario,
+    sobol::Bounds,
+    sweep::Parameter,
+};
+
+/*
+The driver parameters fitted when none are given, with the bounds they're searched within. Wide
+enough for drivers far from the defaults, velocities and accelerations per tick like the flags'.
+ */
+const DEFAULT_INPUTS: [(Parameter, f32, f32); 5] = [
+    (Parameter::ReactionTime, 0.1, 2.5),
+    (Parameter::MaxVelocity, 0.02, 0.5),
+    (Parameter::Acceleration, 0.0005, 0.01),
+    (Parameter::Deceleration, 0.002, 0.05),
+    (Parameter::BreakDistance, 1.0, 15.0),
+];
+/*
+Nelder-Mead's reflection, expansion, contraction and shrink coefficients (the standard ones)
+ */
+const REFLECTION: f64 = 1.0;
+const EXPANSION: f64 = 2.0;
+const CONTRACTION: f64 = 0.5;
+const SHRINK: f64 = 0.5;
+/*
+The initial simplex's size, as a share of every input's bounds, and the size under which it's
+considered converged and restarted around its best vertex with the next (smaller) size
+ */
+const INITIAL_STEPS: [f64; 3] = [0.25, 0.1, 0.03];
+const CONVERGED_SIZE: f64 = 1e-4;
+
+/**
+ * What the fit minimizes
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    Spacing,
+    Speed,
+    /**
+     * Both RMSEs, each divided by the RMS of what it's measured on so neither dominates
+     */
+    Both,
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spacing" => Ok(Fit::Spacing),
+            "speed" => Ok(Fit::Speed),
+            "both" => Ok(Fit::Both),
+            _ => Err(format!(
+                "expected 'spacing', 'speed' or 'both', got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+pub struct CalibrationConfig {
+    pub pairs: Vec<Pair>,
+    /**
+     * Where the search starts from, and the values of the inputs that aren't fitted
+     */
+    pub driver: Driver,
+    pub inputs: Vec<(Parameter, Bounds)>,
+    pub fit: Fit,
+    /**
+     * Whether every follower gets its own driver, otherwise one is fitted to every pair
+     */
+    pub per_vehicle: bool,
+    /**
+     * Per driver
+     */
+    pub max_evaluations: usize,
+    pub threads: usize,
+    pub output: Option<PathBuf>,
+}
+
+impl CalibrationConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut known_flags = vec![
+            "scenario",
+            "inputs",
+            "fit",
+            "per-vehicle",
+            "max-evaluations",
+            "threads",
+            "output",
+        ];
+        known_flags.extend(DATASET_FLAGS);
+        known_flags.extend(DEFAULT_INPUTS.map(|(parameter, _, _)| parameter.flag()));
+        let flags = Flags::parse(args, &known_flags)?;
+        let names = flags.get_optional::<String>("inputs")?;
+        let inputs = DEFAULT_INPUTS
+            .into_iter()
+            .filter(|(parameter, _, _)| {
+                names.as_ref().is_none_or(|names| {
+                    names.split(',').any(|name| name.trim() == parameter.flag())
+                })
+            })
+            .map(|(parameter, min, max)| {
+                let bounds = flags.get(parameter.flag(), Bounds { min, max })?;
+                parameter.check(bounds.min)?;
+                parameter.check(bounds.max)?;
+                Ok((parameter, bounds))
+            })
+            .collect::<Result<Vec<(Parameter, Bounds)>, String>>()?;
+        if inputs.is_empty() {
+            return Err(format!(
+                "'--inputs' must be some of: {}",
+                DEFAULT_INPUTS
+                    .map(|(parameter, _, _)| parameter.flag())
+                    .join(",")
+            ));
+        }
+        let config = CalibrationConfig {
+            pairs: Dataset::pairs_from_flags(&flags)?,
+            driver: Driver::from_scenario(&flags.get("scenario", Scenario::default())?),
+            inputs,
+            fit: flags.get("fit", Fit::Both)?,
+            per_vehicle: flags.get("per-vehicle", true)?,
+            max_evaluations: flags.get("max-evaluations", 400)?,
+            threads: flags.get("threads", headless::default_threads())?,
+            output: flags.get_optional("output")?,
+        };
+        if config.max_evaluations < config.inputs.len() + 1 {
+            return Err(format!(
+                "'--max-evaluations' must be at least {} to build the first simplex",
+                config.inputs.len() + 1
+            ));
+        }
+        Ok(config)
+    }
+
+    /**
+     * The driver at a point of the unit box, every coordinate a share of its input's bounds
+     */
+    fn driver_at(&self, point: &[f64]) -> Driver {
+        let mut driver = self.driver;
+        for ((parameter, bounds), x) in self.inputs.iter().zip(point) {
+            driver.set(
+                *parameter,
+                bounds.min + (bounds.max - bounds.min) * *x as f32,
+            );
+        }
+        driver
+    }
+
+    /**
+     * Where `driver` is in the unit box, clamped into it
+     */
+    fn point_of(&self, driver: &Driver) -> Vec<f64> {
+        self.inputs
+            .iter()
+            .map(|(parameter, bounds)| {
+                ((driver.get(*parameter) - bounds.min) / (bounds.max - bounds.min)).clamp(0.0, 1.0)
+                    as f64
+            })
+            .collect()
+    }
+}
+
+fn rms(values: impl Iterator<Item = f32>) -> f64 {
+    let (count, sum) = values.fold((0, 0.0), |(count, sum), value| {
+        (count + 1, sum + (value as f64).powi(2))
+    });
+    if count == 0 {
+        return 0.0;
+    }
+    (sum / count as f64).sqrt()
+}
+
+/**
+ * The error `fit` measures, `scales` are the recorded spacings' and velocities' RMS
+ */
+fn cost(fit: Fit, errors: &ReplayErrors, scales: (f64, f64)) -> f64 {
+    let spacing = errors.spacing_rmse as f64;
+    let speed = errors.speed_rmse as f64;
+    match fit {
+        Fit::Spacing => spacing,
+        Fit::Speed => speed,
+        Fit::Both => spacing / scales.0.max(f64::EPSILON) + speed / scales.1.max(f64::EPSILON),
+    }
+}
+
+fn errors_of(pairs: &[&Pair], driver: &Driver) -> ReplayErrors {
+    let replays = pairs
+        .iter()
+        .map(|pair| replay::replay(pair, driver))
+        .collect::<Vec<Vec<Sample>>>();
+    ReplayErrors::of(replays.iter().flatten())
+}
+
+/**
+ * Nelder-Mead's downhill simplex over the unit box, clamping every point into it. The simplex
+ * starts around `start` and, whenever it has converged, is rebuilt smaller around its best vertex
+ * to escape the collapses it's prone to. Returns the best point and its cost.
+ */
+fn nelder_mead(
+    start: Vec<f64>,
+    max_evaluations: usize,
+    cost: impl Fn(&[f64]) -> f64,
+) -> (Vec<f64>, f64) {
+    let dimensions = start.len();
+    let evaluations = Cell::new(0);
+    let evaluate = |point: Vec<f64>| {
+        evaluations.set(evaluations.get() + 1);
+        let point = point
+            .into_iter()
+            .map(|x| x.clamp(0.0, 1.0))
+            .collect::<Vec<f64>>();
+        let value = cost(&point);
+        // A diverged replay is as bad as it gets, not a NaN the comparisons would ignore
+        (point, if value.is_nan() { f64::INFINITY } else { value })
+    };
+    let mut best = evaluate(start);
+    for step in INITIAL_STEPS {
+        if evaluations.get() + dimensions > max_evaluations {
+            break;
+        }
+        let mut simplex = vec![best.clone()];
+        for i in 0..dimensions {
+            let mut vertex = best.0.clone();
+            // Towards the middle of the box, so the vertex isn't clamped back onto the best one
+            vertex[i] += if vertex[i] > 0.5 { -step } else { step };
+            simplex.push(evaluate(vertex));
+        }
+        while evaluations.get() < max_evaluations {
+            simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let size = simplex[1..]
+                .iter()
+                .flat_map(|(vertex, _)| vertex.iter().zip(simplex[0].0.iter()))
+                .map(|(x, best)| (x - best).abs())
+                .fold(0.0, f64::max);
+            if size < CONVERGED_SIZE {
+                break;
+            }
+            let centroid = (0..dimensions)
+                .map(|i| {
+                    simplex[..dimensions]
+                        .iter()
+                        .map(|(vertex, _)| vertex[i])
+                        .sum::<f64>()
+                        / dimensions as f64
+                })
+                .collect::<Vec<f64>>();
+            let worst = simplex[dimensions].clone();
+            let towards = |coefficient: f64| {
+                centroid
+                    .iter()
+                    .zip(worst.0.iter())
+                    .map(|(c, w)| c + coefficient * (c - w))
+                    .collect::<Vec<f64>>()
+            };
+            let reflected = evaluate(towards(REFLECTION));
+            if reflected.1 < simplex[0].1 {
+                let expanded = evaluate(towards(EXPANSION));
+                simplex[dimensions] = if expanded.1 < reflected.1 {
+                    expanded
+                } else {
+                    reflected
+                };
+                continue;
+            }
+            if reflected.1 < simplex[dimensions - 1].1 {
+                simplex[dimensions] = reflected;
+                continue;
+            }
+            let contracted = if reflected.1 < worst.1 {
+                evaluate(towards(REFLECTION * CONTRACTION))
+            } else {
+                evaluate(towards(-CONTRACTION))
+            };
+            if contracted.1 < reflected.1.min(worst.1) {
+                simplex[dimensions] = contracted;
+                continue;
+            }
+            let best_vertex = simplex[0].0.clone();
+            for vertex in simplex[1..].iter_mut() {
+                let shrunk = best_vertex
+                    .iter()
+                    .zip(vertex.0.iter())
+                    .map(|(b, x)| b + SHRINK * (x - b))
+                    .collect::<Vec<f64>>();
+                *vertex = evaluate(shrunk);
+            }
+        }
+        let simplex_best = simplex
+            .into_iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        if simplex_best.1 < best.1 {
+            best = simplex_best;
+        }
+    }
+    best
+}
+
+/**
+ * A driver fitted to some pairs, with the errors of the starting driver (`before`) and of the
+ * fitted one (`after`)
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibrated {
+    pub driver: Driver,
+    pub before: ReplayErrors,
+    pub after: ReplayErrors,
+}
+
+fn calibrate(config: &CalibrationConfig, pairs: &[&Pair]) -> Calibrated {
+    let recorded = pairs
+        .iter()
+        .flat_map(|pair| pair.follower_points.iter())
+        .map(|point| point.velocity);
+    let spacings = pairs
+        .iter()
+        .flat_map(|pair| replay::replay(pair, &config.driver))
+        .map(|sample| sample.recorded_spacing());
+    let scales = (rms(spacings), rms(recorded));
+    let (point, _) = nelder_mead(
+        config.point_of(&config.driver),
+        config.max_evaluations,
+        |point| {
+            cost(
+                config.fit,
+                &errors_of(pairs, &config.driver_at(point)),
+                scales,
+            )
+        },
+    );
+    let driver = config.driver_at(&point);
+    Calibrated {
+        driver,
+        before: errors_of(pairs, &config.driver),
+        after: errors_of(pairs, &driver),
+    }
+}
+
+fn mean_and_sd(values: &[f32]) -> (f32, f32) {
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    if values.len() < 2 {
+        return (mean, 0.0);
+    }
+    let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / (n - 1.0);
+    (mean, variance.sqrt())
+}
+
+/**
+ * Fits the driver parameters to the recorded followers by replaying them behind their recorded
+ * leaders, one driver per follower (in parallel) or one for all, and prints the calibrated values
+ * and the errors before and after
+ */
+pub fn run(config: &CalibrationConfig) -> std::io::Result<()> {
+    let started = Instant::now();
+    let mut groups = BTreeMap::<u64, Vec<&Pair>>::new();
+    for pair in config.pairs.iter() {
+        let key = if config.per_vehicle { pair.follower } else { 0 };
+        groups.entry(key).or_default().push(pair);
+    }
+    let groups = groups.into_iter().collect::<Vec<(u64, Vec<&Pair>)>>();
+    eprintln!(
+        "fitting {} driver(s) to {} pairs on {} threads",
+        groups.len(),
+        config.pairs.len(),
+        config.threads
+    );
+    let results = headless::run_in_parallel(&groups, config.threads, |(_, pairs)| {
+        calibrate(config, pairs)
+    });
+
+    let mut header = format!("{:>8} {:>8} |", "follower", "samples");
+    for (parameter, _) in config.inputs.iter() {
+        write!(header, " {:>14}", parameter.flag()).unwrap();
+    }
+    write!(
+        header,
+        " | {:>9} {:>9} | {:>9} {:>9}",
+        "spacing", "(before)", "speed", "(before)"
+    )
+    .unwrap();
+    println!("{}", header);
+    let mut csv = String::from("follower,samples");
+    for (parameter, _) in config.inputs.iter() {
+        write!(csv, ",{}", parameter.column()).unwrap();
+    }
+    csv.push_str(",spacing_rmse,spacing_rmse_before,speed_rmse,speed_rmse_before\n");
+    for ((follower, _), calibrated) in groups.iter().zip(results.iter()) {
+        let follower = if config.per_vehicle {
+            follower.to_string()
+        } else {
+            "all".to_string()
+        };
+        let mut row = format!("{:>8} {:>8} |", follower, calibrated.after.samples);
+        write!(csv, "{},{}", follower, calibrated.after.samples).unwrap();
+        for (parameter, _) in config.inputs.iter() {
+            let value = calibrated.driver.get(*parameter);
+            write!(row, " {:>14.4}", value).unwrap();
+            write!(csv, ",{}", value).unwrap();
+        }
+        println!(
+            "{} | {:>9.3} {:>9.3} | {:>9.3} {:>9.3}",
+            row,
+            calibrated.after.spacing_rmse,
+            calibrated.before.spacing_rmse,
+            calibrated.after.speed_rmse,
+            calibrated.before.speed_rmse
+        );
+        writeln!(
+            csv,
+            ",{},{},{},{}",
+            calibrated.after.spacing_rmse,
+            calibrated.before.spacing_rmse,
+            calibrated.after.speed_rmse,
+            calibrated.before.speed_rmse
+        )
+        .unwrap();
+    }
+
+    // Every follower's samples weigh the same in the overall errors, whichever driver they got
+    let pooled = |errors: &dyn Fn(&Calibrated) -> ReplayErrors| {
+        let (mut samples, mut spacing, mut speed) = (0, 0.0, 0.0);
+        for calibrated in results.iter() {
+            let errors = errors(calibrated);
+            samples += errors.samples;
+            spacing += (errors.spacing_rmse as f64).powi(2) * errors.samples as f64;
+            speed += (errors.speed_rmse as f64).powi(2) * errors.samples as f64;
+        }
+        let samples = samples.max(1) as f64;
+        ((spacing / samples).sqrt(), (speed / samples).sqrt())
+    };
+    let (spacing_before, speed_before) = pooled(&|calibrated| calibrated.before);
+    let (spacing_after, speed_after) = pooled(&|calibrated| calibrated.after);
+    println!(
+        "overall: spacing rmse {:.3} (default driver {:.3}), speed rmse {:.3} (default driver {:.3})",
+        spacing_after, spacing_before, speed_after, speed_before
+    );
+    if results.len() > 1 {
+        for (parameter, _) in config.inputs.iter() {
+            let values = results
+                .iter()
+                .map(|calibrated| calibrated.driver.get(*parameter))
+                .collect::<Vec<f32>>();
+            let (mean, sd) = mean_and_sd(&values);
+            println!(
+                "{:>14}: mean {:.4}, sd {:.4} (default {:.4})",
+                parameter.flag(),
+                mean,
+                sd,
+                config.driver.get(*parameter)
+            );
+        }
+    }
+
+    if let Some(output) = &config.output {
+        fs::write(output, csv)?;
+        eprintln!("wrote the calibrated drivers to {}", output.display());
+    }
+    eprintln!(
+        "calibrated {} driver(s) in {:.2}s",
+        results.len(),
+        started.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::RecordedPoint;
+
+    fn assert_near(point: &[f64], expected: &[f64], tolerance: f64) {
+        assert!(
+            point
+                .iter()
+                .zip(expected)
+                .all(|(x, expected)| (x - expected).abs() < tolerance),
+            "{:?} isn't within {} of {:?}",
+            point,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn minimizes_a_quadratic() {
+        let minimum = [0.3, 0.7, 0.5];
+        let (point, cost) = nelder_mead(vec![0.9, 0.1, 0.5], 500, |x| {
+            x.iter().zip(minimum).map(|(x, m)| (x - m).powi(2)).sum()
+        });
+        assert_near(&point, &minimum, 1e-3);
+        assert!(cost < 1e-6);
+    }
+
+    #[test]
+    fn minimizes_the_rosenbrock_function() {
+        // Scaled from [-2, 2] into the unit box, its minimum (1, 1) is at (0.75, 0.75)
+        let rosenbrock = |u: &[f64]| {
+            let (x, y) = (4.0 * u[0] - 2.0, 4.0 * u[1] - 2.0);
+            (1.0 - x).powi(2) + 100.0 * (y - x * x).powi(2)
+        };
+        let (point, cost) = nelder_mead(vec![0.2, 0.8], 2000, rosenbrock);
+        assert_near(&point, &[0.75, 0.75], 1e-2);
+        assert!(cost < 1e-3);
+    }
+
+    #[test]
+    fn stays_in_the_unit_box() {
+        // The minimum is outside the box, the best point is on its edge
+        let (point, _) = nelder_mead(vec![0.5, 0.5], 500, |x| (x[0] - 2.0).powi(2) + x[1].powi(2));
+        assert_near(&point, &[1.0, 0.0], 1e-3);
+    }
+
+    #[test]
+    fn stops_after_max_evaluations() {
+        let evaluations = Cell::new(0);
+        nelder_mead(vec![0.9, 0.1], 50, |x| {
+            evaluations.set(evaluations.get() + 1);
+            x[0] * x[0] + x[1] * x[1]
+        });
+        // A step can evaluate a whole shrunk simplex past the limit
+        assert!(evaluations.get() <= 50 + 2);
+    }
+
+    /**
+     * A follower that `driver` drives off from a stop, recorded every 0.1s for 5s, with its leader
+     * cruising far enough ahead that only the acceleration shapes the follower's run
+     */
+    fn recorded_with(driver: &Driver) -> Pair {
+        let leader_points: Vec<_> = (0..50)
+            .map(|tick| RecordedPoint {
+                time: tick as f32 * 0.1,
+                position: 200.0 + tick as f32 * 0.64,
+                velocity: 6.4,
+                preceding: 0,
+                lane: 0,
+            })
+            .collect();
+        let mut pair = Pair {
+            leader: 1,
+            follower: 2,
+            follower_points: leader_points
+                .iter()
+                .map(|point| RecordedPoint {
+                    position: 0.0,
+                    velocity: 0.0,
+                    preceding: 1,
+                    ..*point
+                })
+                .collect(),
+            leader_points,
+        };
+        let samples = replay::replay(&pair, driver);
+        for (point, sample) in pair.follower_points.iter_mut().zip(samples) {
+            point.position = sample.simulated_position;
+            point.velocity = sample.simulated_velocity;
+        }
+        pair
+    }
+
+    #[test]
+    fn recovers_the_driver_that_was_recorded() {
+        let start = Driver::from_scenario(&Scenario::default());
+        let mut recorded = start;
+        recorded.set(Parameter::Acceleration, 0.005);
+        let pair = recorded_with(&recorded);
+        let config = CalibrationConfig {
+            pairs: vec![pair.clone()],
+            driver: start,
+            inputs: vec![(
+                Parameter::Acceleration,
+                Bounds {
+                    min: 0.0005,
+                    max: 0.01,
+                },
+            )],
+            fit: Fit::Both,
+            per_vehicle: true,
+            max_evaluations: 200,
+            threads: 1,
+            output: None,
+        };
+        let calibrated = calibrate(&config, &[&pair]);
+        assert!(calibrated.before.spacing_rmse > 0.1, "{:?}", calibrated);
+        assert!(calibrated.after.spacing_rmse < 0.02, "{:?}", calibrated);
+        assert!(calibrated.after.speed_rmse < 0.02, "{:?}", calibrated);
+        let fitted = calibrated.driver.get(Parameter::Acceleration);
+        assert!((fitted - 0.005).abs() < 0.0001, "{:?}", calibrated);
+        // Only the inputs are fitted
+        assert_eq!(
+            calibrated.driver.get(Parameter::Deceleration),
+            start.get(Parameter::Deceleration)
+        );
+    }
+}
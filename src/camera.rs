@@ -36,7 +36,7 @@ pub fn update(
             let pitch_rotation = Quat::from_rotation_x(pitch);
 
             let rotation = yaw_rotation * pitch_rotation;
-            transform.rotation = transform.rotation * rotation;
+            transform.rotation *= rotation;
         }
     }
     for event in mouse_scroll_evr.read() {
@@ -1,19 +1,22 @@
+use std::collections::VecDeque;
+
 use bevy::{
-    asset::Handle,
     ecs::{component::Component, system::Res},
-    prelude::{Bundle, Mut},
-    scene::{Scene, SceneBundle},
-    time::{Time, Timer, TimerMode},
+    prelude::{Bundle, Mut, Resource, SpatialBundle},
+    time::Time,
     transform::components::Transform,
 };
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+pub use traffic_core::driver::{
+    CarSnapshot, CarState, Intersection, ModelParameters, Performance, MAX_VELOCITY,
+};
+use traffic_core::timer::Timer;
 
-use crate::traffic_light::Light;
-
-/*
-The distance a car should leave before itself and the obstacle (other car/stoplight) in front of it
-I'm pretty sure the car's position is the center of the car, so I'm setting this value to half_car_length + some offset
- */
-const BREAK_DISTANCE: f32 = 6.0;
+use crate::{
+    rng::StreamRng,
+    road::{OnLane, Route},
+};
 
 #[derive(Component)]
 pub struct Car;
@@ -30,42 +33,130 @@ pub struct ReactionTimer(pub Timer);
 #[derive(Component)]
 pub struct IsBreaking(pub bool);
 
-const MAX_VELOCITY: f32 = 0.1;
-const SPEED_UP_ACCELERATION: f32 = 0.003;
-const SLOW_DOWN_ACCELERATION: f32 = -0.01;
+/**
+ * When (in seconds of simulated time) the car came to a full stop at the stop line, which it must
+ * do before crossing a stop sign or a dark/flashing red light
+ */
+#[derive(Component)]
+pub struct StoppedAtLine(pub Option<f32>);
+
+/**
+ * The lane the car was spawned on, where it goes back to when the simulation is reset
+ */
+#[derive(Component)]
+pub struct StartingLane(pub usize);
+
+/**
+ * A kind of vehicle, `share` is how often it's picked relative to the other classes
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VehicleClass {
+    pub name: String,
+    #[serde(deserialize_with = "crate::scenario::non_negative")]
+    pub share: f32,
+    #[serde(deserialize_with = "crate::scenario::positive")]
+    pub reaction_time: f32,
+    #[serde(deserialize_with = "crate::scenario::positive")]
+    pub max_velocity: f32,
+    #[serde(deserialize_with = "crate::scenario::positive")]
+    pub acceleration: f32,
+    #[serde(deserialize_with = "crate::scenario::positive")]
+    pub deceleration: f32,
+}
+
+impl Default for VehicleClass {
+    fn default() -> Self {
+        VehicleClass {
+            name: "car".to_string(),
+            share: 1.0,
+            reaction_time: 0.4,
+            max_velocity: MAX_VELOCITY,
+            acceleration: 0.003,
+            deceleration: 0.01,
+        }
+    }
+}
+
+impl VehicleClass {
+    pub fn performance(&self) -> Performance {
+        Performance {
+            max_velocity: self.max_velocity,
+            acceleration: self.acceleration,
+            deceleration: self.deceleration,
+        }
+    }
+}
+
+/**
+ * The vehicle classes new cars are drawn from
+ */
+#[derive(Resource, Debug, Clone, PartialEq, Serialize)]
+pub struct VehicleClasses(pub Vec<VehicleClass>);
+
+impl Default for VehicleClasses {
+    fn default() -> Self {
+        VehicleClasses(vec![VehicleClass::default()])
+    }
+}
+
+impl VehicleClasses {
+    /**
+     * A class picked by share. With a single class nothing is drawn, so `rng`'s other draws (e.g.
+     * a source's arrivals) are the same as without classes.
+     */
+    pub fn draw(&self, rng: &mut StreamRng) -> &VehicleClass {
+        if let [class] = self.0.as_slice() {
+            return class;
+        }
+        let total = self.0.iter().map(|class| class.share).sum::<f32>();
+        let mut pick = rng.gen::<f32>() * total;
+        for class in self.0.iter() {
+            if pick < class.share {
+                return class;
+            }
+            pick -= class.share;
+        }
+        &self.0[self.0.len() - 1]
+    }
+}
 
 #[derive(Bundle)]
 pub struct CarBundle {
-    scene: SceneBundle,
+    spatial: SpatialBundle,
     car: Car,
     velocity: Velocity,
     acceleration: Acceleration,
     reaction_timer: ReactionTimer,
     is_breaking: IsBreaking,
+    stopped_at_line: StoppedAtLine,
+    on_lane: OnLane,
+    starting_lane: StartingLane,
+    route: Route,
+    performance: Performance,
 }
 
 pub fn get_car_bundle(
-    car_asset: Handle<Scene>,
     transform: Transform,
+    lane: usize,
+    route: VecDeque<usize>,
+    class: &VehicleClass,
     velocity: Option<f32>,
     acceleration: Option<f32>,
-    reaction_time_in_seconds: Option<f32>,
 ) -> CarBundle {
-    return CarBundle {
-        scene: SceneBundle {
-            scene: car_asset,
-            transform,
-            ..Default::default()
-        },
+    CarBundle {
+        spatial: SpatialBundle::from_transform(transform),
         car: Car {},
         velocity: Velocity(velocity.unwrap_or(0.0f32)),
         acceleration: Acceleration(acceleration.unwrap_or(0.0f32)),
-        reaction_timer: ReactionTimer(Timer::from_seconds(
-            reaction_time_in_seconds.unwrap_or(0.4),
-            TimerMode::Once,
-        )),
+        reaction_timer: ReactionTimer(Timer::from_seconds(class.reaction_time)),
         is_breaking: IsBreaking(false),
-    };
+        stopped_at_line: StoppedAtLine(None),
+        on_lane: OnLane(lane),
+        starting_lane: StartingLane(lane),
+        route: Route(route),
+        performance: class.performance(),
+    }
 }
 
 pub fn apply_movement(
@@ -75,102 +166,52 @@ pub fn apply_movement(
         Mut<'_, Velocity>,
         Mut<'_, ReactionTimer>,
         Mut<'_, IsBreaking>,
+        Mut<'_, StoppedAtLine>,
+        Mut<'_, OnLane>,
+        Mut<'_, Route>,
+        &Performance,
     ),
-    cars_z_positions: &Vec<f32>,
+    cars: &[CarSnapshot],
     time: &Res<Time>,
-    current_traffic_light: Light,
-    traffic_light_position: &Transform,
+    intersection: &Intersection,
 ) {
-    let (mut car_transform, mut acceleration, mut velocity, mut reaction_timer, mut is_breaking) =
-        car;
-    let should_break = should_break(
-        car_transform.translation.z,
-        velocity.0,
-        &cars_z_positions,
-        current_traffic_light,
-        traffic_light_position,
+    let (
+        mut car_transform,
+        mut acceleration,
+        mut velocity,
+        mut reaction_timer,
+        mut is_breaking,
+        mut stopped_at_line,
+        mut on_lane,
+        mut route,
+        performance,
+    ) = car;
+    let mut state = CarState {
+        lane: on_lane.0,
+        position: intersection.layout.lanes[on_lane.0].position_of(car_transform.translation),
+        velocity: velocity.0,
+        acceleration: acceleration.0,
+        reaction_timer: reaction_timer.0,
+        is_breaking: is_breaking.0,
+        stopped_at_line: stopped_at_line.0,
+        route: std::mem::take(&mut route.0),
+        performance: *performance,
+    };
+    state.step(
+        cars,
+        intersection,
+        time.elapsed_seconds(),
+        time.delta_seconds(),
     );
-    if should_break {
-        if !is_breaking.0 {
-            is_breaking.0 = true;
-            acceleration.0 = SLOW_DOWN_ACCELERATION
-        }
-    } else {
-        if is_breaking.0 {
-            is_breaking.0 = false;
-            reaction_timer.0.reset();
-        }
-        reaction_timer.0.tick(time.delta());
-        if reaction_timer.0.finished() {
-            acceleration.0 = SPEED_UP_ACCELERATION;
-        }
-    }
-
-    let new_velocity = velocity.0 + acceleration.0;
-    if new_velocity > MAX_VELOCITY {
-        velocity.0 = MAX_VELOCITY;
-    } else if new_velocity < 0.0 {
-        velocity.0 = 0.0;
-    } else {
-        velocity.0 = new_velocity;
-    }
-    car_transform.translation.z += velocity.0;
-}
-
-fn calculate_stopping_distance(current_velocity: f32) -> f32 {
-    // I'm adding  0.1 to the stopping distance to avoid it being 0
-    0.1 + (-current_velocity.powi(2)) / (2.0 * SLOW_DOWN_ACCELERATION)
-}
-
-fn get_car_infront_z_position(
-    cars_z_positions: &Vec<f32>,
-    current_car_z_position: f32,
-) -> Option<f32> {
-    let x = cars_z_positions
-        .iter()
-        .copied() // TODO: Not ideal, but the borrow-checker is giving me hell here
-        .filter_map(|other_car_z_position| {
-            if other_car_z_position > current_car_z_position {
-                Some(other_car_z_position)
-            } else {
-                None
-            }
-        })
-        .min_by(|a, b| a.partial_cmp(b).unwrap());
-    return x;
-}
 
-fn should_break(
-    position: f32,
-    velocity: f32,
-    other_cars_z_position: &Vec<f32>,
-    current_traffic_light: Light,
-    traffic_light_position: &Transform,
-) -> bool {
-    let minimum_distance_to_stop = calculate_stopping_distance(velocity);
-    let car_infront_z_position = get_car_infront_z_position(&other_cars_z_position, position);
-    match car_infront_z_position {
-        Some(car_infront_z_position) => {
-            if position + minimum_distance_to_stop + BREAK_DISTANCE >= car_infront_z_position {
-                return true;
-            }
-        }
-        None => {}
-    }
-    let before_traffic_light: bool =
-        position + BREAK_DISTANCE <= traffic_light_position.translation.z;
-    if before_traffic_light {
-        match current_traffic_light {
-            Light::RedLight => {
-                if position + minimum_distance_to_stop + BREAK_DISTANCE
-                    >= traffic_light_position.translation.z
-                {
-                    return true;
-                }
-                return false;
-            }
-            _ => {}
-        }
-    }
-    return false;
+    let lane = &intersection.layout.lanes[state.lane];
+    car_transform.translation = lane.point_at(state.position);
+    car_transform.rotation = lane.rotation_at(state.position);
+    acceleration.0 = state.acceleration;
+    velocity.0 = state.velocity;
+    reaction_timer.0 = state.reaction_timer;
+    is_breaking.0 = state.is_breaking;
+    stopped_at_line.0 = state.stopped_at_line;
+    on_lane.0 = state.lane;
+    route.0 = state.route;
 }
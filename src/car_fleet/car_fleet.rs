@@ -1,33 +1,170 @@
-use std::time::Duration;
-
 use crate::{
-    traffic_light::CurrentLight,
+    rng::{RngStream, SimulationRng},
+    road::{IntersectionControl, Lane, OnLane, RoadLayout, Route},
+    routing::{self, RoutingConfig, SinceReroute},
+    traffic_light::{CurrentLight, LightChangeTimer, SignalControl},
     ui_components::{
         reaction_timer_controls::ReactionTimeChanged, reset_simulation_button::ResetSimluation,
     },
 };
-use bevy::prelude::{EventReader, Without};
+use bevy::prelude::{
+    App, EventReader, FixedUpdate, IntoSystemConfigs, IntoSystemSetConfigs, Plugin, Startup,
+    SystemSet, Update, Without,
+};
 
-use super::car::{self, get_car_bundle, Acceleration, Car, IsBreaking, ReactionTimer, Velocity};
+use super::{
+    car::{
+        self, get_car_bundle, Acceleration, Car, CarSnapshot, Intersection, IsBreaking,
+        ModelParameters, Performance, ReactionTimer, StartingLane, StoppedAtLine, VehicleClasses,
+        Velocity,
+    },
+    source::{self, TrafficDemand},
+};
 use bevy::{
     asset::{AssetServer, Handle},
-    prelude::{Commands, Query, Res, With},
+    prelude::{Added, Commands, Entity, Query, Res, With},
     scene::Scene,
     time::Time,
     transform::components::Transform,
 };
 
-pub fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let scene: Handle<Scene> = asset_server.load("car.gltf#Scene0");
-    for i in 1..=6 {
-        commands.spawn(get_car_bundle(
-            scene.clone(),
-            Transform::from_xyz(0.0, 0.0, -10.0 * i as f32),
-            //.with_rotation(Quat::from_rotation_y(std::f32::consts::PI)),
-            None,
-            None,
-            None,
-        ));
+/**
+ * The cars: spawning them (queued or from the sources), routing, moving and despawning them at the
+ * sinks
+ */
+pub struct CarFleetPlugin {
+    /**
+     * Gives every car its model, without it the cars are only simulated
+     */
+    pub render: bool,
+}
+
+impl Default for CarFleetPlugin {
+    fn default() -> Self {
+        CarFleetPlugin { render: true }
+    }
+}
+
+/**
+ * The car fleet's systems, run in this order in `FixedUpdate`
+ */
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CarFleetSet {
+    /**
+     * The sources spawn new cars
+     */
+    Spawn,
+    /**
+     * Cars with a destination may pick a new route
+     */
+    Route,
+    /**
+     * Every car drives one tick
+     */
+    Movement,
+    /**
+     * The cars that reached a sink are removed
+     */
+    Despawn,
+}
+
+impl Plugin for CarFleetPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RoadLayout>()
+            .init_resource::<IntersectionControl>()
+            .init_resource::<TrafficDemand>()
+            .init_resource::<RoutingConfig>()
+            .init_resource::<SinceReroute>()
+            .init_resource::<VehicleClasses>()
+            .init_resource::<ModelParameters>()
+            .init_resource::<SimulationRng>()
+            .add_event::<ResetSimluation>()
+            .add_event::<ReactionTimeChanged>()
+            .configure_sets(
+                FixedUpdate,
+                (
+                    CarFleetSet::Spawn,
+                    CarFleetSet::Route,
+                    CarFleetSet::Movement,
+                    CarFleetSet::Despawn,
+                )
+                    .chain(),
+            )
+            .add_systems(Startup, (setup, source::setup))
+            .add_systems(
+                FixedUpdate,
+                (
+                    source::generate.in_set(CarFleetSet::Spawn),
+                    routing::reroute.in_set(CarFleetSet::Route),
+                    update.in_set(CarFleetSet::Movement),
+                    source::despawn_at_sinks.in_set(CarFleetSet::Despawn),
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    reset_simulation_listener,
+                    source::reset_simulation_listener,
+                    reaction_time_changes_listener,
+                ),
+            );
+        if self.render {
+            app.add_systems(Update, setup_scenes);
+        }
+    }
+}
+
+/**
+ * Where the `i`th car (starting from 1) of a lane starts, behind the intersection
+ */
+fn initial_transform(lane: &Lane, i: usize, spacing: f32) -> Transform {
+    let position = lane.junction.unwrap_or_default() - spacing * i as f32;
+    Transform::from_translation(lane.point_at(position)).with_rotation(lane.rotation_at(position))
+}
+
+/**
+ * Without sources, cars start queued on the lanes that lead into the intersection
+ */
+pub fn setup(
+    mut commands: Commands,
+    road_layout: Res<RoadLayout>,
+    demand: Res<TrafficDemand>,
+    vehicle_classes: Res<VehicleClasses>,
+    simulation_rng: Res<SimulationRng>,
+) {
+    if !demand.sources.is_empty() {
+        return;
+    }
+    let mut rng = simulation_rng.stream(RngStream::InitialQueue);
+    for (lane_index, lane) in road_layout.lanes.iter().enumerate() {
+        if lane.junction.is_none() {
+            continue;
+        }
+        for i in 1..=demand.initial_queue {
+            commands.spawn(get_car_bundle(
+                initial_transform(lane, i, demand.queue_spacing),
+                lane_index,
+                road_layout.route_from(lane_index, i - 1),
+                vehicle_classes.draw(&mut rng),
+                None,
+                None,
+            ));
+        }
+    }
+}
+
+/**
+ * Cars are spawned without a model so the simulation can run headless, this gives every new car
+ * its scene when rendering.
+ */
+pub fn setup_scenes(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    new_cars_q: Query<Entity, Added<Car>>,
+) {
+    for car_entity in new_cars_q.iter() {
+        let scene: Handle<Scene> = asset_server.load("car.gltf#Scene0");
+        commands.entity(car_entity).insert(scene);
     }
 }
 
@@ -40,28 +177,44 @@ pub fn update(
             &mut Velocity,
             &mut ReactionTimer,
             &mut IsBreaking,
+            &mut StoppedAtLine,
+            &mut OnLane,
+            &mut Route,
+            &Performance,
         ),
         With<Car>,
     >,
-    traffic_light_q: Query<(&CurrentLight, &Transform), Without<Car>>,
+    traffic_light_q: Query<(&CurrentLight, &LightChangeTimer), Without<Car>>,
+    road_layout: Res<RoadLayout>,
+    intersection_control: Res<IntersectionControl>,
+    signal_control: Res<SignalControl>,
+    model: Res<ModelParameters>,
     time: Res<Time>,
 ) {
-    let (current_light, traffic_light_transform) = traffic_light_q.single();
+    let (current_light, light_change_timer) = traffic_light_q.single();
+    let intersection = Intersection {
+        control: *intersection_control,
+        light: current_light.0,
+        minor_light: light_change_timer.minor_light(current_light.0, *signal_control),
+        layout: &road_layout,
+        model: &model,
+    };
 
-    let cars_z_positions = car_q
-        .transmute_lens::<&Transform>()
+    let cars = car_q
+        .transmute_lens::<(&Transform, &Velocity, &StoppedAtLine, &OnLane)>()
         .query()
         .iter()
-        .map(|transform| transform.translation.z)
-        .collect::<Vec<f32>>();
+        .map(
+            |(transform, velocity, stopped_at_line, on_lane)| CarSnapshot {
+                lane: on_lane.0,
+                position: road_layout.lanes[on_lane.0].position_of(transform.translation),
+                velocity: velocity.0,
+                stopped_at_line: stopped_at_line.0,
+            },
+        )
+        .collect::<Vec<CarSnapshot>>();
     for car in car_q.iter_mut() {
-        car::apply_movement(
-            car,
-            &cars_z_positions,
-            &time,
-            current_light.0,
-            traffic_light_transform,
-        );
+        car::apply_movement(car, &cars, &time, &intersection);
     }
 }
 
@@ -74,17 +227,31 @@ pub fn reset_simulation_listener(
         &mut Acceleration,
         &mut ReactionTimer,
         &mut IsBreaking,
+        &mut StoppedAtLine,
+        &mut OnLane,
+        &mut Route,
+        &StartingLane,
     )>,
+    road_layout: Res<RoadLayout>,
+    demand: Res<TrafficDemand>,
 ) {
     for _ in reset_simulation_event.read() {
-        let mut i = 0;
+        let mut cars_on_lane = vec![0; road_layout.lanes.len()];
         for mut car in query.iter_mut() {
-            i += 1;
-            *car.1 = Transform::from_xyz(0.0, 0.0, -10.0 * i as f32);
+            let lane = car.9 .0;
+            cars_on_lane[lane] += 1;
+            *car.7 = OnLane(lane);
+            *car.8 = Route(road_layout.route_from(lane, cars_on_lane[lane] - 1));
+            *car.1 = initial_transform(
+                &road_layout.lanes[lane],
+                cars_on_lane[lane],
+                demand.queue_spacing,
+            );
             *car.2 = Velocity(0.0);
             *car.3 = Acceleration(0.0);
             (car.4 .0).reset();
             *car.5 = IsBreaking(false);
+            *car.6 = StoppedAtLine(None);
         }
     }
 }
@@ -96,11 +263,11 @@ pub fn reaction_time_changes_listener(
     for e in reaction_time_changed_event.read() {
         // At the  moment we change all the cars' reaction time
         for mut car in query.iter_mut() {
-            let current_reaction_time = car.1 .0.duration().as_secs_f32();
+            let current_reaction_time = car.1 .0.duration();
             let reaction_timer = &mut car.1 .0;
             let new_reaction_time = current_reaction_time + e.delta;
             if new_reaction_time > 0.0 {
-                reaction_timer.set_duration(Duration::from_secs_f32(new_reaction_time));
+                reaction_timer.set_duration(new_reaction_time);
             }
         }
     }
@@ -0,0 +1,221 @@
+use std::{fs, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+/*
+Traffic counts are usually given per 15 minutes
+ */
+const COUNT_INTERVAL: f32 = 15.0 * 60.0;
+
+/**
+ * An arrival rate (in cars per second) that changes over the simulated time (in seconds).
+ * Before the first point the rate is the first point's, after the last point it's the last one's.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DemandProfile {
+    /**
+     * Linearly interpolated between the (time, rate) points
+     */
+    PiecewiseLinear(#[serde(deserialize_with = "valid_points")] Vec<(f32, f32)>),
+    /**
+     * Every (time, rate) point holds until the next one
+     */
+    Step(#[serde(deserialize_with = "valid_points")] Vec<(f32, f32)>),
+}
+
+/**
+ * A profile needs at least one point, increasing times and rates that aren't negative
+ */
+fn check_points(points: &[(f32, f32)]) -> Result<(), String> {
+    if points.is_empty() {
+        return Err("a profile needs at least one point".to_string());
+    }
+    if let Some((time, rate)) = points.iter().find(|(_, rate)| *rate < 0.0) {
+        return Err(format!("the rate at {} is negative: {}", time, rate));
+    }
+    if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return Err("the times must be increasing".to_string());
+    }
+    Ok(())
+}
+
+fn valid_points<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(f32, f32)>, D::Error> {
+    let points = Vec::deserialize(deserializer)?;
+    check_points(&points).map_err(de::Error::custom)?;
+    Ok(points)
+}
+
+impl DemandProfile {
+    fn points(&self) -> &[(f32, f32)] {
+        match self {
+            DemandProfile::PiecewiseLinear(points) | DemandProfile::Step(points) => points,
+        }
+    }
+
+    pub fn rate_at(&self, time: f32) -> f32 {
+        let points = self.points();
+        let next = points.partition_point(|(point_time, _)| *point_time <= time);
+        match (next, self) {
+            (0, _) => points[0].1,
+            (next, _) if next == points.len() => points[next - 1].1,
+            (next, DemandProfile::Step(_)) => points[next - 1].1,
+            (next, DemandProfile::PiecewiseLinear(_)) => {
+                let ((start, start_rate), (end, end_rate)) = (points[next - 1], points[next]);
+                start_rate + (end_rate - start_rate) * (time - start) / (end - start)
+            }
+        }
+    }
+
+    pub fn max_rate(&self) -> f32 {
+        self.points()
+            .iter()
+            .map(|(_, rate)| *rate)
+            .fold(0.0, f32::max)
+    }
+
+    /**
+     * When the profile stops changing
+     */
+    pub fn end(&self) -> f32 {
+        self.points().last().map_or(0.0, |(time, _)| *time)
+    }
+
+    /**
+     * A step profile from a CSV file with one row per 15 minutes, the count being the row's last
+     * column (so a time of day can come first). A header row is skipped. No cars arrive after the
+     * last row's 15 minutes, so the counted period can clear.
+     */
+    pub fn from_counts_csv(path: &str) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("couldn't read '{}': {}", path, e))?;
+        let mut points = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let Some(count) = line.rsplit(',').next().map(str::trim) else {
+                continue;
+            };
+            if count.is_empty() {
+                continue;
+            }
+            match count.parse::<f32>() {
+                Ok(count) if count >= 0.0 => {
+                    points.push((points.len() as f32 * COUNT_INTERVAL, count / COUNT_INTERVAL))
+                }
+                Err(_) if index == 0 => continue,
+                _ => {
+                    return Err(format!(
+                        "{}:{}: '{}' isn't a count of cars",
+                        path,
+                        index + 1,
+                        count
+                    ))
+                }
+            }
+        }
+        if points.is_empty() {
+            return Err(format!("'{}' has no counts", path));
+        }
+        points.push((points.len() as f32 * COUNT_INTERVAL, 0.0));
+        Ok(DemandProfile::Step(points))
+    }
+}
+
+impl FromStr for DemandProfile {
+    type Err = String;
+
+    /**
+     * `linear:<time>=<rate>,...`, `step:<time>=<rate>,...` or `counts:<file.csv>`
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = "expected linear:<time>=<rate>,..., step:<time>=<rate>,... or counts:<file>";
+        let (kind, values) = s.split_once(':').ok_or(usage)?;
+        if kind == "counts" {
+            return DemandProfile::from_counts_csv(values);
+        }
+        let points = values
+            .split(',')
+            .map(|point| {
+                let parsed = point
+                    .split_once('=')
+                    .and_then(|(time, rate)| Some((time.parse().ok()?, rate.parse().ok()?)));
+                parsed.ok_or(format!("'{}' isn't <time>=<rate>", point))
+            })
+            .collect::<Result<Vec<(f32, f32)>, String>>()?;
+        check_points(&points)?;
+        match kind {
+            "linear" => Ok(DemandProfile::PiecewiseLinear(points)),
+            "step" => Ok(DemandProfile::Step(points)),
+            _ => Err(usage.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_profiles_interpolate_between_the_points() {
+        let profile: DemandProfile = "linear:0=0.1,100=0.3,200=0.1".parse().unwrap();
+        assert_eq!(profile.rate_at(-10.0), 0.1);
+        assert_eq!(profile.rate_at(0.0), 0.1);
+        assert!((profile.rate_at(50.0) - 0.2).abs() < 1e-6);
+        assert_eq!(profile.rate_at(100.0), 0.3);
+        assert!((profile.rate_at(175.0) - 0.15).abs() < 1e-6);
+        assert_eq!(profile.rate_at(500.0), 0.1);
+        assert_eq!(profile.max_rate(), 0.3);
+        assert_eq!(profile.end(), 200.0);
+    }
+
+    #[test]
+    fn step_profiles_hold_every_rate_until_the_next_point() {
+        let profile: DemandProfile = "step:60=0.2,120=0.5".parse().unwrap();
+        assert_eq!(profile.rate_at(0.0), 0.2);
+        assert_eq!(profile.rate_at(119.9), 0.2);
+        assert_eq!(profile.rate_at(120.0), 0.5);
+        assert_eq!(profile.rate_at(1000.0), 0.5);
+    }
+
+    #[test]
+    fn rejects_invalid_points() {
+        assert!("linear:0=0.1,0=0.2".parse::<DemandProfile>().is_err());
+        assert!("step:0=-0.1".parse::<DemandProfile>().is_err());
+        assert!("step:0".parse::<DemandProfile>().is_err());
+        assert!("cubic:0=0.1".parse::<DemandProfile>().is_err());
+    }
+
+    #[test]
+    fn counts_are_per_15_minutes_and_stop_after_the_last_one() {
+        let path = std::env::temp_dir().join(format!("counts-{}.csv", std::process::id()));
+        fs::write(&path, "time,cars\n08:00,90\n08:15,180\n08:30,45\n").unwrap();
+        let profile = DemandProfile::from_counts_csv(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let profile = profile.unwrap();
+        assert_eq!(
+            profile,
+            DemandProfile::Step(vec![
+                (0.0, 0.1),
+                (900.0, 0.2),
+                (1800.0, 0.05),
+                (2700.0, 0.0)
+            ])
+        );
+        assert_eq!(profile.rate_at(1000.0), 0.2);
+        assert_eq!(profile.rate_at(2700.0), 0.0);
+        assert_eq!(profile.rate_at(10_000.0), 0.0);
+    }
+
+    #[test]
+    fn counts_must_be_numbers() {
+        let path = std::env::temp_dir().join(format!("bad-counts-{}.csv", std::process::id()));
+        fs::write(&path, "time,cars\n08:00,90\n08:15,lots\n").unwrap();
+        let error = DemandProfile::from_counts_csv(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(
+            error
+                .as_ref()
+                .is_err_and(|error| error.ends_with(":3: 'lots' isn't a count of cars")),
+            "{:?}",
+            error
+        );
+    }
+}
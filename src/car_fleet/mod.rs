@@ -1,5 +1,7 @@
-pub mod car_fleet;
 pub mod car;
+#[allow(clippy::module_inception)]
+pub mod car_fleet;
+pub mod demand_profile;
+pub mod source;
 
-
-pub use car_fleet::*;
\ No newline at end of file
+pub use car_fleet::*;
@@ -0,0 +1,574 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    cli::Flags,
+    rng::{RngStream, SimulationRng, StreamRng},
+    road::{LaneGeometry, OnLane, RoadLayout},
+    routing::{self, Destination, OdMatrix, RoutingConfig},
+    ui_components::reset_simulation_button::ResetSimluation,
+};
+
+use super::{
+    car::{get_car_bundle, Car, VehicleClasses, Velocity},
+    demand_profile::DemandProfile,
+};
+
+/*
+How far before its junction a source spawns cars, and how far past it a sink removes them
+ */
+const SOURCE_DISTANCE: f32 = 100.0;
+const SINK_DISTANCE: f32 = 100.0;
+/*
+A source only spawns a car when the last one is at least this far down the lane, the same spacing
+the initial queues have
+ */
+const SPAWN_SPACING: f32 = 10.0;
+
+/**
+ * How the time between two cars of a source (the headway, in seconds) is drawn
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum ArrivalProcess {
+    /**
+     * `rate` cars per second on average, with exponentially distributed headways
+     */
+    Poisson {
+        #[serde(deserialize_with = "crate::scenario::positive")]
+        rate: f32,
+    },
+    /**
+     * Exactly `headway` seconds between cars
+     */
+    Uniform {
+        #[serde(deserialize_with = "crate::scenario::positive")]
+        headway: f32,
+    },
+    /**
+     * Headways picked at random among observed ones
+     */
+    Empirical {
+        #[serde(deserialize_with = "observed_headways")]
+        headways: Vec<f32>,
+    },
+    /**
+     * Poisson arrivals whose rate follows `profile`
+     */
+    TimeVarying { profile: DemandProfile },
+}
+
+fn observed_headways<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+    let headways = Vec::<f32>::deserialize(deserializer)?;
+    if headways.is_empty() || headways.iter().any(|headway| *headway <= 0.0) {
+        return Err(de::Error::custom(
+            "expected at least one headway, all of them positive",
+        ));
+    }
+    Ok(headways)
+}
+
+fn exponential_headway(rate: f32, rng: &mut StreamRng) -> f32 {
+    -(1.0 - rng.gen::<f32>()).ln() / rate
+}
+
+impl ArrivalProcess {
+    /**
+     * The headway to the arrival after the one at `now`, infinite if no car ever comes again
+     */
+    fn next_headway(&self, now: f32, rng: &mut StreamRng) -> f32 {
+        match self {
+            ArrivalProcess::Poisson { rate } => exponential_headway(*rate, rng),
+            ArrivalProcess::Uniform { headway } => *headway,
+            ArrivalProcess::Empirical { headways } => headways[rng.gen_range(0..headways.len())],
+            ArrivalProcess::TimeVarying { profile } => {
+                // Thinning: candidates come at the highest rate and are kept with the probability
+                // of the actual rate at their time
+                let max_rate = profile.max_rate();
+                if max_rate <= 0.0 {
+                    return f32::INFINITY;
+                }
+                let mut time = now;
+                loop {
+                    if time >= profile.end() && profile.rate_at(time) <= 0.0 {
+                        return f32::INFINITY;
+                    }
+                    time += exponential_headway(max_rate, rng);
+                    if rng.gen::<f32>() * max_rate < profile.rate_at(time) {
+                        return time - now;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ArrivalProcess {
+    type Err = String;
+
+    /**
+     * `poisson:<cars per second>`, `uniform:<headway>`, `empirical:<headway>,<headway>,...` or a
+     * `DemandProfile`
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = "expected poisson:<rate>, uniform:<headway>, empirical:<headway>,..., \
+            linear:<time>=<rate>,..., step:<time>=<rate>,... or counts:<file>";
+        let (kind, values) = s.split_once(':').ok_or(usage)?;
+        if matches!(kind, "linear" | "step" | "counts") {
+            return Ok(ArrivalProcess::TimeVarying {
+                profile: s.parse()?,
+            });
+        }
+        let values = values
+            .split(',')
+            .map(|value| match value.trim().parse::<f32>() {
+                Ok(value) if value > 0.0 => Ok(value),
+                _ => Err(format!("'{}' isn't a positive number", value)),
+            })
+            .collect::<Result<Vec<f32>, String>>()?;
+        match (kind, values.as_slice()) {
+            ("poisson", [rate]) => Ok(ArrivalProcess::Poisson { rate: *rate }),
+            ("uniform", [headway]) => Ok(ArrivalProcess::Uniform { headway: *headway }),
+            ("empirical", _) => Ok(ArrivalProcess::Empirical { headways: values }),
+            _ => Err(usage.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceDemand {
+    pub lane: usize,
+    pub arrivals: ArrivalProcess,
+    /**
+     * (lane, weight) every car's destination is drawn from, cars without one take the layout's
+     * default route
+     */
+    #[serde(default)]
+    pub destinations: Vec<(usize, f32)>,
+}
+
+/**
+ * Where cars come from. Without any source every lane into the intersection starts with a queue of
+ * six cars that drive on forever, with sources cars keep arriving and are removed at the end of
+ * the road.
+ */
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafficDemand {
+    pub sources: Vec<SourceDemand>,
+    pub initial_queue: usize,
+    #[serde(deserialize_with = "crate::scenario::positive")]
+    pub queue_spacing: f32,
+}
+
+impl Default for TrafficDemand {
+    fn default() -> Self {
+        TrafficDemand {
+            sources: Vec::new(),
+            initial_queue: 6,
+            queue_spacing: 10.0,
+        }
+    }
+}
+
+impl TrafficDemand {
+    /**
+     * The same arrivals on every lane leading into the intersection
+     */
+    pub fn on_every_entry(road_layout: &RoadLayout, arrivals: ArrivalProcess) -> Self {
+        TrafficDemand {
+            sources: road_layout
+                .lanes
+                .iter()
+                .enumerate()
+                .filter(|(_, lane)| lane.junction.is_some())
+                .map(|(lane, _)| SourceDemand {
+                    lane,
+                    arrivals: arrivals.clone(),
+                    destinations: Vec::new(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * Poisson arrivals on every origin at its total number of trips per hour
+     */
+    pub fn from_od_matrix(od_matrix: &OdMatrix) -> Self {
+        TrafficDemand {
+            sources: od_matrix
+                .origins
+                .iter()
+                .zip(od_matrix.trips.iter())
+                .filter(|(_, trips)| trips.iter().sum::<f32>() > 0.0)
+                .map(|(origin, trips)| SourceDemand {
+                    lane: *origin,
+                    arrivals: ArrivalProcess::Poisson {
+                        rate: trips.iter().sum::<f32>() / 3600.0,
+                    },
+                    destinations: od_matrix
+                        .destinations
+                        .iter()
+                        .copied()
+                        .zip(trips.iter().copied())
+                        .collect(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * From either `--arrivals` (on every entry) or `--od` (an OD matrix CSV), no sources otherwise
+     */
+    pub fn from_flags(flags: &Flags, road_layout: &RoadLayout) -> Result<Self, String> {
+        let arrivals = flags.get_optional::<ArrivalProcess>("arrivals")?;
+        let od_matrix = flags.get_optional::<OdMatrix>("od")?;
+        match (arrivals, od_matrix) {
+            (Some(_), Some(_)) => Err("'--arrivals' and '--od' can't be used together".to_string()),
+            (Some(arrivals), None) => Ok(TrafficDemand::on_every_entry(road_layout, arrivals)),
+            (None, Some(od_matrix)) => {
+                od_matrix.validate(road_layout)?;
+                Ok(TrafficDemand::from_od_matrix(&od_matrix))
+            }
+            (None, None) => Ok(TrafficDemand::default()),
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct VehicleSource {
+    pub lane: usize,
+    pub arrivals: ArrivalProcess,
+    destinations: Option<(Vec<usize>, WeightedIndex<f32>)>,
+    rng: StreamRng,
+    clock: f32,
+    until_next_arrival: f32,
+    /**
+     * Cars that arrived while the entry was blocked, they're spawned as soon as it clears
+     */
+    pub waiting: u32,
+    pub spawned: usize,
+}
+
+impl VehicleSource {
+    fn new(demand: &SourceDemand, simulation_rng: &SimulationRng) -> Self {
+        let mut rng = simulation_rng.stream(RngStream::Source(demand.lane));
+        let until_next_arrival = demand.arrivals.next_headway(0.0, &mut rng);
+        let (lanes, weights): (Vec<usize>, Vec<f32>) = demand.destinations.iter().copied().unzip();
+        VehicleSource {
+            lane: demand.lane,
+            arrivals: demand.arrivals.clone(),
+            destinations: WeightedIndex::new(weights)
+                .ok()
+                .map(|weights| (lanes, weights)),
+            rng,
+            clock: 0.0,
+            until_next_arrival,
+            waiting: 0,
+            spawned: 0,
+        }
+    }
+}
+
+/**
+ * What a source has done so far, enough to carry on exactly from there
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SourceState {
+    pub lane: usize,
+    /**
+     * How many 32-bit words of its random stream the source has drawn
+     */
+    pub rng_position: u64,
+    pub clock: f32,
+    pub until_next_arrival: f32,
+    pub waiting: u32,
+    pub spawned: usize,
+}
+
+impl VehicleSource {
+    pub fn state(&self) -> SourceState {
+        SourceState {
+            lane: self.lane,
+            rng_position: self.rng.get_word_pos() as u64,
+            clock: self.clock,
+            until_next_arrival: self.until_next_arrival,
+            waiting: self.waiting,
+            spawned: self.spawned,
+        }
+    }
+
+    /**
+     * Carries on from `state`, with its stream drawn from `simulation_rng`
+     */
+    pub fn restore(&mut self, state: &SourceState, simulation_rng: &SimulationRng) {
+        self.rng = simulation_rng.stream(RngStream::Source(self.lane));
+        self.rng.set_word_pos(state.rng_position as u128);
+        self.clock = state.clock;
+        self.until_next_arrival = state.until_next_arrival;
+        self.waiting = state.waiting;
+        self.spawned = state.spawned;
+    }
+}
+
+/**
+ * Cars reaching it are removed, it's at the end of lanes that lead nowhere
+ */
+#[derive(Component)]
+pub struct VehicleSink {
+    pub lane: usize,
+}
+
+fn spawn_sources_and_sinks(
+    commands: &mut Commands,
+    demand: &TrafficDemand,
+    road_layout: &RoadLayout,
+    simulation_rng: &SimulationRng,
+) {
+    if demand.sources.is_empty() {
+        return;
+    }
+    for source in demand.sources.iter() {
+        let lane = &road_layout.lanes[source.lane];
+        let position = lane.junction.unwrap_or_default() - SOURCE_DISTANCE;
+        commands.spawn((
+            SpatialBundle::from_transform(Transform::from_translation(lane.point_at(position))),
+            VehicleSource::new(source, simulation_rng),
+        ));
+    }
+    for (index, lane) in road_layout.lanes.iter().enumerate() {
+        if matches!(lane.geometry, LaneGeometry::Circle { .. }) {
+            continue;
+        }
+        let position = lane.junction.unwrap_or_default() + SINK_DISTANCE;
+        commands.spawn((
+            SpatialBundle::from_transform(Transform::from_translation(lane.point_at(position))),
+            VehicleSink { lane: index },
+        ));
+    }
+}
+
+pub fn setup(
+    mut commands: Commands,
+    demand: Res<TrafficDemand>,
+    road_layout: Res<RoadLayout>,
+    simulation_rng: Res<SimulationRng>,
+) {
+    spawn_sources_and_sinks(&mut commands, &demand, &road_layout, &simulation_rng);
+}
+
+/**
+ * Draws the arrivals of every source and spawns the cars the entry has room for, routed to a
+ * destination drawn from the source's
+ */
+pub fn generate(
+    mut commands: Commands,
+    mut source_q: Query<(&mut VehicleSource, &Transform)>,
+    car_q: Query<(&Transform, &OnLane, &Velocity), (With<Car>, Without<VehicleSource>)>,
+    road_layout: Res<RoadLayout>,
+    routing_config: Res<RoutingConfig>,
+    vehicle_classes: Res<VehicleClasses>,
+    time: Res<Time>,
+) {
+    for (mut source, source_transform) in source_q.iter_mut() {
+        let source = &mut *source;
+        source.clock += time.delta_seconds();
+        source.until_next_arrival -= time.delta_seconds();
+        while source.until_next_arrival <= 0.0 {
+            source.waiting += 1;
+            let arrival_time = source.clock + source.until_next_arrival;
+            source.until_next_arrival +=
+                source.arrivals.next_headway(arrival_time, &mut source.rng);
+        }
+        if source.waiting == 0 {
+            continue;
+        }
+        let lane = &road_layout.lanes[source.lane];
+        let source_position = lane.position_of(source_transform.translation);
+        let is_blocked = car_q.iter().any(|(car_transform, on_lane, _)| {
+            on_lane.0 == source.lane
+                && lane
+                    .distance_ahead(source_position, lane.position_of(car_transform.translation))
+                    .is_some_and(|distance| distance < SPAWN_SPACING)
+        });
+        if is_blocked {
+            continue;
+        }
+        let destination = source
+            .destinations
+            .as_ref()
+            .map(|(lanes, weights)| lanes[weights.sample(&mut source.rng)]);
+        let route = match destination {
+            Some(destination) => {
+                let velocities = routing::lane_velocities(
+                    &road_layout,
+                    routing_config.travel_times,
+                    car_q
+                        .iter()
+                        .map(|(_, on_lane, velocity)| (on_lane, velocity)),
+                );
+                routing::shortest_route(
+                    &road_layout,
+                    source.lane,
+                    source_position,
+                    destination,
+                    &velocities,
+                )
+                .unwrap_or_default()
+            }
+            None => road_layout.route_from(source.lane, source.spawned),
+        };
+        let class = vehicle_classes.draw(&mut source.rng);
+        let mut car = commands.spawn(get_car_bundle(
+            Transform::from_translation(lane.point_at(source_position))
+                .with_rotation(lane.rotation_at(source_position)),
+            source.lane,
+            route,
+            class,
+            Some(class.max_velocity),
+            None,
+        ));
+        if let Some(destination) = destination {
+            car.insert(Destination(destination));
+        }
+        source.waiting -= 1;
+        source.spawned += 1;
+    }
+}
+
+pub fn despawn_at_sinks(
+    mut commands: Commands,
+    sink_q: Query<(&VehicleSink, &Transform)>,
+    car_q: Query<(Entity, &Transform, &OnLane), (With<Car>, Without<VehicleSink>)>,
+    road_layout: Res<RoadLayout>,
+) {
+    for (sink, sink_transform) in sink_q.iter() {
+        let lane = &road_layout.lanes[sink.lane];
+        let sink_position = lane.position_of(sink_transform.translation);
+        for (car_entity, car_transform, on_lane) in car_q.iter() {
+            if on_lane.0 == sink.lane
+                && lane
+                    .distance_ahead(sink_position, lane.position_of(car_transform.translation))
+                    .is_some()
+            {
+                commands.entity(car_entity).despawn_recursive();
+            }
+        }
+    }
+}
+
+/**
+ * With sources, the cars on the road are all removed and the sources start over
+ */
+pub fn reset_simulation_listener(
+    mut commands: Commands,
+    mut reset_simulation_event: EventReader<ResetSimluation>,
+    source_q: Query<Entity, Or<(With<VehicleSource>, With<VehicleSink>)>>,
+    car_q: Query<Entity, With<Car>>,
+    demand: Res<TrafficDemand>,
+    road_layout: Res<RoadLayout>,
+    simulation_rng: Res<SimulationRng>,
+) {
+    for _ in reset_simulation_event.read() {
+        if demand.sources.is_empty() {
+            continue;
+        }
+        for entity in source_q.iter().chain(car_q.iter()) {
+            commands.entity(entity).despawn_recursive();
+        }
+        spawn_sources_and_sinks(&mut commands, &demand, &road_layout, &simulation_rng);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    fn headways(arrivals: &ArrivalProcess, count: usize) -> Vec<f32> {
+        let mut rng = SimulationRng::new(0).stream(RngStream::Source(0));
+        (0..count)
+            .map(|_| arrivals.next_headway(0.0, &mut rng))
+            .collect()
+    }
+
+    fn mean(values: &[f32]) -> f32 {
+        values.iter().sum::<f32>() / values.len() as f32
+    }
+
+    #[test]
+    fn poisson_headways_average_one_over_the_rate() {
+        let headways = headways(&ArrivalProcess::Poisson { rate: 0.5 }, 20_000);
+        assert!((mean(&headways) - 2.0).abs() < 0.05, "{}", mean(&headways));
+        assert!(headways.iter().all(|headway| *headway > 0.0));
+        // Exponential, so their standard deviation is their mean too
+        let variance = mean(
+            &headways
+                .iter()
+                .map(|headway| (headway - 2.0).powi(2))
+                .collect::<Vec<f32>>(),
+        );
+        assert!((variance.sqrt() - 2.0).abs() < 0.1, "{}", variance.sqrt());
+    }
+
+    #[test]
+    fn uniform_headways_are_all_the_same() {
+        let headways = headways(&ArrivalProcess::Uniform { headway: 3.0 }, 100);
+        assert!(headways.iter().all(|headway| *headway == 3.0));
+    }
+
+    #[test]
+    fn empirical_headways_are_drawn_from_the_observed_ones() {
+        let observed = vec![1.0, 2.0, 6.0];
+        let headways = headways(
+            &ArrivalProcess::Empirical {
+                headways: observed.clone(),
+            },
+            9_000,
+        );
+        for headway in observed {
+            let count = headways.iter().filter(|drawn| **drawn == headway).count();
+            assert!((2_700..3_300).contains(&count), "{}: {}", headway, count);
+        }
+        assert!((mean(&headways) - 3.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn sinks_remove_the_cars_past_them() {
+        let mut world = World::new();
+        world.insert_resource(RoadLayout::default());
+        world.insert_resource(SimulationRng::new(0));
+        world.insert_resource(TrafficDemand {
+            sources: vec![SourceDemand {
+                lane: 0,
+                arrivals: ArrivalProcess::Uniform { headway: 1.0 },
+                destinations: Vec::new(),
+            }],
+            ..default()
+        });
+        world.run_system_once(setup);
+        let mut car_at = |position: f32| {
+            world
+                .spawn((
+                    Car,
+                    Transform::from_translation(Vec3::Z * position),
+                    OnLane(0),
+                ))
+                .id()
+        };
+        let before = car_at(SINK_DISTANCE - 1.0);
+        let past = car_at(SINK_DISTANCE + 1.0);
+        let further = car_at(SINK_DISTANCE + 20.0);
+        world.run_system_once(despawn_at_sinks);
+        assert!(world.get_entity(before).is_some());
+        assert!(world.get_entity(past).is_none());
+        assert!(world.get_entity(further).is_none());
+    }
+}
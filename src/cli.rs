@@ -0,0 +1,57 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+/**
+ * The `--name value` pairs given after a subcommand, e.g. `traffic-sim optimize --generations 20`
+ */
+pub struct Flags(HashMap<String, String>);
+
+impl Flags {
+    pub fn parse(args: &[String], known_flags: &[&str]) -> Result<Self, String> {
+        let mut flags = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(format!("unexpected argument '{}'", arg));
+            };
+            if !known_flags.contains(&name) {
+                return Err(format!(
+                    "unknown flag '--{}', expected one of: --{}",
+                    name,
+                    known_flags.join(", --")
+                ));
+            }
+            let Some(value) = args.next() else {
+                return Err(format!("missing value for '--{}'", name));
+            };
+            flags.insert(name.to_string(), value.clone());
+        }
+        Ok(Flags(flags))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn get<T>(&self, name: &str, default: T) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        Ok(self.get_optional(name)?.unwrap_or(default))
+    }
+
+    pub fn get_optional<T>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.0
+            .get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid value '{}' for '--{}': {}", value, name, e))
+            })
+            .transpose()
+    }
+}
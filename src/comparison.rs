@@ -0,0 +1,88 @@
+use crate::{
+    car_fleet::source::TrafficDemand,
+    cli::Flags,
+    headless::HeadlessSimulation,
+    road::{IntersectionControl, RoadLayout},
+    routing::RoutingConfig,
+    traffic_light::SignalPlan,
+};
+
+pub struct ComparisonConfig {
+    pub duration: f32,
+    pub road_layout: RoadLayout,
+    pub signal_plan: SignalPlan,
+    pub demand: TrafficDemand,
+    pub routing_config: RoutingConfig,
+    /**
+     * Every control is run with it, so they're compared on the same arrivals
+     */
+    pub seed: u64,
+}
+
+impl ComparisonConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let flags = Flags::parse(
+            args,
+            &[
+                "duration",
+                "layout",
+                "green",
+                "yellow",
+                "red",
+                "arrivals",
+                "od",
+                "travel-times",
+                "reroute",
+                "seed",
+            ],
+        )?;
+        let default_plan = SignalPlan::default();
+        let road_layout = flags.get("layout", RoadLayout::crossroads())?;
+        let demand = TrafficDemand::from_flags(&flags, &road_layout)?;
+        Ok(ComparisonConfig {
+            duration: flags.get("duration", 120.0)?,
+            road_layout,
+            demand,
+            routing_config: RoutingConfig::from_flags(&flags)?,
+            seed: flags.get("seed", 0)?,
+            signal_plan: SignalPlan {
+                green: flags.get("green", default_plan.green)?,
+                yellow: flags.get("yellow", default_plan.yellow)?,
+                red: flags.get("red", default_plan.red)?,
+                offset: 0.0,
+                ..default_plan
+            },
+        })
+    }
+}
+
+/**
+ * Runs the same demand through every kind of intersection control, to see whether the junction
+ * should be signalized at all
+ */
+pub fn run(config: &ComparisonConfig) {
+    println!(
+        "{:<12} | {:>10} {:>6} {:>10}",
+        "control", "delay", "stops", "throughput"
+    );
+    for intersection_control in [
+        IntersectionControl::Signal,
+        IntersectionControl::TwoWayStop,
+        IntersectionControl::AllWayStop,
+        IntersectionControl::Yield,
+    ] {
+        let mut simulation = HeadlessSimulation::new(config.signal_plan, config.seed);
+        simulation.set_road_layout(config.road_layout.clone());
+        simulation.set_intersection_control(intersection_control);
+        simulation.set_demand(config.demand.clone());
+        simulation.set_routing_config(config.routing_config);
+        let metrics = simulation.run_until(config.duration);
+        println!(
+            "{:<12} | {:>10.1} {:>6} {:>10}",
+            format!("{:?}", intersection_control),
+            metrics.total_delay,
+            metrics.stops,
+            metrics.throughput
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::{io, path::Path};
+
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+
+#[cfg(feature = "sqlite")]
+use rusqlite::{params, Connection};
+
+use crate::{headless::HeadlessSimulation, scenario::Scenario, trajectories::Trajectories};
+
+/*
+The tables, created if the file doesn't have them yet. Every table but `scenarios` is keyed by the
+run, so any number of runs can share a file. Times are in seconds since the run started.
+ */
+#[cfg(feature = "sqlite")]
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS scenarios (
+    hash TEXT PRIMARY KEY,
+    description TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    scenario_hash TEXT NOT NULL REFERENCES scenarios (hash),
+    seed INTEGER NOT NULL,
+    duration REAL NOT NULL,
+    parameters TEXT NOT NULL,
+    elapsed REAL NOT NULL,
+    delay REAL NOT NULL,
+    stops INTEGER NOT NULL,
+    throughput INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS trajectories (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    vehicle_id INTEGER NOT NULL,
+    frame INTEGER NOT NULL,
+    time REAL NOT NULL,
+    x REAL NOT NULL,
+    y REAL NOT NULL,
+    lane INTEGER NOT NULL,
+    position REAL NOT NULL,
+    velocity REAL NOT NULL,
+    acceleration REAL NOT NULL,
+    braking INTEGER NOT NULL,
+    leader_id INTEGER,
+    space_headway REAL,
+    PRIMARY KEY (run_id, vehicle_id, frame)
+);
+CREATE TABLE IF NOT EXISTS signal_changes (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    time REAL NOT NULL,
+    light TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS detector_readings (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    lane INTEGER NOT NULL,
+    time REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS vehicles (
+    run_id INTEGER NOT NULL REFERENCES runs (id),
+    vehicle_id INTEGER NOT NULL,
+    first_seen REAL NOT NULL,
+    last_seen REAL NOT NULL,
+    reached_sink INTEGER NOT NULL,
+    first_lane INTEGER NOT NULL,
+    last_lane INTEGER NOT NULL,
+    distance REAL NOT NULL,
+    stops INTEGER NOT NULL,
+    delay REAL NOT NULL,
+    PRIMARY KEY (run_id, vehicle_id)
+);
+";
+
+/**
+ * What a run was set up from
+ */
+pub struct RunMetadata<'a> {
+    pub scenario: &'a Scenario,
+    pub seed: u64,
+    pub duration: f32,
+    /**
+     * What was changed from the scenario (e.g. by a sweep), by name. Stored as a JSON object so
+     * it can be queried with `json_extract`.
+     */
+    pub parameters: &'a [(String, f32)],
+}
+
+#[cfg(feature = "sqlite")]
+impl RunMetadata<'_> {
+    fn parameters_json(&self) -> String {
+        let fields = self
+            .parameters
+            .iter()
+            .map(|(name, value)| format!("\"{}\":{}", name, value))
+            .collect::<Vec<String>>();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/**
+ * How a run's cars should be recorded for the database: their trips, and their trajectories only
+ * with a `sample_interval` (every tick would be far too many rows)
+ */
+pub fn recording(sample_interval: Option<f32>) -> Trajectories {
+    match sample_interval {
+        Some(_) => Trajectories::new(sample_interval),
+        None => Trajectories::vehicles_only(),
+    }
+}
+
+/**
+ * A SQLite file runs are written into: their metadata and metrics, the light's changes, the
+ * stop-line detectors' readings (a car entering the intersection from a lane), every car's trip
+ * and the sampled trajectories. It can be shared between the threads running the simulations.
+ *
+ * It needs the `sqlite` feature, without it opening one fails.
+ */
+pub struct Database {
+    #[cfg(feature = "sqlite")]
+    connection: Mutex<Connection>,
+    #[cfg(not(feature = "sqlite"))]
+    never: std::convert::Infallible,
+}
+
+#[cfg(feature = "sqlite")]
+impl Database {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(io::Error::other)?;
+        connection.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /**
+     * The ID after the file's last run, runs are given theirs up front so they don't depend on
+     * which finishes first
+     */
+    pub fn next_run_id(&self) -> io::Result<i64> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COALESCE(MAX(id), 0) + 1 FROM runs", [], |row| {
+                row.get(0)
+            })
+            .map_err(io::Error::other)
+    }
+
+    /**
+     * Writes everything `simulation` recorded as run `id`, all or nothing
+     */
+    pub fn write_run(
+        &self,
+        id: i64,
+        run: &RunMetadata,
+        simulation: &HeadlessSimulation,
+    ) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        Database::insert_run(&mut connection, id, run, simulation).map_err(io::Error::other)
+    }
+
+    fn insert_run(
+        connection: &mut Connection,
+        id: i64,
+        run: &RunMetadata,
+        simulation: &HeadlessSimulation,
+    ) -> rusqlite::Result<()> {
+        let transaction = connection.transaction()?;
+        let hash = run.scenario.fingerprint();
+        transaction.execute(
+            "INSERT OR IGNORE INTO scenarios (hash, description) VALUES (?1, ?2)",
+            params![hash, run.scenario.canonical_ron()],
+        )?;
+        let metrics = simulation.metrics();
+        transaction.execute(
+            "INSERT INTO runs VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                id,
+                hash,
+                // SQLite's integers are signed, the seed keeps its bits
+                run.seed as i64,
+                run.duration,
+                run.parameters_json(),
+                metrics.elapsed,
+                metrics.total_delay,
+                metrics.stops,
+                metrics.throughput
+            ],
+        )?;
+        {
+            let mut insert =
+                transaction.prepare("INSERT INTO signal_changes VALUES (?1, ?2, ?3)")?;
+            for (time, light) in simulation.signal_changes().0.iter() {
+                insert.execute(params![id, time, light.to_string()])?;
+            }
+            let mut insert =
+                transaction.prepare("INSERT INTO detector_readings VALUES (?1, ?2, ?3)")?;
+            for (lane, entries) in simulation.junction_entries().0.iter().enumerate() {
+                for time in entries {
+                    insert.execute(params![id, lane, time])?;
+                }
+            }
+        }
+        if let Some(trajectories) = simulation.trajectories() {
+            let mut insert = transaction
+                .prepare("INSERT INTO vehicles VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)")?;
+            for summary in trajectories.summaries.iter() {
+                insert.execute(params![
+                    id,
+                    summary.vehicle.0,
+                    summary.first_seen,
+                    summary.last_seen,
+                    summary.reached_sink,
+                    summary.first_lane,
+                    summary.last_lane,
+                    summary.distance,
+                    summary.stops,
+                    summary.delay
+                ])?;
+            }
+            let mut insert = transaction.prepare(
+                "INSERT INTO trajectories
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            )?;
+            for point in trajectories.points.iter() {
+                insert.execute(params![
+                    id,
+                    point.vehicle.0,
+                    point.frame,
+                    point.time,
+                    point.x,
+                    point.y,
+                    point.lane,
+                    point.position,
+                    point.velocity,
+                    point.acceleration,
+                    point.braking,
+                    point.leader.map(|leader| leader.0),
+                    point.space_headway
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl Database {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Err(io::Error::other(format!(
+            "can't write '{}', traffic-sim was built without the 'sqlite' feature",
+            path.display()
+        )))
+    }
+
+    pub fn next_run_id(&self) -> io::Result<i64> {
+        match self.never {}
+    }
+
+    pub fn write_run(
+        &self,
+        _id: i64,
+        _run: &RunMetadata,
+        _simulation: &HeadlessSimulation,
+    ) -> io::Result<()> {
+        match self.never {}
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use bevy::utils::default;
+
+    use super::*;
+    use crate::car_fleet::source::{ArrivalProcess, SourceDemand, TrafficDemand};
+
+    fn arriving_every(headway: f32) -> Scenario {
+        Scenario {
+            demand: TrafficDemand {
+                sources: vec![SourceDemand {
+                    lane: 0,
+                    arrivals: ArrivalProcess::Uniform { headway },
+                    destinations: Vec::new(),
+                }],
+                ..default()
+            },
+            ..default()
+        }
+    }
+
+    fn run(scenario: &Scenario, seed: u64) -> HeadlessSimulation {
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, seed);
+        simulation.set_scenario(scenario.clone());
+        simulation.record_trajectories(recording(Some(1.0)));
+        simulation.run_until(30.0);
+        simulation
+    }
+
+    fn count(database: &Database, sql: &str, id: i64) -> usize {
+        database
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(sql, [id], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn runs_share_a_file() {
+        let path = std::env::temp_dir().join(format!("runs-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let database = Database::open(&path).unwrap();
+        assert_eq!(database.next_run_id().unwrap(), 1);
+
+        let scenario = arriving_every(2.0);
+        let parameters = [("green".to_string(), 10.0)];
+        let simulations = [run(&scenario, 1), run(&scenario, 2)];
+        for (index, simulation) in simulations.iter().enumerate() {
+            let run = RunMetadata {
+                scenario: &scenario,
+                seed: index as u64 + 1,
+                duration: 30.0,
+                parameters: &parameters,
+            };
+            let id = database.next_run_id().unwrap();
+            assert_eq!(id, index as i64 + 1);
+            database.write_run(id, &run, simulation).unwrap();
+        }
+        drop(database);
+
+        // Reopening keeps the runs, and a different scenario gets its own row
+        let database = Database::open(&path).unwrap();
+        assert_eq!(database.next_run_id().unwrap(), 3);
+        let other_scenario = arriving_every(3.0);
+        let other_run = RunMetadata {
+            scenario: &other_scenario,
+            seed: 1,
+            duration: 30.0,
+            parameters: &[],
+        };
+        database
+            .write_run(3, &other_run, &run(&other_scenario, 1))
+            .unwrap();
+
+        for (index, simulation) in simulations.iter().enumerate() {
+            let id = index as i64 + 1;
+            let trajectories = simulation.trajectories().unwrap();
+            assert!(!trajectories.points.is_empty());
+            assert_eq!(
+                count(
+                    &database,
+                    "SELECT COUNT(*) FROM trajectories WHERE run_id = ?1",
+                    id
+                ),
+                trajectories.points.len()
+            );
+            assert_eq!(
+                count(
+                    &database,
+                    "SELECT COUNT(*) FROM vehicles WHERE run_id = ?1",
+                    id
+                ),
+                trajectories.summaries.len()
+            );
+            assert_eq!(
+                count(
+                    &database,
+                    "SELECT COUNT(*) FROM signal_changes WHERE run_id = ?1",
+                    id
+                ),
+                simulation.signal_changes().0.len()
+            );
+            assert_eq!(
+                count(&database, "SELECT seed FROM runs WHERE id = ?1", id),
+                index + 1
+            );
+        }
+        let connection = database.connection.lock().unwrap();
+        let scenarios = connection
+            .prepare("SELECT hash, description FROM scenarios ORDER BY description")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()
+            .unwrap();
+        let mut expected = [&scenario, &other_scenario]
+            .map(|scenario| (scenario.fingerprint(), scenario.canonical_ron()));
+        expected.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(scenarios, expected);
+        let (green, run_scenario): (f32, String) = connection
+            .query_row(
+                "SELECT json_extract(parameters, '$.green'), scenario_hash FROM runs WHERE id = 2",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(green, 10.0);
+        assert_eq!(run_scenario, scenario.fingerprint());
+        drop(connection);
+        drop(database);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
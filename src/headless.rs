@@ -0,0 +1,437 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use bevy::{prelude::*, time::TimeUpdateStrategy, utils::Instant};
+
+use crate::{
+    car_fleet::{source::TrafficDemand, CarFleetPlugin},
+    cli::Flags,
+    database::{self, Database, RunMetadata},
+    metrics::{JunctionEntries, MetricsPlugin, SignalChanges, SimulationMetrics},
+    rng::SimulationRng,
+    road::{IntersectionControl, RoadLayout},
+    routing::RoutingConfig,
+    scenario::Scenario,
+    snapshot::SimulationSnapshot,
+    traffic_light::{FaultSchedule, SignalControl, SignalPlan, TrafficLightPlugin},
+    trajectories::{Trajectories, TrajectoryPlugin},
+};
+
+/**
+ * The car and traffic light systems without a window, renderer or assets.
+ * Time is advanced manually by exactly one fixed timestep per update, so a run doesn't depend on
+ * how fast the machine is, and it runs as fast as the CPU allows.
+ *
+ * The road layout and intersection control are read when the simulation starts, which is the
+ * first time it runs (or its world is accessed), so they must be set before that.
+ */
+pub struct HeadlessSimulation {
+    app: App,
+    started: bool,
+}
+
+impl HeadlessSimulation {
+    pub fn new(signal_plan: SignalPlan, seed: u64) -> Self {
+        let mut app = App::new();
+        let timestep = Time::<Fixed>::default().timestep();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(timestep))
+            .insert_resource(signal_plan)
+            .insert_resource(SimulationRng::new(seed))
+            .add_plugins((
+                TrafficLightPlugin { render: false },
+                CarFleetPlugin { render: false },
+                MetricsPlugin,
+                TrajectoryPlugin,
+            ));
+        app.finish();
+        app.cleanup();
+        HeadlessSimulation {
+            app,
+            started: false,
+        }
+    }
+
+    /**
+     * Runs `Startup`, the first update doesn't advance time so no fixed tick happens yet
+     */
+    fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            self.app.update();
+        }
+    }
+
+    pub fn set_road_layout(&mut self, road_layout: RoadLayout) {
+        self.app.insert_resource(road_layout);
+    }
+
+    pub fn set_intersection_control(&mut self, intersection_control: IntersectionControl) {
+        self.app.insert_resource(intersection_control);
+    }
+
+    pub fn set_signal_control(&mut self, signal_control: SignalControl) {
+        self.app.insert_resource(signal_control);
+    }
+
+    pub fn set_demand(&mut self, demand: TrafficDemand) {
+        self.app.insert_resource(demand);
+    }
+
+    pub fn set_routing_config(&mut self, routing_config: RoutingConfig) {
+        self.app.insert_resource(routing_config);
+    }
+
+    /**
+     * Replaces everything the scenario sets, including the signal plan given to `new`
+     */
+    pub fn set_scenario(&mut self, scenario: Scenario) {
+        scenario.insert_into(&mut self.app);
+    }
+
+    pub fn set_fault_schedule(&mut self, fault_schedule: FaultSchedule) {
+        self.app.insert_resource(fault_schedule);
+    }
+
+    /**
+     * Gives the cars IDs and records their trips (and trajectories, see `Trajectories::new`) from
+     * the start
+     */
+    pub fn record_trajectories(&mut self, trajectories: Trajectories) {
+        self.app.insert_resource(trajectories);
+    }
+
+    /**
+     * `None` unless `record_trajectories` was called
+     */
+    pub fn trajectories(&self) -> Option<&Trajectories> {
+        self.app.world().get_resource::<Trajectories>()
+    }
+
+    /**
+     * The simulation's state now, see `SimulationSnapshot`
+     */
+    pub fn snapshot(&mut self) -> SimulationSnapshot {
+        SimulationSnapshot::capture(self.world_mut())
+    }
+
+    /**
+     * Carries on from `snapshot` instead of where the simulation is, including its seed and
+     * elapsed time
+     */
+    pub fn restore(&mut self, snapshot: &SimulationSnapshot) -> Result<(), String> {
+        snapshot.restore(self.world_mut())
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        self.start();
+        self.app.world_mut()
+    }
+
+    pub fn metrics(&self) -> SimulationMetrics {
+        *self.app.world().resource::<SimulationMetrics>()
+    }
+
+    pub fn junction_entries(&self) -> &JunctionEntries {
+        self.app.world().resource::<JunctionEntries>()
+    }
+
+    pub fn signal_changes(&self) -> &SignalChanges {
+        self.app.world().resource::<SignalChanges>()
+    }
+
+    /**
+     * Runs a single tick
+     */
+    pub fn step(&mut self) -> SimulationMetrics {
+        self.start();
+        self.app.update();
+        self.metrics()
+    }
+
+    /**
+     * Runs the simulation until `seconds` of simulated time have passed since it started.
+     */
+    pub fn run_until(&mut self, seconds: f32) -> SimulationMetrics {
+        self.start();
+        while self.metrics().elapsed < seconds {
+            self.app.update();
+        }
+        self.metrics()
+    }
+
+    /**
+     * Runs the simulation for another `seconds` of simulated time.
+     */
+    pub fn run_for(&mut self, seconds: f32) -> SimulationMetrics {
+        self.run_until(self.metrics().elapsed + seconds)
+    }
+}
+
+/**
+ * What a batch run writes besides printing its metrics
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchOutput {
+    /**
+     * The cars' trajectories as NGSIM-like CSV
+     */
+    pub trajectories: Option<PathBuf>,
+    /**
+     * The run and what happened in it, added to a SQLite database (see `Database`)
+     */
+    pub database: Option<PathBuf>,
+    /**
+     * How often trajectories are sampled, in seconds. Without it the CSV has every tick and the
+     * database no trajectories.
+     */
+    pub sample_interval: Option<f32>,
+    /**
+     * The state at the end of the run, to start others from
+     */
+    pub snapshot: Option<PathBuf>,
+}
+
+impl BatchOutput {
+    /**
+     * From `--trajectories <file.csv>`, `--database <file.sqlite>`, `--sample-interval <seconds>`
+     * and `--save-snapshot <file.ron>`
+     */
+    pub fn from_flags(flags: &Flags) -> Result<Self, String> {
+        let output = BatchOutput {
+            trajectories: flags.get_optional("trajectories")?,
+            database: flags.get_optional("database")?,
+            sample_interval: flags.get_optional("sample-interval")?,
+            snapshot: flags.get_optional("save-snapshot")?,
+        };
+        if let Some(interval) = output.sample_interval {
+            if interval <= 0.0 {
+                return Err("'--sample-interval' must be positive".to_string());
+            }
+            if output.trajectories.is_none() && output.database.is_none() {
+                return Err(
+                    "'--sample-interval' needs '--trajectories' or '--database'".to_string()
+                );
+            }
+        }
+        Ok(output)
+    }
+}
+
+/**
+ * Runs `scenario` until `duration` simulated seconds have passed without a window and prints its
+ * metrics, for batch runs (e.g. in CI), then writes what `output` asks for. With a `start` the run
+ * carries on from it (and its seed) rather than from the beginning.
+ */
+pub fn run_batch(
+    scenario: Scenario,
+    duration: f32,
+    seed: u64,
+    start: Option<&SimulationSnapshot>,
+    output: &BatchOutput,
+) -> io::Result<()> {
+    let started = Instant::now();
+    // Opened first so a bad path fails before the run rather than after it
+    let database = output.database.as_deref().map(Database::open).transpose()?;
+    let mut simulation = HeadlessSimulation::new(scenario.signal_plan, seed);
+    simulation.set_scenario(scenario.clone());
+    if output.trajectories.is_some() {
+        simulation.record_trajectories(Trajectories::new(output.sample_interval));
+    } else if database.is_some() {
+        simulation.record_trajectories(database::recording(output.sample_interval));
+    }
+    if let Some(start) = start {
+        simulation
+            .restore(start)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    let metrics = simulation.run_until(duration);
+    println!("{:<10} | {:>10}", "metric", "value");
+    println!("{:<10} | {:>10.1}", "elapsed", metrics.elapsed);
+    println!("{:<10} | {:>10.1}", "delay", metrics.total_delay);
+    println!("{:<10} | {:>10}", "stops", metrics.stops);
+    println!("{:<10} | {:>10}", "throughput", metrics.throughput);
+    eprintln!(
+        "simulated {:.0}s in {:.2}s",
+        metrics.elapsed,
+        started.elapsed().as_secs_f32()
+    );
+    if let (Some(path), Some(trajectories)) = (&output.trajectories, simulation.trajectories()) {
+        trajectories.write_csv(path)?;
+        eprintln!(
+            "wrote {} trajectory points to {}",
+            trajectories.points.len(),
+            path.display()
+        );
+    }
+    if let Some(path) = &output.snapshot {
+        simulation.snapshot().save(path)?;
+        eprintln!(
+            "wrote the state at {:.0}s to {}",
+            metrics.elapsed,
+            path.display()
+        );
+    }
+    if let (Some(path), Some(database)) = (&output.database, database) {
+        let id = database.next_run_id()?;
+        let run = RunMetadata {
+            scenario: &scenario,
+            seed,
+            duration,
+            parameters: &[],
+        };
+        database.write_run(id, &run, &simulation)?;
+        eprintln!("wrote run {} to {}", id, path.display());
+    }
+    Ok(())
+}
+
+/**
+ * The number of threads to run simulations on when it isn't given: one per core
+ */
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/**
+ * Calls `run` on every job from `threads` threads, each building its own simulations, and returns
+ * the results in the jobs' order
+ */
+pub fn run_in_parallel<T, R>(jobs: &[T], threads: usize, run: impl Fn(&T) -> R + Sync) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let next_job = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads.clamp(1, jobs.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next_job.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = jobs.get(index) else {
+                            return results;
+                        };
+                        results.push((index, run(job)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("a simulation thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use traffic_core::simulation::Simulation;
+
+    use super::*;
+    use crate::{
+        car_fleet::car::{
+            Acceleration, Car, CarState, IsBreaking, ModelParameters, Performance, ReactionTimer,
+            StoppedAtLine, Velocity,
+        },
+        road::{OnLane, Route},
+        traffic_light::{CurrentLight, Light, TrafficLight},
+    };
+
+    /**
+     * The cars in the order they were spawned, as the core simulation keeps them
+     */
+    fn cars(world: &mut World) -> Vec<CarState> {
+        let road_layout = world.resource::<RoadLayout>().clone();
+        let mut cars = world
+            .query_filtered::<(
+                Entity,
+                &Transform,
+                &Acceleration,
+                &Velocity,
+                &ReactionTimer,
+                &IsBreaking,
+                &StoppedAtLine,
+                &OnLane,
+                &Route,
+                &Performance,
+            ), With<Car>>()
+            .iter(world)
+            .map(
+                |(
+                    entity,
+                    transform,
+                    acceleration,
+                    velocity,
+                    reaction_timer,
+                    is_breaking,
+                    stopped_at_line,
+                    on_lane,
+                    route,
+                    performance,
+                )| {
+                    let state = CarState {
+                        lane: on_lane.0,
+                        position: road_layout.lanes[on_lane.0].position_of(transform.translation),
+                        velocity: velocity.0,
+                        acceleration: acceleration.0,
+                        reaction_timer: reaction_timer.0,
+                        is_breaking: is_breaking.0,
+                        stopped_at_line: stopped_at_line.0,
+                        route: route.0.clone(),
+                        performance: *performance,
+                    };
+                    (entity, state)
+                },
+            )
+            .collect::<Vec<_>>();
+        cars.sort_by_key(|(entity, _)| *entity);
+        cars.into_iter().map(|(_, state)| state).collect()
+    }
+
+    fn light(world: &mut World) -> Light {
+        world
+            .query_filtered::<&CurrentLight, With<TrafficLight>>()
+            .single(world)
+            .0
+    }
+
+    #[test]
+    fn core_simulation_matches_the_app() {
+        let signal_plan = SignalPlan {
+            offset: 8.0,
+            ..default()
+        };
+        let mut headless = HeadlessSimulation::new(signal_plan, 0);
+        headless.set_road_layout(RoadLayout::crossroads());
+        let mut core = Simulation::new(
+            RoadLayout::crossroads(),
+            IntersectionControl::Signal,
+            signal_plan,
+            ModelParameters::default(),
+        );
+        let initial_queue = cars(headless.world_mut());
+        assert!(!initial_queue.is_empty());
+        for car in initial_queue {
+            core.add_car(car);
+        }
+        let timestep = Time::<Fixed>::default().timestep().as_secs_f32();
+        for _ in 0..64 * 40 {
+            headless.step();
+            core.step(timestep);
+            assert_eq!(light(headless.world_mut()), core.light());
+            assert_eq!(cars(headless.world_mut()), core.cars());
+        }
+        assert!(core
+            .cars()
+            .iter()
+            .any(|car| car.lane == 1 && car.route.is_empty()));
+    }
+}
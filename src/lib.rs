@@ -0,0 +1,28 @@
+// Bevy queries and system parameters are complex types by nature
+#![allow(clippy::type_complexity)]
+
+pub mod calibration;
+pub mod camera;
+pub mod car_fleet;
+pub mod cli;
+pub mod comparison;
+pub mod database;
+pub mod headless;
+pub mod metrics;
+pub mod optimizer;
+pub mod replay;
+pub mod replications;
+pub mod rewind;
+pub mod rl_env;
+pub mod rng;
+pub mod road;
+pub mod roundabout;
+pub mod routing;
+pub mod scenario;
+pub mod sim_clock;
+pub mod snapshot;
+pub mod sobol;
+pub mod sweep;
+pub mod traffic_light;
+pub mod trajectories;
+pub mod ui_components;
@@ -1,45 +1,214 @@
-mod camera;
-mod car_fleet;
-mod traffic_light;
-mod ui_components;
+use std::path::PathBuf;
 
 use bevy::prelude::*;
+use traffic_sim::{
+    calibration,
+    car_fleet::CarFleetPlugin,
+    cli::Flags,
+    comparison, headless,
+    metrics::MetricsPlugin,
+    optimizer,
+    replay::{self, GhostPlugin, Ghosts},
+    replications,
+    rng::SimulationRng,
+    roundabout,
+    scenario::{self, Scenario, ScenarioAsset, ScenarioLoader},
+    sim_clock::SimClockPlugin,
+    snapshot::{self, SimulationSnapshot},
+    sobol, sweep,
+    traffic_light::TrafficLightPlugin,
+    ui_components::SimUiPlugin,
+};
 
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        // Slow Motion
-        // .insert_resource(Time::<Fixed>::from_hz(10.0))
-        .add_event::<traffic_light::LightChange>()
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("optimize") => {
+            let config =
+                optimizer::OptimizerConfig::from_args(&args[1..]).unwrap_or_else(exit_with_usage);
+            if let Err(e) = optimizer::run(&config) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("calibrate") => {
+            let config = calibration::CalibrationConfig::from_args(&args[1..])
+                .unwrap_or_else(exit_with_usage);
+            if let Err(e) = calibration::run(&config) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("compare-control") => {
+            let config =
+                comparison::ComparisonConfig::from_args(&args[1..]).unwrap_or_else(exit_with_usage);
+            comparison::run(&config);
+        }
+        Some("replicate") => {
+            let config = replications::ReplicationConfig::from_args(&args[1..])
+                .unwrap_or_else(exit_with_usage);
+            replications::run(&config);
+        }
+        Some("replay") => {
+            let config =
+                replay::ReplayConfig::from_args(&args[1..]).unwrap_or_else(exit_with_usage);
+            if let Err(e) = replay::run(&config) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("sobol") => {
+            let config = sobol::SobolConfig::from_args(&args[1..]).unwrap_or_else(exit_with_usage);
+            sobol::run(&config);
+        }
+        Some("sweep") => {
+            let config = sweep::SweepConfig::from_args(&args[1..]).unwrap_or_else(exit_with_usage);
+            if let Err(e) = sweep::run(&config) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("roundabout-capacity") => {
+            let config =
+                roundabout::CapacityConfig::from_args(&args[1..]).unwrap_or_else(exit_with_usage);
+            roundabout::run(&config);
+        }
+        _ => {
+            let flags = Flags::parse(
+                &args,
+                &[
+                    "headless",
+                    "seed",
+                    "scenario",
+                    "layout",
+                    "control",
+                    "arrivals",
+                    "od",
+                    "travel-times",
+                    "reroute",
+                    "trajectories",
+                    "database",
+                    "sample-interval",
+                    "save-snapshot",
+                    "load-snapshot",
+                    "ghosts",
+                ],
+            )
+            .unwrap_or_else(exit_with_usage);
+            let scenario = Scenario::from_flags(&flags).unwrap_or_else(exit_with_usage);
+            let seed = flags.get("seed", 0).unwrap_or_else(exit_with_usage);
+            let headless_duration = flags
+                .get_optional::<f32>("headless")
+                .unwrap_or_else(exit_with_usage);
+            let output = headless::BatchOutput::from_flags(&flags).unwrap_or_else(exit_with_usage);
+            let start = flags
+                .get_optional::<SimulationSnapshot>("load-snapshot")
+                .unwrap_or_else(exit_with_usage);
+            let ghosts = flags
+                .get_optional::<String>("ghosts")
+                .unwrap_or_else(exit_with_usage)
+                .map(|path| Ghosts::load(&path).unwrap_or_else(exit_with_usage));
+            if let Some(duration) = headless_duration {
+                if ghosts.is_some() {
+                    exit_with_usage::<()>("'--ghosts' are only shown in the app".to_string());
+                }
+                if let Err(e) =
+                    headless::run_batch(scenario, duration, seed, start.as_ref(), &output)
+                {
+                    eprintln!("error: {}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            if output.trajectories.is_some() || output.database.is_some() {
+                exit_with_usage::<()>(
+                    "'--trajectories' and '--database' are only written with '--headless'"
+                        .to_string(),
+                );
+            }
+            let scenario_path = flags
+                .get_optional::<String>("scenario")
+                .unwrap_or_else(exit_with_usage);
+            run_app(
+                scenario,
+                scenario_path,
+                seed,
+                start,
+                output.snapshot,
+                ghosts,
+            );
+        }
+    }
+}
+
+fn exit_with_usage<T>(error: String) -> T {
+    eprintln!("error: {}", error);
+    eprintln!("usage: traffic-sim [--scenario <file.ron>] [--seed <seed>] [--headless <seconds>]");
+    eprintln!("                   [--trajectories <file.csv>] [--database <file.sqlite>]");
+    eprintln!("                   [--sample-interval <seconds>] [--load-snapshot <file.ron>]");
+    eprintln!("                   [--save-snapshot <file.ron>] (at the end, or on F5 in the app)");
+    eprintln!("                   [--ghosts <file.csv>] (recorded trajectories, shown in the app)");
+    eprintln!(
+        "       traffic-sim [--layout <layout>] [--control <control>] [--arrivals <arrivals>]"
+    );
+    eprintln!("                   [--od <file>] [--travel-times <times>] [--reroute <seconds>]");
+    eprintln!("       traffic-sim optimize [--scenario <file.ron>] [--objective <metric>] [--<flag> <value>]...");
+    eprintln!("       traffic-sim calibrate --trajectories <file.csv> [--fit spacing|speed|both]");
+    eprintln!("                         [--inputs <parameter>,...] [--<parameter> <min>:<max>]");
+    eprintln!("       traffic-sim compare-control [--seed <seed>] [--<flag> <value>]...");
+    eprintln!("       traffic-sim roundabout-capacity [--legs <n>] [--demand <start>:<end>:<step>] [--seed <seed>]");
+    eprintln!("       traffic-sim replay --trajectories <file.csv> [--scale <factor>] [--output <file.csv>]");
+    eprintln!("       traffic-sim replicate [--replications <n>] [--target <metric>:<half-width>]");
+    eprintln!("       traffic-sim sobol [--inputs <parameter>,...] [--samples <n>] [--<parameter> <min>:<max>]");
+    eprintln!("       traffic-sim sweep [--reaction-time <start>:<end>:<step>] [--green <range>]");
+    eprintln!(
+        "                         [--arrival-rate <range>] [--cars <range>] [--<flag> <value>]..."
+    );
+    eprintln!("                         [--database <file.sqlite>] [--sample-interval <seconds>]");
+    std::process::exit(2);
+}
+
+fn run_app(
+    scenario: Scenario,
+    scenario_path: Option<String>,
+    seed: u64,
+    start: Option<SimulationSnapshot>,
+    snapshot_file: Option<PathBuf>,
+    ghosts: Option<Ghosts>,
+) {
+    let mut app = App::new();
+    scenario.insert_into(&mut app);
+    app.insert_resource(SimulationRng::new(seed));
+    if let Some(scenario_path) = scenario_path {
+        scenario::watch_scenario_file(&mut app, &scenario_path);
+    }
+    if let Some(start) = start {
+        app.add_systems(PostStartup, move |world: &mut World| {
+            start.restore(world).unwrap_or_else(exit_with_usage)
+        });
+    }
+    if let Some(snapshot_file) = snapshot_file {
+        app.insert_resource(snapshot::SnapshotFile(snapshot_file))
+            .add_systems(Update, snapshot::save_on_key);
+    }
+    if let Some(ghosts) = ghosts {
+        app.insert_resource(ghosts).add_plugins(GhostPlugin);
+    }
+    app.add_plugins(DefaultPlugins)
         // .add_plugins((LogDiagnosticsPlugin::default(), FrameTimeDiagnosticsPlugin))
+        .add_plugins((
+            TrafficLightPlugin::default(),
+            CarFleetPlugin::default(),
+            MetricsPlugin,
+            SimClockPlugin,
+            SimUiPlugin::default(),
+        ))
         .add_systems(Startup, setup)
-        // Camera
-        .add_systems(Startup, camera::setup)
-        .add_systems(Update, camera::update)
-        // Tarffic Light
-        .add_systems(Startup, traffic_light::setup)
-        .add_systems(
-            Update,
-            (traffic_light::update_event_emitter, traffic_light::update),
-        )
-        .add_systems(PreUpdate, traffic_light::on_scene_loaded)
-        // Car Fleet
-        .add_systems(Startup, car_fleet::setup)
-        .add_systems(FixedUpdate, car_fleet::update)
-        // UI
-        .add_systems(Startup, ui_components::setup)
-        .add_systems(Update, ui_components::reset_simulation_button::update)
-        .add_systems(Update, ui_components::reaction_timer_controls::buttons_listenerr)
-        .add_systems(Update, ui_components::buttons_hover_effect::update)
-        // Simulation Reset
-        .add_event::<ui_components::reset_simulation_button::ResetSimluation>()
-        .add_systems(Update, camera::reset_simulation_listener)
-        .add_systems(Update, car_fleet::reset_simulation_listener)
-        .add_systems(Update, traffic_light::reset_simulation_listener)
-        // Reaction Time Modification
-        .add_event::<ui_components::reaction_timer_controls::ReactionTimeChanged>()
-        .add_systems(Update, car_fleet::reaction_time_changes_listener)
-        .add_systems(Update, ui_components::reaction_timer_controls::update_reaction_time_text)
+        // Scenario Hot-Reload
+        .init_asset::<ScenarioAsset>()
+        .init_asset_loader::<ScenarioLoader>()
+        .add_systems(Startup, scenario::load_watched)
+        .add_systems(Update, scenario::reload)
         .run();
 }
 
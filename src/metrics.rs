@@ -0,0 +1,221 @@
+use std::{collections::HashMap, str::FromStr};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    car_fleet::{
+        car::{Car, Performance, Velocity},
+        CarFleetSet,
+    },
+    road::{OnLane, RoadLayout},
+    traffic_light::{CurrentLight, Light, TrafficLight, TrafficLightSet},
+    ui_components::reset_simulation_button::ResetSimluation,
+};
+
+/**
+ * Measures the cars as they move, into `SimulationMetrics`, `CarTrips` and `JunctionEntries`, and
+ * logs the light's changes into `SignalChanges`
+ */
+pub struct MetricsPlugin;
+
+impl Plugin for MetricsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationMetrics>()
+            .init_resource::<CarTrips>()
+            .init_resource::<JunctionEntries>()
+            .init_resource::<SignalChanges>()
+            .add_event::<ResetSimluation>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    record.after(CarFleetSet::Movement),
+                    record_signal_changes.after(TrafficLightSet::Change),
+                ),
+            )
+            .add_systems(Update, reset_simulation_listener);
+    }
+}
+
+/**
+ * Performance measures of the intersection since the simulation started (or was reset).
+ * `total_delay` is in seconds, summed over all the cars that haven't passed the light yet.
+ */
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SimulationMetrics {
+    pub elapsed: f32,
+    pub total_delay: f32,
+    pub stops: u32,
+    pub throughput: u32,
+}
+
+impl SimulationMetrics {
+    /**
+     * What was accumulated between `earlier` and these metrics
+     */
+    pub fn since(&self, earlier: &SimulationMetrics) -> SimulationMetrics {
+        SimulationMetrics {
+            elapsed: self.elapsed - earlier.elapsed,
+            total_delay: self.total_delay - earlier.total_delay,
+            stops: self.stops - earlier.stops,
+            throughput: self.throughput - earlier.throughput,
+        }
+    }
+}
+
+/**
+ * One of `SimulationMetrics`' measures, for reporting on it by name
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Delay,
+    Stops,
+    Throughput,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 3] = [Metric::Delay, Metric::Stops, Metric::Throughput];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Delay => "delay",
+            Metric::Stops => "stops",
+            Metric::Throughput => "throughput",
+        }
+    }
+
+    pub fn value(&self, metrics: &SimulationMetrics) -> f32 {
+        match self {
+            Metric::Delay => metrics.total_delay,
+            Metric::Stops => metrics.stops as f32,
+            Metric::Throughput => metrics.throughput as f32,
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Metric::ALL
+            .into_iter()
+            .find(|metric| metric.name() == s)
+            .ok_or("expected one of: delay, stops, throughput".to_string())
+    }
+}
+
+/**
+ * How far `record` has measured a car: whether it's stopped, and whether it has passed the light
+ * (and from which lane)
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CarTrip {
+    pub stopped: bool,
+    pub passed_light: bool,
+    pub lane: usize,
+}
+
+#[derive(Resource, Default)]
+pub struct CarTrips(pub HashMap<Entity, CarTrip>);
+
+/**
+ * When (in seconds since the start) cars passed into the intersection, by the lane they came from
+ */
+#[derive(Resource, Debug, Clone, Default)]
+pub struct JunctionEntries(pub Vec<Vec<f32>>);
+
+impl JunctionEntries {
+    /**
+     * The flow (in vehicles per hour) `lane` discharged at between its first and last entry, which
+     * is its capacity as long as it had a queue the whole time. `None` under two entries.
+     */
+    pub fn saturation_flow(&self, lane: usize) -> Option<f32> {
+        let entries = self.0.get(lane)?;
+        let (first, last) = (entries.first()?, entries.last()?);
+        (entries.len() > 1 && last > first)
+            .then(|| 3600.0 * (entries.len() - 1) as f32 / (last - first))
+    }
+}
+
+/**
+ * When (in seconds since the start) the light changed and to what, starting with the light it
+ * started with at the first tick
+ */
+#[derive(Resource, Debug, Clone, Default)]
+pub struct SignalChanges(pub Vec<(f32, Light)>);
+
+pub fn record_signal_changes(
+    time: Res<Time>,
+    mut changes: ResMut<SignalChanges>,
+    traffic_light_q: Query<Ref<CurrentLight>, With<TrafficLight>>,
+) {
+    for current_light in traffic_light_q.iter() {
+        // A restored snapshot sets the light it already logged
+        if current_light.is_changed()
+            && changes.0.last().map(|(_, light)| *light) != Some(current_light.0)
+        {
+            changes.0.push((time.elapsed_seconds(), current_light.0));
+        }
+    }
+}
+
+pub fn record(
+    time: Res<Time>,
+    mut metrics: ResMut<SimulationMetrics>,
+    mut trips: ResMut<CarTrips>,
+    mut entries: ResMut<JunctionEntries>,
+    car_q: Query<(Entity, &Transform, &Velocity, &Performance, &OnLane), With<Car>>,
+    mut removed_cars: RemovedComponents<Car>,
+    road_layout: Res<RoadLayout>,
+) {
+    for car_entity in removed_cars.read() {
+        trips.0.remove(&car_entity);
+    }
+    let delta = time.delta_seconds();
+    metrics.elapsed += delta;
+    for (car_entity, car_transform, velocity, performance, on_lane) in car_q.iter() {
+        // Cars start standing still, that isn't counted as a stop
+        let trip = trips.0.entry(car_entity).or_insert(CarTrip {
+            stopped: velocity.0 == 0.0,
+            passed_light: false,
+            lane: on_lane.0,
+        });
+        if trip.passed_light {
+            continue;
+        }
+        // Lanes without a junction are inside or past the intersection
+        let lane = &road_layout.lanes[on_lane.0];
+        let position = lane.position_of(car_transform.translation);
+        if lane.junction.is_none_or(|junction| position > junction) {
+            trip.passed_light = true;
+            metrics.throughput += 1;
+            if entries.0.len() <= trip.lane {
+                entries.0.resize(trip.lane + 1, Vec::new());
+            }
+            entries.0[trip.lane].push(metrics.elapsed);
+            continue;
+        }
+        trip.lane = on_lane.0;
+        metrics.total_delay += delta * (1.0 - velocity.0 / performance.max_velocity);
+        let stopped = velocity.0 == 0.0;
+        if stopped && !trip.stopped {
+            metrics.stops += 1;
+        }
+        trip.stopped = stopped;
+    }
+}
+
+pub fn reset_simulation_listener(
+    mut reset_simulation_event: EventReader<ResetSimluation>,
+    mut metrics: ResMut<SimulationMetrics>,
+    mut trips: ResMut<CarTrips>,
+    mut entries: ResMut<JunctionEntries>,
+    mut signal_changes: ResMut<SignalChanges>,
+) {
+    for _ in reset_simulation_event.read() {
+        *metrics = SimulationMetrics::default();
+        trips.0.clear();
+        entries.0.clear();
+        signal_changes.0.clear();
+    }
+}
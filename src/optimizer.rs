@@ -0,0 +1,486 @@
+use std::{fs, path::PathBuf};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use ron::ser::PrettyConfig;
+use serde::Serialize;
+
+use crate::{
+    cli::Flags,
+    headless::HeadlessSimulation,
+    metrics::{Metric, SimulationMetrics},
+    scenario::Scenario,
+    traffic_light::SignalPlan,
+};
+
+/**
+ * Lower is better for every metric, throughput is negated so it can be minimized as well
+ */
+fn cost(objective: Metric, metrics: &SimulationMetrics) -> f32 {
+    match objective {
+        Metric::Throughput => -objective.value(metrics),
+        _ => objective.value(metrics),
+    }
+}
+
+pub struct OptimizerConfig {
+    /**
+     * Every plan is scored on it, in place of its own signal plan
+     */
+    pub scenario: Scenario,
+    pub population: usize,
+    pub generations: usize,
+    pub duration: f32,
+    pub min_cycle: f32,
+    pub max_cycle: f32,
+    pub yellow: f32,
+    pub objective: Metric,
+    pub seed: u64,
+    pub output: PathBuf,
+}
+
+impl OptimizerConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let flags = Flags::parse(
+            args,
+            &[
+                "scenario",
+                "layout",
+                "control",
+                "arrivals",
+                "od",
+                "travel-times",
+                "reroute",
+                "population",
+                "generations",
+                "duration",
+                "min-cycle",
+                "max-cycle",
+                "yellow",
+                "objective",
+                "seed",
+                "output",
+            ],
+        )?;
+        let scenario = Scenario::from_flags(&flags)?;
+        let config = OptimizerConfig {
+            population: flags.get("population", 20)?,
+            generations: flags.get("generations", 15)?,
+            duration: flags.get("duration", 120.0)?,
+            min_cycle: flags.get("min-cycle", 10.0)?,
+            max_cycle: flags.get("max-cycle", 60.0)?,
+            yellow: flags.get("yellow", scenario.signal_plan.yellow)?,
+            objective: flags.get("objective", Metric::Delay)?,
+            seed: flags.get("seed", 0)?,
+            output: flags.get("output", PathBuf::from("best_plan.ron"))?,
+            scenario,
+        };
+        if config.duration <= 0.0 {
+            return Err("'--duration' must be positive".to_string());
+        }
+        if config.population < 2 {
+            return Err("'--population' must be at least 2".to_string());
+        }
+        if config.min_cycle <= config.yellow || config.max_cycle < config.min_cycle {
+            return Err(
+                "'--min-cycle' must be longer than '--yellow' and not above '--max-cycle'"
+                    .to_string(),
+            );
+        }
+        Ok(config)
+    }
+}
+
+/**
+ * A candidate timing, `split` is the green's share of the non-yellow time and `offset` is a
+ * fraction of the cycle, so every gene stays valid whatever the cycle length is.
+ */
+#[derive(Debug, Clone, Copy)]
+struct Genome {
+    cycle: f32,
+    split: f32,
+    offset: f32,
+}
+
+const MIN_SPLIT: f32 = 0.1;
+const MAX_SPLIT: f32 = 0.9;
+const MUTATION_RATE: f32 = 0.2;
+const TOURNAMENT_SIZE: usize = 3;
+const ELITES: usize = 2;
+
+impl Genome {
+    fn random(rng: &mut StdRng, config: &OptimizerConfig) -> Self {
+        Genome {
+            cycle: rng.gen_range(config.min_cycle..=config.max_cycle),
+            split: rng.gen_range(MIN_SPLIT..=MAX_SPLIT),
+            offset: rng.gen_range(0.0..1.0),
+        }
+    }
+
+    fn to_plan(self, config: &OptimizerConfig) -> SignalPlan {
+        let green_and_red = self.cycle - config.yellow;
+        SignalPlan {
+            green: green_and_red * self.split,
+            yellow: config.yellow,
+            red: green_and_red * (1.0 - self.split),
+            offset: self.cycle * self.offset,
+            ..config.scenario.signal_plan
+        }
+    }
+
+    /**
+     * Blend crossover (BLX-0.5): each gene is drawn around the interval between the parents' genes
+     */
+    fn crossover(&self, other: &Genome, rng: &mut StdRng, config: &OptimizerConfig) -> Self {
+        let mut blend = |a: f32, b: f32, min: f32, max: f32| {
+            let spread = (a - b).abs() * 0.5;
+            let low = (a.min(b) - spread).max(min);
+            let high = (a.max(b) + spread).min(max);
+            if low < high {
+                rng.gen_range(low..=high)
+            } else {
+                low
+            }
+        };
+        Genome {
+            cycle: blend(self.cycle, other.cycle, config.min_cycle, config.max_cycle),
+            split: blend(self.split, other.split, MIN_SPLIT, MAX_SPLIT),
+            offset: blend(self.offset, other.offset, 0.0, 1.0),
+        }
+    }
+
+    fn mutate(&mut self, rng: &mut StdRng, config: &OptimizerConfig) {
+        let mut perturb = |gene: &mut f32, min: f32, max: f32| {
+            if rng.gen::<f32>() < MUTATION_RATE {
+                *gene = (*gene + gaussian(rng) * (max - min) * 0.1).clamp(min, max);
+            }
+        };
+        perturb(&mut self.cycle, config.min_cycle, config.max_cycle);
+        perturb(&mut self.split, MIN_SPLIT, MAX_SPLIT);
+        perturb(&mut self.offset, 0.0, 1.0);
+    }
+}
+
+/**
+ * Standard normal sample (Box-Muller)
+ */
+fn gaussian(rng: &mut StdRng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+struct Evaluated {
+    genome: Genome,
+    plan: SignalPlan,
+    metrics: SimulationMetrics,
+}
+
+fn evaluate(genome: Genome, config: &OptimizerConfig) -> Evaluated {
+    let plan = genome.to_plan(config);
+    let mut simulation = HeadlessSimulation::new(plan, config.seed);
+    simulation.set_scenario(Scenario {
+        signal_plan: plan,
+        ..config.scenario.clone()
+    });
+    let metrics = simulation.run_until(config.duration);
+    Evaluated {
+        genome,
+        plan,
+        metrics,
+    }
+}
+
+/**
+ * `a` dominates `b` if it's at least as good in delay, stops and throughput, and better in one of them
+ */
+fn dominates(a: &SimulationMetrics, b: &SimulationMetrics) -> bool {
+    let no_worse =
+        a.total_delay <= b.total_delay && a.stops <= b.stops && a.throughput >= b.throughput;
+    let better = a.total_delay < b.total_delay || a.stops < b.stops || a.throughput > b.throughput;
+    no_worse && better
+}
+
+fn pareto_front(evaluated: &[Evaluated]) -> Vec<&Evaluated> {
+    let mut front: Vec<&Evaluated> = evaluated
+        .iter()
+        .filter(|candidate| {
+            !evaluated
+                .iter()
+                .any(|other| dominates(&other.metrics, &candidate.metrics))
+        })
+        .collect();
+    front.sort_by(|a, b| a.metrics.total_delay.total_cmp(&b.metrics.total_delay));
+    front.dedup_by(|a, b| a.metrics == b.metrics);
+    front
+}
+
+fn tournament<'a>(
+    population: &'a [Evaluated],
+    rng: &mut StdRng,
+    objective: Metric,
+) -> &'a Evaluated {
+    (0..TOURNAMENT_SIZE)
+        .map(|_| &population[rng.gen_range(0..population.len())])
+        .min_by(|a, b| cost(objective, &a.metrics).total_cmp(&cost(objective, &b.metrics)))
+        .unwrap()
+}
+
+fn print_plan(candidate: &Evaluated) {
+    println!(
+        "{:>7.1} {:>7.1} {:>7.1} {:>7.1} {:>7.1} | {:>10.1} {:>6} {:>10}",
+        candidate.plan.cycle(),
+        candidate.plan.green,
+        candidate.plan.yellow,
+        candidate.plan.red,
+        candidate.plan.offset,
+        candidate.metrics.total_delay,
+        candidate.metrics.stops,
+        candidate.metrics.throughput,
+    );
+}
+
+/**
+ * A scenario file setting only the signal plan
+ */
+#[derive(Serialize)]
+struct PlanFile {
+    signal_plan: SignalPlan,
+}
+
+/**
+ * Writes the plan as a scenario file, `--scenario` runs it on the default road and demand, or its
+ * `signal_plan` can be copied into the scenario it was optimized for
+ */
+fn write_plan(config: &OptimizerConfig, best: &Evaluated) -> std::io::Result<()> {
+    let plan = ron::ser::to_string_pretty(
+        &PlanFile {
+            signal_plan: best.plan,
+        },
+        PrettyConfig::default(),
+    )
+    .map_err(std::io::Error::other)?;
+    let contents = format!(
+        "// Best signal plan found by `traffic-sim optimize` (objective: {})\n\
+         // delay = {:.1}, stops = {}, throughput = {}\n\
+         {}\n",
+        config.objective.name(),
+        best.metrics.total_delay,
+        best.metrics.stops,
+        best.metrics.throughput,
+        plan,
+    );
+    fs::write(&config.output, contents)
+}
+
+/**
+ * Genetic search over the signal's cycle, split and offset, every candidate is scored by running the
+ * headless simulation of the scenario for `config.duration` seconds (all with the same seed, so
+ * they're compared on the same arrivals).
+ */
+pub fn run(config: &OptimizerConfig) -> std::io::Result<()> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut population: Vec<Evaluated> = (0..config.population)
+        .map(|_| evaluate(Genome::random(&mut rng, config), config))
+        .collect();
+    let mut archive: Vec<Evaluated> = Vec::new();
+
+    for generation in 1..=config.generations {
+        population.sort_by(|a, b| {
+            cost(config.objective, &a.metrics).total_cmp(&cost(config.objective, &b.metrics))
+        });
+        println!(
+            "generation {}/{}: best {} = {:.1}",
+            generation,
+            config.generations,
+            config.objective.name(),
+            config.objective.value(&population[0].metrics)
+        );
+        let mut next_generation: Vec<Genome> = population
+            .iter()
+            .take(ELITES)
+            .map(|elite| elite.genome)
+            .collect();
+        while next_generation.len() < config.population {
+            let first_parent = tournament(&population, &mut rng, config.objective);
+            let second_parent = tournament(&population, &mut rng, config.objective);
+            let mut child = first_parent
+                .genome
+                .crossover(&second_parent.genome, &mut rng, config);
+            child.mutate(&mut rng, config);
+            next_generation.push(child);
+        }
+        // Elites are kept as they are, there's no need to simulate them again
+        let elites = population.drain(..ELITES.min(population.len()));
+        let mut evaluated_next: Vec<Evaluated> = elites.collect();
+        archive.append(&mut population);
+        evaluated_next.extend(
+            next_generation
+                .into_iter()
+                .skip(evaluated_next.len())
+                .map(|genome| evaluate(genome, config)),
+        );
+        population = evaluated_next;
+    }
+    archive.append(&mut population);
+
+    println!("\nPareto front (delay, stops, throughput):");
+    println!(
+        "{:>7} {:>7} {:>7} {:>7} {:>7} | {:>10} {:>6} {:>10}",
+        "cycle", "green", "yellow", "red", "offset", "delay", "stops", "throughput"
+    );
+    for candidate in pareto_front(&archive) {
+        print_plan(candidate);
+    }
+
+    let best = archive
+        .iter()
+        .min_by(|a, b| {
+            cost(config.objective, &a.metrics).total_cmp(&cost(config.objective, &b.metrics))
+        })
+        .unwrap();
+    write_plan(config, best)?;
+    println!(
+        "\nBest plan for {} written to {}",
+        config.objective.name(),
+        config.output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OptimizerConfig {
+        OptimizerConfig::from_args(&[]).unwrap()
+    }
+
+    fn assert_valid(genome: &Genome, config: &OptimizerConfig) {
+        assert!(
+            (config.min_cycle..=config.max_cycle).contains(&genome.cycle)
+                && (MIN_SPLIT..=MAX_SPLIT).contains(&genome.split)
+                && (0.0..=1.0).contains(&genome.offset),
+            "{:?}",
+            genome
+        );
+    }
+
+    fn evaluated(total_delay: f32, stops: u32, throughput: u32) -> Evaluated {
+        Evaluated {
+            genome: Genome {
+                cycle: 30.0,
+                split: 0.5,
+                offset: 0.0,
+            },
+            plan: SignalPlan::default(),
+            metrics: SimulationMetrics {
+                elapsed: 120.0,
+                total_delay,
+                stops,
+                throughput,
+            },
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_flags() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(OptimizerConfig::from_args(&args(&["--duration", "0"])).is_err());
+        assert!(OptimizerConfig::from_args(&args(&["--duration", "-10"])).is_err());
+        assert!(OptimizerConfig::from_args(&args(&["--population", "1"])).is_err());
+        assert!(OptimizerConfig::from_args(&args(&["--min-cycle", "0.5"])).is_err());
+        assert!(OptimizerConfig::from_args(&args(&["--max-cycle", "5"])).is_err());
+    }
+
+    #[test]
+    fn plans_keep_the_cycle_and_the_yellow() {
+        let config = config();
+        let plan = Genome {
+            cycle: 40.0,
+            split: 0.25,
+            offset: 0.5,
+        }
+        .to_plan(&config);
+        assert_eq!(plan.cycle(), 40.0);
+        assert_eq!(plan.yellow, config.yellow);
+        assert_eq!(plan.green, (40.0 - config.yellow) * 0.25);
+        assert_eq!(plan.offset, 20.0);
+    }
+
+    #[test]
+    fn crossover_stays_between_the_bounds() {
+        let config = config();
+        let mut rng = StdRng::seed_from_u64(0);
+        let a = Genome {
+            cycle: config.min_cycle,
+            split: MIN_SPLIT,
+            offset: 0.0,
+        };
+        let b = Genome {
+            cycle: config.max_cycle,
+            split: MAX_SPLIT,
+            offset: 1.0,
+        };
+        for _ in 0..1000 {
+            assert_valid(&a.crossover(&b, &mut rng, &config), &config);
+        }
+        // Identical parents have nothing to blend
+        let child = a.crossover(&a, &mut rng, &config);
+        assert_eq!(
+            (child.cycle, child.split, child.offset),
+            (a.cycle, a.split, a.offset)
+        );
+    }
+
+    #[test]
+    fn mutation_stays_between_the_bounds() {
+        let config = config();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut genome = Genome::random(&mut rng, &config);
+        let mut mutated = 0;
+        for _ in 0..1000 {
+            let before = genome;
+            genome.mutate(&mut rng, &config);
+            assert_valid(&genome, &config);
+            if genome.cycle != before.cycle {
+                mutated += 1;
+            }
+        }
+        // Every gene mutates with `MUTATION_RATE`
+        assert!((150..250).contains(&mutated), "{}", mutated);
+    }
+
+    #[test]
+    fn pareto_front_keeps_the_non_dominated_plans() {
+        let archive = [
+            evaluated(50.0, 10, 20),
+            // Dominated by the first
+            evaluated(60.0, 10, 20),
+            evaluated(40.0, 12, 20),
+            evaluated(70.0, 5, 18),
+            // The same metrics as the first, kept once
+            evaluated(50.0, 10, 20),
+            // Dominated by the first, only its throughput is as good
+            evaluated(55.0, 11, 20),
+        ];
+        let front = pareto_front(&archive)
+            .into_iter()
+            .map(|candidate| {
+                (
+                    candidate.metrics.total_delay,
+                    candidate.metrics.stops,
+                    candidate.metrics.throughput,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(front, vec![(40.0, 12, 20), (50.0, 10, 20), (70.0, 5, 18)]);
+    }
+
+    #[test]
+    fn costs_are_minimized() {
+        let metrics = evaluated(50.0, 10, 20).metrics;
+        assert_eq!(cost(Metric::Delay, &metrics), 50.0);
+        assert_eq!(cost(Metric::Stops, &metrics), 10.0);
+        assert_eq!(cost(Metric::Throughput, &metrics), -20.0);
+    }
+}
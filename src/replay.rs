@@ -0,0 +1,919 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+};
+
+use bevy::{prelude::*, utils::Instant};
+use traffic_core::{
+    driver::{CarSnapshot, CarState, Intersection, ModelParameters, Performance},
+    road::{IntersectionControl, RoadLayout},
+    signal::Light,
+};
+
+use crate::{cli::Flags, metrics::SimulationMetrics, scenario::Scenario, sweep::Parameter};
+
+/*
+The driver parameters a replay's flags can override, the ones car-following depends on
+ */
+pub const DRIVER_PARAMETERS: [Parameter; 5] = [
+    Parameter::ReactionTime,
+    Parameter::MaxVelocity,
+    Parameter::Acceleration,
+    Parameter::Deceleration,
+    Parameter::BreakDistance,
+];
+
+/*
+How far apart (in seconds) a leader's and a follower's records can be and still count as recorded
+at the same time
+ */
+const TIME_TOLERANCE: f32 = 0.001;
+
+/**
+ * A vehicle's recorded state, `time` in seconds since the dataset's first record and `position`
+ * and `velocity` (per second) already scaled to the road's units
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedPoint {
+    pub time: f32,
+    pub position: f32,
+    pub velocity: f32,
+    /**
+     * The vehicle ahead, 0 for none as in NGSIM
+     */
+    pub preceding: u64,
+    /**
+     * The lane `position` is along, 0 without a `Lane_ID` column
+     */
+    pub lane: usize,
+}
+
+/**
+ * Every vehicle's points by ID, in time order
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Dataset(pub BTreeMap<u64, Vec<RecordedPoint>>);
+
+/**
+ * How the CSV's values are turned into the simulation's
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Units {
+    /**
+     * Multiplies positions and velocities, e.g. 0.3048 for NGSIM's feet into meters
+     */
+    pub scale: f32,
+    /**
+     * Seconds per `Frame_ID`, only used without a `Global_Time` column
+     */
+    pub frame_interval: f32,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units {
+            scale: 1.0,
+            frame_interval: 0.1,
+        }
+    }
+}
+
+impl Dataset {
+    /**
+     * Reads a trajectory CSV with NGSIM's column names: `Vehicle_ID`, `Preceding`, `v_Vel`, the
+     * time from `Global_Time` (in milliseconds) or `Frame_ID` and the position along the road from
+     * `Position` (as `--trajectories` writes it) or `Local_Y`, and the lane from `Lane_ID` if there's
+     * one. Other columns are ignored.
+     */
+    pub fn parse(content: &str, path: &str, units: Units) -> Result<Self, String> {
+        let mut lines = content.lines().enumerate();
+        let Some((_, header)) = lines.next() else {
+            return Err(format!("{}: the file is empty", path));
+        };
+        let columns = header
+            .split(',')
+            .enumerate()
+            .map(|(i, name)| (name.trim(), i))
+            .collect::<HashMap<&str, usize>>();
+        let column = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| columns.get(name).copied())
+                .ok_or_else(|| format!("{}: no '{}' column", path, names.join("' or '")))
+        };
+        let vehicle = column(&["Vehicle_ID"])?;
+        let preceding = column(&["Preceding"])?;
+        let velocity = column(&["v_Vel"])?;
+        let position = column(&["Position", "Local_Y"])?;
+        let lane = column(&["Lane_ID"]).ok();
+        let (time, time_scale) = match column(&["Global_Time"]) {
+            Ok(time) => (time, 0.001),
+            Err(_) => (column(&["Frame_ID"])?, units.frame_interval as f64),
+        };
+
+        // NGSIM's global times are milliseconds since 1970, too big for an f32 until the first is
+        // subtracted
+        let mut records = Vec::new();
+        for (i, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = line.split(',').map(str::trim).collect::<Vec<&str>>();
+            let field = |column: usize| {
+                fields
+                    .get(column)
+                    .ok_or_else(|| format!("{}:{}: missing fields", path, i + 1))
+            };
+            let number = |column: usize| -> Result<f64, String> {
+                let value = field(column)?;
+                value
+                    .parse()
+                    .map_err(|_| format!("{}:{}: '{}' isn't a number", path, i + 1, value))
+            };
+            let id = |column: usize| -> Result<u64, String> {
+                let value = field(column)?;
+                value
+                    .parse()
+                    .map_err(|_| format!("{}:{}: '{}' isn't a vehicle ID", path, i + 1, value))
+            };
+            let lane_index = |column: usize| -> Result<usize, String> {
+                let value = field(column)?;
+                value
+                    .parse()
+                    .map_err(|_| format!("{}:{}: '{}' isn't a lane", path, i + 1, value))
+            };
+            records.push((
+                id(vehicle)?,
+                number(time)? * time_scale,
+                number(position)? as f32 * units.scale,
+                number(velocity)? as f32 * units.scale,
+                id(preceding)?,
+                lane.map(lane_index).transpose()?.unwrap_or_default(),
+            ));
+        }
+        let start = records
+            .iter()
+            .map(|record| record.1)
+            .min_by(f64::total_cmp)
+            .unwrap_or_default();
+        let mut dataset = Dataset::default();
+        for (vehicle, time, position, velocity, preceding, lane) in records {
+            dataset.0.entry(vehicle).or_default().push(RecordedPoint {
+                time: (time - start) as f32,
+                position,
+                velocity,
+                preceding,
+                lane,
+            });
+        }
+        for points in dataset.0.values_mut() {
+            points.sort_by(|a, b| a.time.total_cmp(&b.time));
+        }
+        Ok(dataset)
+    }
+
+    /**
+     * Every stretch of at least `min_duration` seconds where a vehicle followed the same leader
+     * and both were recorded at the same times
+     */
+    pub fn pairs(&self, min_duration: f32) -> Vec<Pair> {
+        let mut pairs = Vec::new();
+        for (follower, points) in self.0.iter() {
+            let mut current: Option<Pair> = None;
+            for point in points {
+                let leader_point = self.0.get(&point.preceding).and_then(|leader_points| {
+                    let at = leader_points.partition_point(|leader_point| {
+                        leader_point.time < point.time - TIME_TOLERANCE
+                    });
+                    leader_points
+                        .get(at)
+                        .filter(|leader_point| leader_point.time <= point.time + TIME_TOLERANCE)
+                });
+                let continues = current
+                    .as_ref()
+                    .is_some_and(|pair| pair.leader == point.preceding && leader_point.is_some());
+                if !continues {
+                    pairs.extend(
+                        current
+                            .take()
+                            .filter(|pair| pair.duration() >= min_duration),
+                    );
+                    if leader_point.is_some() {
+                        current = Some(Pair {
+                            leader: point.preceding,
+                            follower: *follower,
+                            leader_points: Vec::new(),
+                            follower_points: Vec::new(),
+                        });
+                    }
+                }
+                if let (Some(pair), Some(leader_point)) = (current.as_mut(), leader_point) {
+                    pair.leader_points.push(*leader_point);
+                    pair.follower_points.push(*point);
+                }
+            }
+            pairs.extend(current.filter(|pair| pair.duration() >= min_duration));
+        }
+        pairs
+    }
+}
+
+/**
+ * A follower and its leader over the same recorded times
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pair {
+    pub leader: u64,
+    pub follower: u64,
+    pub leader_points: Vec<RecordedPoint>,
+    pub follower_points: Vec<RecordedPoint>,
+}
+
+impl Pair {
+    pub fn duration(&self) -> f32 {
+        match (self.follower_points.first(), self.follower_points.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.0,
+        }
+    }
+
+    /**
+     * The leader's position and velocity (per second) at `time`, between its recorded points
+     */
+    fn leader_at(&self, time: f32) -> (f32, f32) {
+        let next = self
+            .leader_points
+            .partition_point(|point| point.time < time)
+            .clamp(1, self.leader_points.len() - 1);
+        let (before, after) = (self.leader_points[next - 1], self.leader_points[next]);
+        let t = ((time - before.time) / (after.time - before.time)).clamp(0.0, 1.0);
+        (
+            before.position + (after.position - before.position) * t,
+            before.velocity + (after.velocity - before.velocity) * t,
+        )
+    }
+}
+
+/**
+ * The simulated follower's driver
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Driver {
+    pub performance: Performance,
+    pub reaction_time: f32,
+    pub model: ModelParameters,
+}
+
+impl Driver {
+    /**
+     * The scenario's first vehicle class, with its driver model
+     */
+    pub fn from_scenario(scenario: &Scenario) -> Self {
+        let class = &scenario.vehicle_classes.0[0];
+        Driver {
+            performance: class.performance(),
+            reaction_time: class.reaction_time,
+            model: scenario.model_parameters,
+        }
+    }
+
+    /**
+     * One of `DRIVER_PARAMETERS`, in the same units as its flag
+     */
+    pub fn get(&self, parameter: Parameter) -> f32 {
+        match parameter {
+            Parameter::ReactionTime => self.reaction_time,
+            Parameter::MaxVelocity => self.performance.max_velocity,
+            Parameter::Acceleration => self.performance.acceleration,
+            Parameter::Deceleration => self.performance.deceleration,
+            Parameter::BreakDistance => self.model.break_distance,
+            _ => panic!("'{}' isn't a driver parameter", parameter.flag()),
+        }
+    }
+
+    pub fn set(&mut self, parameter: Parameter, value: f32) {
+        match parameter {
+            Parameter::ReactionTime => self.reaction_time = value,
+            Parameter::MaxVelocity => self.performance.max_velocity = value,
+            Parameter::Acceleration => self.performance.acceleration = value,
+            Parameter::Deceleration => self.performance.deceleration = value,
+            Parameter::BreakDistance => self.model.break_distance = value,
+            _ => panic!("'{}' isn't a driver parameter", parameter.flag()),
+        }
+    }
+}
+
+/**
+ * The recorded follower and the simulated one at a recorded time, velocities per second
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub time: f32,
+    pub leader_position: f32,
+    pub recorded_position: f32,
+    pub simulated_position: f32,
+    pub recorded_velocity: f32,
+    pub simulated_velocity: f32,
+}
+
+impl Sample {
+    pub fn recorded_spacing(&self) -> f32 {
+        self.leader_position - self.recorded_position
+    }
+
+    pub fn simulated_spacing(&self) -> f32 {
+        self.leader_position - self.simulated_position
+    }
+}
+
+/**
+ * Drives a simulated follower behind the recorded leader (a ghost the driver model sees but
+ * doesn't move), from where and as fast as the recorded follower started, and samples it at the
+ * recorded times. The road is a single lane without an intersection, so only car-following is
+ * compared.
+ */
+pub fn replay(pair: &Pair, driver: &Driver) -> Vec<Sample> {
+    let timestep = Time::<Fixed>::default().timestep().as_secs_f32();
+    let layout = RoadLayout::default();
+    let intersection = Intersection {
+        control: IntersectionControl::default(),
+        light: Light::GreenLight,
+        minor_light: Light::RedLight,
+        layout: &layout,
+        model: &driver.model,
+    };
+    let first = pair.follower_points[0];
+    let mut car = CarState::new(
+        0,
+        first.position,
+        VecDeque::new(),
+        driver.performance,
+        driver.reaction_time,
+    );
+    // Velocities in the driver model are per tick
+    car.velocity = first.velocity * timestep;
+    // Already driving, so not waiting to react
+    car.reaction_timer.tick(driver.reaction_time);
+
+    let mut samples = Vec::with_capacity(pair.follower_points.len());
+    let mut now = first.time;
+    for point in pair.follower_points.iter() {
+        while now + timestep / 2.0 < point.time {
+            let (leader_position, leader_velocity) = pair.leader_at(now);
+            let ghost = CarSnapshot {
+                lane: 0,
+                position: leader_position,
+                velocity: leader_velocity * timestep,
+                stopped_at_line: None,
+            };
+            car.step(&[ghost], &intersection, now, timestep);
+            now += timestep;
+        }
+        samples.push(Sample {
+            time: point.time,
+            leader_position: pair.leader_at(point.time).0,
+            recorded_position: point.position,
+            simulated_position: car.position,
+            recorded_velocity: point.velocity,
+            simulated_velocity: car.velocity / timestep,
+        });
+    }
+    samples
+}
+
+/**
+ * Root-mean-square errors between the simulated and recorded followers over every sample
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayErrors {
+    pub samples: usize,
+    pub spacing_rmse: f32,
+    pub speed_rmse: f32,
+}
+
+impl ReplayErrors {
+    pub fn of<'a>(samples: impl IntoIterator<Item = &'a Sample>) -> Self {
+        let (mut count, mut spacing, mut speed) = (0, 0.0, 0.0);
+        for sample in samples {
+            count += 1;
+            spacing += (sample.simulated_spacing() - sample.recorded_spacing()).powi(2) as f64;
+            speed += (sample.simulated_velocity - sample.recorded_velocity).powi(2) as f64;
+        }
+        if count == 0 {
+            return ReplayErrors::default();
+        }
+        ReplayErrors {
+            samples: count,
+            spacing_rmse: (spacing / count as f64).sqrt() as f32,
+            speed_rmse: (speed / count as f64).sqrt() as f32,
+        }
+    }
+}
+
+/**
+ * Replays every pair with `driver`, the samples by pair
+ */
+pub fn replay_all(pairs: &[Pair], driver: &Driver) -> Vec<Vec<Sample>> {
+    pairs.iter().map(|pair| replay(pair, driver)).collect()
+}
+
+/*
+The flags `Dataset::from_flags` reads
+ */
+pub const DATASET_FLAGS: [&str; 4] = ["trajectories", "scale", "frame-interval", "min-duration"];
+
+impl Dataset {
+    /**
+     * Reads `--trajectories` with `--scale` and `--frame-interval`, and returns its pairs of at
+     * least `--min-duration` seconds
+     */
+    pub fn pairs_from_flags(flags: &Flags) -> Result<Vec<Pair>, String> {
+        let Some(path) = flags.get_optional::<String>("trajectories")? else {
+            return Err("'--trajectories <file.csv>' is required".to_string());
+        };
+        let units = Units {
+            scale: flags.get("scale", 1.0)?,
+            frame_interval: flags.get("frame-interval", 0.1)?,
+        };
+        if units.scale <= 0.0 || units.frame_interval <= 0.0 {
+            return Err("'--scale' and '--frame-interval' must be positive".to_string());
+        }
+        let min_duration = flags.get("min-duration", 5.0)?;
+        if min_duration <= 0.0 {
+            return Err("'--min-duration' must be positive".to_string());
+        }
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("couldn't read '{}': {}", path, e))?;
+        let pairs = Dataset::parse(&content, &path, units)?.pairs(min_duration);
+        if pairs.is_empty() {
+            return Err(format!(
+                "{}: no vehicle followed the same recorded leader for {}s",
+                path, min_duration
+            ));
+        }
+        Ok(pairs)
+    }
+}
+
+pub struct ReplayConfig {
+    pub pairs: Vec<Pair>,
+    pub driver: Driver,
+    pub output: Option<PathBuf>,
+}
+
+impl ReplayConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut known_flags = vec!["scenario", "output"];
+        known_flags.extend(DATASET_FLAGS);
+        known_flags.extend(DRIVER_PARAMETERS.map(|parameter| parameter.flag()));
+        let flags = Flags::parse(args, &known_flags)?;
+        let mut scenario = flags.get("scenario", Scenario::default())?;
+        for parameter in DRIVER_PARAMETERS {
+            if let Some(value) = flags.get_optional(parameter.flag())? {
+                parameter.check(value)?;
+                parameter.apply(value, &mut scenario);
+            }
+        }
+        Ok(ReplayConfig {
+            pairs: Dataset::pairs_from_flags(&flags)?,
+            driver: Driver::from_scenario(&scenario),
+            output: flags.get_optional("output")?,
+        })
+    }
+}
+
+/**
+ * Replays every leader-follower pair of the dataset and prints the errors, optionally writing
+ * every sample as CSV
+ */
+pub fn run(config: &ReplayConfig) -> std::io::Result<()> {
+    let started = Instant::now();
+    let pairs = &config.pairs;
+    let replays = replay_all(pairs, &config.driver);
+    println!(
+        "{:>8} {:>8} | {:>8} {:>8} | {:>12} {:>10}",
+        "leader", "follower", "duration", "samples", "spacing rmse", "speed rmse"
+    );
+    for (pair, samples) in pairs.iter().zip(replays.iter()) {
+        let errors = ReplayErrors::of(samples);
+        println!(
+            "{:>8} {:>8} | {:>8.1} {:>8} | {:>12.3} {:>10.3}",
+            pair.leader,
+            pair.follower,
+            pair.duration(),
+            errors.samples,
+            errors.spacing_rmse,
+            errors.speed_rmse
+        );
+    }
+    let errors = ReplayErrors::of(replays.iter().flatten());
+    println!(
+        "{:>17} | {:>8} {:>8} | {:>12.3} {:>10.3}",
+        "all", "", errors.samples, errors.spacing_rmse, errors.speed_rmse
+    );
+
+    if let Some(output) = &config.output {
+        let mut csv = String::from(
+            "leader,follower,time,leader_position,recorded_position,simulated_position,recorded_velocity,simulated_velocity\n",
+        );
+        for (pair, samples) in pairs.iter().zip(replays.iter()) {
+            for sample in samples {
+                writeln!(
+                    csv,
+                    "{},{},{},{},{},{},{},{}",
+                    pair.leader,
+                    pair.follower,
+                    sample.time,
+                    sample.leader_position,
+                    sample.recorded_position,
+                    sample.simulated_position,
+                    sample.recorded_velocity,
+                    sample.simulated_velocity
+                )
+                .unwrap();
+            }
+        }
+        fs::write(output, csv)?;
+        eprintln!("wrote the samples to {}", output.display());
+    }
+    eprintln!(
+        "replayed {} pairs in {:.2}s",
+        pairs.len(),
+        started.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
+
+/*
+The size of the box a recorded vehicle is drawn as, about a car's
+ */
+const GHOST_SIZE: Vec3 = Vec3::new(1.8, 1.4, 4.5);
+
+/**
+ * Shows the vehicles of `Ghosts` beside the simulated cars, as translucent boxes at their
+ * recorded positions when as much simulated time has passed. The simulated cars don't see them.
+ */
+pub struct GhostPlugin;
+
+impl Plugin for GhostPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, setup_ghosts)
+            .add_systems(Update, move_ghosts.run_if(resource_exists::<Ghosts>));
+    }
+}
+
+/**
+ * The recorded vehicles to show, the dataset's first record at the simulation's start
+ */
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Ghosts(pub Dataset);
+
+impl Ghosts {
+    /**
+     * Reads a trajectory CSV (see `Dataset::parse`) in the road's units, like the one
+     * `--trajectories` writes
+     */
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("couldn't read '{}': {}", path, e))?;
+        Ok(Ghosts(Dataset::parse(&content, path, Units::default())?))
+    }
+}
+
+/**
+ * A recorded vehicle's box, by the vehicle's ID in the dataset
+ */
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ghost(pub u64);
+
+#[derive(Resource)]
+struct GhostAppearance {
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
+fn setup_ghosts(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.insert_resource(GhostAppearance {
+        mesh: meshes.add(Cuboid::from_size(GHOST_SIZE)),
+        material: materials.add(StandardMaterial {
+            base_color: Color::srgba(0.6, 0.8, 1.0, 0.4),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..default()
+        }),
+    });
+}
+
+/**
+ * Where the vehicle was at `time`, between its recorded points. `None` before its first point,
+ * after its last and on a lane the road doesn't have.
+ */
+fn recorded_at(points: &[RecordedPoint], time: f32, road_layout: &RoadLayout) -> Option<Transform> {
+    let (first, last) = (points.first()?, points.last()?);
+    if time < first.time || time > last.time {
+        return None;
+    }
+    let next = points.partition_point(|point| point.time <= time);
+    let before = points[next - 1];
+    let after = points.get(next).copied().unwrap_or(before);
+    let lane = road_layout.lanes.get(before.lane)?;
+    let position = if after.lane == before.lane && after.time > before.time {
+        // Along the lane, so around a roundabout it doesn't go back across the circle's start
+        let driven = lane
+            .distance_ahead(before.position, after.position)
+            .unwrap_or(after.position - before.position);
+        before.position + driven * (time - before.time) / (after.time - before.time)
+    } else {
+        before.position
+    };
+    Some(
+        Transform::from_translation(lane.point_at(position) + Vec3::Y * GHOST_SIZE.y / 2.0)
+            .with_rotation(lane.rotation_at(position)),
+    )
+}
+
+/**
+ * Moves every ghost to where its vehicle was at the simulation's time, adding the vehicles that
+ * appear and removing those that are gone (or on a lane the road doesn't have)
+ */
+fn move_ghosts(
+    mut commands: Commands,
+    ghosts: Res<Ghosts>,
+    appearance: Res<GhostAppearance>,
+    metrics: Res<SimulationMetrics>,
+    road_layout: Res<RoadLayout>,
+    mut ghost_q: Query<(Entity, &Ghost, &mut Transform)>,
+) {
+    let Ghosts(Dataset(vehicles)) = &*ghosts;
+    let at = |vehicle: &u64| {
+        vehicles
+            .get(vehicle)
+            .and_then(|points| recorded_at(points, metrics.elapsed, &road_layout))
+    };
+    let mut shown = HashSet::new();
+    for (entity, ghost, mut transform) in ghost_q.iter_mut() {
+        match at(&ghost.0) {
+            Some(recorded) => {
+                *transform = recorded;
+                shown.insert(ghost.0);
+            }
+            None => commands.entity(entity).despawn_recursive(),
+        }
+    }
+    for vehicle in vehicles.keys().filter(|vehicle| !shown.contains(*vehicle)) {
+        if let Some(recorded) = at(vehicle) {
+            commands.spawn((
+                PbrBundle {
+                    mesh: appearance.mesh.clone(),
+                    material: appearance.material.clone(),
+                    transform: recorded,
+                    ..default()
+                },
+                Ghost(*vehicle),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(time: f32, lane: usize, position: f32) -> RecordedPoint {
+        RecordedPoint {
+            time,
+            position,
+            velocity: 10.0,
+            preceding: 0,
+            lane,
+        }
+    }
+
+    #[test]
+    fn reads_lanes_when_there_are_some() {
+        let csv = "Vehicle_ID,Global_Time,Position,v_Vel,Lane_ID,Preceding
+1,1000,-50,10,2,0
+1,1500,-45,10,2,0
+";
+        let dataset = Dataset::parse(csv, "file.csv", Units::default()).unwrap();
+        assert_eq!(
+            dataset.0[&1],
+            vec![point(0.0, 2, -50.0), point(0.5, 2, -45.0)]
+        );
+
+        let without_lanes = csv.replace(",Lane_ID", "").replace(",2,", ",");
+        let dataset = Dataset::parse(&without_lanes, "file.csv", Units::default()).unwrap();
+        assert_eq!(dataset.0[&1][1].lane, 0);
+
+        let bad_lane = csv.replace("-45,10,2", "-45,10,x");
+        assert_eq!(
+            Dataset::parse(&bad_lane, "file.csv", Units::default()).unwrap_err(),
+            "file.csv:3: 'x' isn't a lane"
+        );
+    }
+
+    #[test]
+    fn ghosts_follow_the_recorded_points() {
+        let road_layout = RoadLayout::crossroads();
+        let points = [
+            point(1.0, 0, -20.0),
+            point(2.0, 0, -10.0),
+            point(3.0, 1, 2.0),
+        ];
+        let position_at = |time: f32| {
+            recorded_at(&points, time, &road_layout).map(|transform| transform.translation)
+        };
+        let lift = Vec3::Y * GHOST_SIZE.y / 2.0;
+        assert_eq!(position_at(0.5), None);
+        assert_eq!(position_at(1.0), Some(Vec3::new(0.0, 0.0, -20.0) + lift));
+        assert_eq!(position_at(1.5), Some(Vec3::new(0.0, 0.0, -15.0) + lift));
+        // It turned between the points, so it stays where it was until the next one
+        assert_eq!(position_at(2.5), Some(Vec3::new(0.0, 0.0, -10.0) + lift));
+        assert_eq!(position_at(3.0), Some(Vec3::new(2.0, 0.0, 0.0) + lift));
+        assert_eq!(position_at(3.5), None);
+        assert_eq!(recorded_at(&[point(0.0, 5, 0.0)], 0.0, &road_layout), None);
+    }
+
+    #[test]
+    fn ghosts_drive_on_around_a_roundabout() {
+        let road_layout = RoadLayout::roundabout(4, 10.0);
+        let circumference = std::f32::consts::TAU * 10.0;
+        let points = [point(0.0, 0, circumference - 1.0), point(1.0, 0, 1.0)];
+        let halfway = recorded_at(&points, 0.5, &road_layout).unwrap().translation;
+        assert!((halfway - Vec3::new(10.0, GHOST_SIZE.y / 2.0, 0.0)).length() < 1e-3);
+    }
+
+    fn following(time: f32, position: f32, preceding: u64) -> RecordedPoint {
+        RecordedPoint {
+            preceding,
+            ..point(time, 0, position)
+        }
+    }
+
+    /**
+     * Vehicles 1 and 3 recorded every half second for 10s, and vehicle 2 following 1 until 4s
+     * then 3
+     */
+    fn dataset() -> Dataset {
+        let times = (0..=20).map(|i| i as f32 * 0.5);
+        Dataset(BTreeMap::from([
+            (1, times.clone().map(|t| following(t, 100.0, 0)).collect()),
+            (
+                2,
+                times
+                    .clone()
+                    .map(|t| following(t, 50.0, if t < 4.0 { 1 } else { 3 }))
+                    .collect(),
+            ),
+            (3, times.map(|t| following(t, 80.0, 0)).collect()),
+        ]))
+    }
+
+    fn stretch(pair: &Pair) -> (u64, u64, f32, f32) {
+        (
+            pair.leader,
+            pair.follower,
+            pair.follower_points[0].time,
+            pair.follower_points[pair.follower_points.len() - 1].time,
+        )
+    }
+
+    #[test]
+    fn pairs_end_when_the_leader_changes() {
+        let pairs = dataset().pairs(1.0);
+        assert_eq!(
+            pairs.iter().map(stretch).collect::<Vec<_>>(),
+            vec![(1, 2, 0.0, 3.5), (3, 2, 4.0, 10.0)]
+        );
+        for pair in pairs.iter() {
+            assert_eq!(pair.leader_points.len(), pair.follower_points.len());
+            for (leader, follower) in pair.leader_points.iter().zip(&pair.follower_points) {
+                assert_eq!(leader.time, follower.time);
+            }
+        }
+        // The first stretch is only 3.5s long
+        assert_eq!(
+            dataset().pairs(4.0).iter().map(stretch).collect::<Vec<_>>(),
+            vec![(3, 2, 4.0, 10.0)]
+        );
+    }
+
+    #[test]
+    fn pairs_need_the_leader_recorded_at_the_same_time() {
+        let mut dataset = dataset();
+        let leader = dataset.0.get_mut(&3).unwrap();
+        // Off by less than the tolerance still counts
+        leader[10].time += TIME_TOLERANCE / 2.0;
+        // A missing record (at 7s) splits the pair, and so does one recorded too far off (at
+        // 8.5s), leaving 7.5s to 8s too short
+        leader.remove(14);
+        leader[16].time += 0.01;
+        assert_eq!(
+            dataset.pairs(1.0).iter().map(stretch).collect::<Vec<_>>(),
+            vec![(1, 2, 0.0, 3.5), (3, 2, 4.0, 6.5), (3, 2, 9.0, 10.0)]
+        );
+    }
+
+    /**
+     * A leader that cruises, brakes to a stop and drives off again, followed by a car driven by
+     * `driver` itself, both recorded every tick
+     */
+    fn recorded_with(driver: &Driver) -> Dataset {
+        let timestep = Time::<Fixed>::default().timestep().as_secs_f32();
+        let layout = RoadLayout::default();
+        let intersection = Intersection {
+            control: IntersectionControl::default(),
+            light: Light::GreenLight,
+            minor_light: Light::RedLight,
+            layout: &layout,
+            model: &driver.model,
+        };
+        let mut follower = CarState::new(
+            0,
+            -230.0,
+            VecDeque::new(),
+            driver.performance,
+            driver.reaction_time,
+        );
+        follower.velocity = 0.1;
+        follower.reaction_timer.tick(driver.reaction_time);
+        let (mut leader_position, mut leader_velocity) = (-200.0_f32, 0.1_f32);
+        let mut dataset = Dataset::default();
+        for tick in 0..64 * 30 {
+            let time = tick as f32 * timestep;
+            let record = |position: f32, velocity: f32, preceding: u64| RecordedPoint {
+                velocity: velocity / timestep,
+                ..following(time, position, preceding)
+            };
+            dataset
+                .0
+                .entry(1)
+                .or_default()
+                .push(record(leader_position, leader_velocity, 0));
+            dataset
+                .0
+                .entry(2)
+                .or_default()
+                .push(record(follower.position, follower.velocity, 1));
+            let leader = CarSnapshot {
+                lane: 0,
+                position: leader_position,
+                velocity: leader_velocity,
+                stopped_at_line: None,
+            };
+            follower.step(&[leader], &intersection, time, timestep);
+            leader_velocity = match time {
+                time if time < 5.0 => 0.1,
+                time if time < 15.0 => (leader_velocity - 0.005).max(0.0),
+                _ => (leader_velocity + 0.002).min(0.1),
+            };
+            leader_position += leader_velocity;
+        }
+        dataset
+    }
+
+    #[test]
+    fn replays_its_own_driver_exactly() {
+        let driver = Driver::from_scenario(&Scenario::default());
+        let pairs = recorded_with(&driver).pairs(1.0);
+        assert_eq!(pairs.len(), 1);
+        let samples = replay(&pairs[0], &driver);
+        assert_eq!(samples.len(), pairs[0].follower_points.len());
+        // It did have to brake and wait behind the leader
+        assert!(samples.iter().any(|sample| sample.recorded_velocity == 0.0));
+        let errors = ReplayErrors::of(&samples);
+        assert_eq!(errors.samples, samples.len());
+        assert!(errors.spacing_rmse < 1e-3, "{:?}", errors);
+        assert!(errors.speed_rmse < 1e-3, "{:?}", errors);
+
+        let mut slower = driver;
+        slower.set(Parameter::ReactionTime, 1.5);
+        slower.set(Parameter::Acceleration, 0.001);
+        let errors = ReplayErrors::of(&replay(&pairs[0], &slower));
+        assert!(errors.spacing_rmse > 1.0, "{:?}", errors);
+        assert!(errors.speed_rmse > 0.1, "{:?}", errors);
+    }
+
+    #[test]
+    fn errors_are_root_mean_squares() {
+        let sample = |simulated_position: f32, simulated_velocity: f32| Sample {
+            time: 0.0,
+            leader_position: 100.0,
+            recorded_position: 50.0,
+            simulated_position,
+            recorded_velocity: 10.0,
+            simulated_velocity,
+        };
+        let errors = ReplayErrors::of(&[sample(53.0, 10.0), sample(46.0, 13.0)]);
+        assert_eq!(errors.samples, 2);
+        assert!((errors.spacing_rmse - 12.5_f32.sqrt()).abs() < 1e-5);
+        assert!((errors.speed_rmse - 4.5_f32.sqrt()).abs() < 1e-5);
+        assert_eq!(ReplayErrors::of(&[]), ReplayErrors::default());
+    }
+}
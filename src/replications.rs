@@ -0,0 +1,304 @@
+use std::str::FromStr;
+
+use bevy::utils::Instant;
+
+use crate::{
+    cli::Flags,
+    headless::{self, HeadlessSimulation},
+    metrics::{Metric, SimulationMetrics},
+    scenario::Scenario,
+};
+
+/*
+Student's t distribution's 97.5th percentiles for 1 to 30 degrees of freedom, the half-width of a
+two-sided 95% confidence interval in standard errors
+ */
+const T_975: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+/*
+The normal distribution's 97.5th percentile
+ */
+const Z_975: f64 = 1.959_964;
+
+/**
+ * The t distribution's 97.5th percentile for `degrees_of_freedom`, from the table up to 30 and
+ * from the Cornish-Fisher expansion around the normal distribution above (within 0.001)
+ */
+pub fn t_975(degrees_of_freedom: usize) -> f64 {
+    match degrees_of_freedom {
+        0 => f64::INFINITY,
+        1..=30 => T_975[degrees_of_freedom - 1],
+        _ => {
+            let df = degrees_of_freedom as f64;
+            Z_975
+                + (Z_975.powi(3) + Z_975) / (4.0 * df)
+                + (5.0 * Z_975.powi(5) + 16.0 * Z_975.powi(3) + 3.0 * Z_975) / (96.0 * df * df)
+        }
+    }
+}
+
+/**
+ * The sample mean and standard deviation of some replications, and the half-width of the 95%
+ * confidence interval of the mean
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub n: usize,
+    pub mean: f64,
+    pub sd: f64,
+    pub half_width: f64,
+}
+
+impl Summary {
+    pub fn of(values: &[f64]) -> Self {
+        let n = values.len();
+        let mean = values.iter().sum::<f64>() / n as f64;
+        let variance = values
+            .iter()
+            .map(|value| (value - mean).powi(2))
+            .sum::<f64>()
+            / (n as f64 - 1.0);
+        let sd = variance.sqrt();
+        Summary {
+            n,
+            mean,
+            sd,
+            half_width: t_975(n.saturating_sub(1)) * sd / (n as f64).sqrt(),
+        }
+    }
+
+    pub fn confidence_interval(&self) -> (f64, f64) {
+        (self.mean - self.half_width, self.mean + self.half_width)
+    }
+}
+
+/**
+ * Keep replicating until `metric`'s confidence interval is at most `half_width` either side of the
+ * mean, written `<metric>:<half-width>`
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub metric: Metric,
+    pub half_width: f64,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (metric, half_width) = s
+            .split_once(':')
+            .ok_or("expected <metric>:<half-width>, e.g. delay:5")?;
+        let half_width = match half_width.trim().parse::<f64>() {
+            Ok(half_width) if half_width > 0.0 => half_width,
+            _ => return Err(format!("'{}' isn't a positive half-width", half_width)),
+        };
+        Ok(Target {
+            metric: metric.trim().parse()?,
+            half_width,
+        })
+    }
+}
+
+pub struct ReplicationConfig {
+    pub scenario: Scenario,
+    pub duration: f32,
+    /**
+     * Replication `i` runs with seed `seed + i`
+     */
+    pub seed: u64,
+    pub replications: usize,
+    pub target: Option<Target>,
+    pub max_replications: usize,
+    pub threads: usize,
+}
+
+impl ReplicationConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let flags = Flags::parse(
+            args,
+            &[
+                "scenario",
+                "layout",
+                "control",
+                "arrivals",
+                "od",
+                "travel-times",
+                "reroute",
+                "duration",
+                "seed",
+                "replications",
+                "target",
+                "max-replications",
+                "threads",
+            ],
+        )?;
+        let config = ReplicationConfig {
+            scenario: Scenario::from_flags(&flags)?,
+            duration: flags.get("duration", 120.0)?,
+            seed: flags.get("seed", 0)?,
+            replications: flags.get("replications", 10)?,
+            target: flags.get_optional("target")?,
+            max_replications: flags.get("max-replications", 1000)?,
+            threads: flags.get("threads", headless::default_threads())?,
+        };
+        if config.replications < 2 {
+            return Err("'--replications' must be at least 2 for a standard deviation".to_string());
+        }
+        if config.max_replications < config.replications {
+            return Err("'--max-replications' can't be below '--replications'".to_string());
+        }
+        Ok(config)
+    }
+}
+
+fn run_replication(config: &ReplicationConfig, seed: u64) -> SimulationMetrics {
+    let mut simulation = HeadlessSimulation::new(config.scenario.signal_plan, seed);
+    simulation.set_scenario(config.scenario.clone());
+    simulation.run_until(config.duration)
+}
+
+fn summarize(results: &[SimulationMetrics], metric: Metric) -> Summary {
+    let values = results
+        .iter()
+        .map(|metrics| metric.value(metrics) as f64)
+        .collect::<Vec<f64>>();
+    Summary::of(&values)
+}
+
+/**
+ * Runs `config.replications` of the scenario with different seeds, then with a target more of
+ * them (as many as the current spread says are needed) until the target's half-width is reached,
+ * and prints every metric's summary
+ */
+pub fn run(config: &ReplicationConfig) {
+    let started = Instant::now();
+    let mut results = Vec::new();
+    let mut batch = config.replications;
+    loop {
+        let seeds = (results.len()..results.len() + batch)
+            .map(|i| config.seed.wrapping_add(i as u64))
+            .collect::<Vec<u64>>();
+        results.extend(headless::run_in_parallel(&seeds, config.threads, |seed| {
+            run_replication(config, *seed)
+        }));
+        let Some(target) = config.target else {
+            break;
+        };
+        let summary = summarize(&results, target.metric);
+        eprintln!(
+            "{} replications, {} half-width {:.3}",
+            results.len(),
+            target.metric.name(),
+            summary.half_width
+        );
+        if summary.half_width <= target.half_width {
+            break;
+        }
+        if results.len() >= config.max_replications {
+            eprintln!(
+                "warning: stopped at '--max-replications' {} before reaching the target",
+                config.max_replications
+            );
+            break;
+        }
+        // The half-width shrinks with the square root of the number of replications
+        let needed = (results.len() as f64 * (summary.half_width / target.half_width).powi(2))
+            .ceil() as usize;
+        batch = needed
+            .saturating_sub(results.len())
+            .max(config.threads)
+            .min(config.max_replications - results.len());
+    }
+
+    println!("replications: {}", results.len());
+    println!(
+        "{:<10} | {:>10} {:>10} {:>10} {:>10}",
+        "metric", "mean", "sd", "ci95 low", "ci95 high"
+    );
+    for metric in Metric::ALL {
+        let summary = summarize(&results, metric);
+        let (low, high) = summary.confidence_interval();
+        println!(
+            "{:<10} | {:>10.2} {:>10.2} {:>10.2} {:>10.2}",
+            metric.name(),
+            summary.mean,
+            summary.sd,
+            low,
+            high
+        );
+    }
+    eprintln!(
+        "ran {} replications in {:.2}s",
+        results.len(),
+        started.elapsed().as_secs_f32()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} isn't within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn t_975_follows_the_table_then_the_expansion() {
+        assert_eq!(t_975(0), f64::INFINITY);
+        assert_eq!(t_975(1), 12.706);
+        assert_eq!(t_975(10), 2.228);
+        assert_eq!(t_975(30), 2.042);
+        // Published values past the table
+        assert_close(t_975(31), 2.040, 0.001);
+        assert_close(t_975(40), 2.021, 0.001);
+        assert_close(t_975(60), 2.000, 0.001);
+        assert_close(t_975(120), 1.980, 0.001);
+        assert_close(t_975(100_000), Z_975, 0.001);
+        assert!((1..200).all(|df| t_975(df + 1) < t_975(df)));
+    }
+
+    #[test]
+    fn summarizes_replications() {
+        let summary = Summary::of(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(summary.n, 8);
+        assert_eq!(summary.mean, 5.0);
+        // The sample standard deviation, divided by n - 1
+        assert_close(summary.sd, (32.0f64 / 7.0).sqrt(), 1e-12);
+        assert_close(summary.half_width, 2.365 * summary.sd / 8f64.sqrt(), 1e-12);
+        let (low, high) = summary.confidence_interval();
+        assert_close(low, 5.0 - summary.half_width, 1e-12);
+        assert_close(high, 5.0 + summary.half_width, 1e-12);
+    }
+
+    #[test]
+    fn identical_replications_have_no_spread() {
+        let summary = Summary::of(&[3.0; 5]);
+        assert_eq!(summary.mean, 3.0);
+        assert_eq!(summary.sd, 0.0);
+        assert_eq!(summary.half_width, 0.0);
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            "delay: 5".parse::<Target>(),
+            Ok(Target {
+                metric: Metric::Delay,
+                half_width: 5.0
+            })
+        );
+        assert!("delay".parse::<Target>().is_err());
+        assert!("delay:0".parse::<Target>().is_err());
+        assert!("speed:5".parse::<Target>().is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    car_fleet::CarFleetSet,
+    metrics,
+    sim_clock::{SimClock, SimClockPlugin},
+    snapshot::SimulationSnapshot,
+    ui_components::reset_simulation_button::ResetSimluation,
+};
+
+/*
+How often (in simulated seconds) the state is kept, and how many states are: the last five
+minutes can be rewound
+ */
+const HISTORY_INTERVAL: f32 = 0.5;
+const HISTORY_LENGTH: usize = 600;
+
+/**
+ * Keeps the simulation's recent states so it can be rewound to any of them, and carry on from there
+ * (see `rewind`)
+ */
+pub struct RewindPlugin;
+
+impl Plugin for RewindPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<SimClockPlugin>() {
+            app.add_plugins(SimClockPlugin);
+        }
+        app.init_resource::<SnapshotHistory>()
+            .add_event::<ResetSimluation>()
+            .add_systems(
+                FixedUpdate,
+                record
+                    .after(CarFleetSet::Despawn)
+                    .after(metrics::record)
+                    .after(metrics::record_signal_changes),
+            )
+            .add_systems(Update, reset_simulation_listener);
+    }
+}
+
+/**
+ * The states kept, oldest first
+ */
+#[derive(Resource, Debug, Clone, Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<SimulationSnapshot>,
+    ticks_since_last: u32,
+    /**
+     * The state rewound to, until the simulation moves on from it
+     */
+    shown: Option<usize>,
+}
+
+impl SnapshotHistory {
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /**
+     * The simulated time of the `index`th state, in seconds
+     */
+    pub fn time_at(&self, index: usize) -> f32 {
+        self.snapshots[index].time.as_secs_f32()
+    }
+
+    /**
+     * The state the simulation was rewound to, `None` when it's running on from the latest one
+     */
+    pub fn shown(&self) -> Option<usize> {
+        self.shown
+    }
+}
+
+/**
+ * Keeps the state every `HISTORY_INTERVAL`, forgetting the oldest past `HISTORY_LENGTH`. After a
+ * rewind the states past the one rewound to are dropped as soon as the simulation moves on, since
+ * it may take another course (e.g. with changed parameters).
+ */
+pub fn record(world: &mut World) {
+    let timestep = world.resource::<Time>().delta_seconds();
+    let record_every = (HISTORY_INTERVAL / timestep).round().max(1.0) as u32;
+    let mut history = world.resource_mut::<SnapshotHistory>();
+    if let Some(shown) = history.shown.take() {
+        history.snapshots.truncate(shown + 1);
+        history.ticks_since_last = 0;
+    }
+    history.ticks_since_last += 1;
+    if !history.snapshots.is_empty() && history.ticks_since_last < record_every {
+        return;
+    }
+    history.ticks_since_last = 0;
+    let snapshot = SimulationSnapshot::capture(world);
+    let mut history = world.resource_mut::<SnapshotHistory>();
+    if history.snapshots.len() == HISTORY_LENGTH {
+        history.snapshots.pop_front();
+    }
+    history.snapshots.push_back(snapshot);
+}
+
+/**
+ * Pauses the simulation at the `index`th state kept, it carries on from there when resumed
+ */
+pub fn rewind(world: &mut World, index: usize) {
+    let Some(snapshot) = world
+        .resource::<SnapshotHistory>()
+        .snapshots
+        .get(index)
+        .cloned()
+    else {
+        return;
+    };
+    world.resource_mut::<SimClock>().pause();
+    // The road may have been rebuilt by a scenario reload whose reset hasn't cleared the history yet
+    if let Err(e) = snapshot.restore(world) {
+        warn!("Couldn't rewind: {}", e);
+        return;
+    }
+    // Right away rather than from the next frame like `SimClock`, or this frame's tick would move
+    // on from the state and drop the ones after it
+    world.resource_mut::<Time<Virtual>>().pause();
+    world.resource_mut::<SnapshotHistory>().shown = Some(index);
+}
+
+pub fn reset_simulation_listener(
+    mut reset_simulation_event: EventReader<ResetSimluation>,
+    mut history: ResMut<SnapshotHistory>,
+) {
+    for _ in reset_simulation_event.read() {
+        *history = SnapshotHistory::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car_fleet::source::{ArrivalProcess, TrafficDemand},
+        headless::HeadlessSimulation,
+        road::RoadLayout,
+        scenario::Scenario,
+    };
+
+    /**
+     * A headless simulation of traffic on the crossroads, keeping its states like `RewindPlugin`
+     * does
+     */
+    fn rewindable() -> HeadlessSimulation {
+        let road_layout = RoadLayout::crossroads();
+        let scenario = Scenario {
+            demand: TrafficDemand::on_every_entry(
+                &road_layout,
+                ArrivalProcess::Poisson { rate: 0.3 },
+            ),
+            road_layout,
+            ..default()
+        };
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, 3);
+        simulation.set_scenario(scenario);
+        let world = simulation.world_mut();
+        world.init_resource::<SimClock>();
+        world.init_resource::<SnapshotHistory>();
+        world.resource_mut::<Schedules>().add_systems(
+            FixedUpdate,
+            record
+                .after(CarFleetSet::Despawn)
+                .after(metrics::record)
+                .after(metrics::record_signal_changes),
+        );
+        simulation
+    }
+
+    fn history(simulation: &mut HeadlessSimulation) -> SnapshotHistory {
+        simulation.world_mut().resource::<SnapshotHistory>().clone()
+    }
+
+    #[test]
+    fn keeps_a_state_every_interval() {
+        let mut simulation = rewindable();
+        let elapsed = simulation.run_until(10.0).elapsed;
+        let history = history(&mut simulation);
+        assert_eq!(history.len(), 20);
+        for index in 1..history.len() {
+            let interval = history.time_at(index) - history.time_at(index - 1);
+            assert!((interval - HISTORY_INTERVAL).abs() < 1e-4, "{}", interval);
+        }
+        let latest = history.time_at(history.len() - 1);
+        assert!(latest <= elapsed && elapsed - latest < HISTORY_INTERVAL);
+        assert_eq!(history.shown(), None);
+    }
+
+    #[test]
+    fn forgets_the_oldest_states_past_the_history_length() {
+        let mut simulation = rewindable();
+        let elapsed = simulation
+            .run_until(HISTORY_LENGTH as f32 * HISTORY_INTERVAL + 10.0)
+            .elapsed;
+        let history = history(&mut simulation);
+        assert_eq!(history.len(), HISTORY_LENGTH);
+        let latest = history.time_at(HISTORY_LENGTH - 1);
+        assert!(latest <= elapsed && elapsed - latest < HISTORY_INTERVAL);
+        let kept = latest - history.time_at(0);
+        let expected = (HISTORY_LENGTH - 1) as f32 * HISTORY_INTERVAL;
+        assert!((kept - expected).abs() < 1e-2, "{}", kept);
+    }
+
+    #[test]
+    fn rewinds_to_a_kept_state_and_stays_there() {
+        let mut simulation = rewindable();
+        simulation.run_until(10.0);
+        let before = history(&mut simulation);
+        rewind(simulation.world_mut(), 4);
+        assert_eq!(simulation.snapshot(), before.snapshots[4]);
+        assert!(simulation.world_mut().resource::<SimClock>().is_paused());
+
+        // Paused on it, with the states after it still there to go forward to
+        simulation.step();
+        assert_eq!(simulation.snapshot(), before.snapshots[4]);
+        let after = history(&mut simulation);
+        assert_eq!(after.len(), before.len());
+        assert_eq!(after.shown(), Some(4));
+
+        // Past the states kept, nothing happens
+        rewind(simulation.world_mut(), before.len());
+        assert_eq!(simulation.snapshot(), before.snapshots[4]);
+        assert_eq!(history(&mut simulation).shown(), Some(4));
+    }
+
+    #[test]
+    fn drops_the_states_after_the_one_rewound_to_once_it_moves_on() {
+        let mut simulation = rewindable();
+        simulation.run_until(10.0);
+        let before = history(&mut simulation);
+        rewind(simulation.world_mut(), 4);
+        // What `SimClockPlugin` does once the clock is resumed
+        simulation
+            .world_mut()
+            .resource_mut::<Time<Virtual>>()
+            .unpause();
+
+        simulation.step();
+        let after = history(&mut simulation);
+        assert_eq!(after.len(), 5);
+        assert_eq!(after.shown(), None);
+
+        // Nothing changed, so it carries on through the same states as before the rewind
+        simulation.run_until(before.time_at(before.len() - 1));
+        let after = history(&mut simulation);
+        assert_eq!(after.len(), before.len());
+        assert_eq!(after.snapshots, before.snapshots);
+    }
+}
@@ -0,0 +1,297 @@
+use bevy::prelude::*;
+
+use crate::{
+    car_fleet::{
+        car::{Car, Velocity},
+        source::{ArrivalProcess, TrafficDemand},
+    },
+    headless::HeadlessSimulation,
+    metrics::SimulationMetrics,
+    road::{OnLane, RoadLayout},
+    scenario::Scenario,
+    traffic_light::{CurrentLight, Light, LightChangeTimer, SignalControl, TrafficLight},
+};
+
+/*
+The Poisson arrivals on every entry of the default environment's road, in cars per second, enough
+to keep queues building up under a poor policy
+ */
+const DEFAULT_ARRIVAL_RATE: f32 = 0.15;
+
+/**
+ * The light the agent asks for, the fault modes can't be asked for
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Red,
+    Yellow,
+    Green,
+}
+
+impl Action {
+    pub const ALL: [Action; 3] = [Action::Red, Action::Yellow, Action::Green];
+
+    pub fn light(&self) -> Light {
+        match self {
+            Action::Red => Light::RedLight,
+            Action::Yellow => Light::YellowLight,
+            Action::Green => Light::GreenLight,
+        }
+    }
+}
+
+/**
+ * What the agent sees after every step. `queue_lengths` are the standing cars of every lane, and
+ * `speeds` are the velocities of the cars that haven't passed the light yet, closest to it first.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub light: Light,
+    pub time_in_light: f32,
+    pub queue_lengths: Vec<u32>,
+    pub speeds: Vec<f32>,
+}
+
+/**
+ * How a step is rewarded, computed from what the metrics accumulated during that step.
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum Reward {
+    Delay,
+    Stops,
+    Throughput,
+    QueueLength,
+    Custom(fn(&Observation, &SimulationMetrics) -> f32),
+}
+
+impl Reward {
+    fn compute(&self, observation: &Observation, step_metrics: &SimulationMetrics) -> f32 {
+        match self {
+            Reward::Delay => -step_metrics.total_delay,
+            Reward::Stops => -(step_metrics.stops as f32),
+            Reward::Throughput => step_metrics.throughput as f32,
+            Reward::QueueLength => -(observation.queue_lengths.iter().sum::<u32>() as f32),
+            Reward::Custom(reward) => reward(observation, step_metrics),
+        }
+    }
+}
+
+pub struct EnvConfig {
+    /**
+     * What every episode runs: its road, demand and vehicles. The light starts in the signal
+     * plan's state, and the plan's yellow duration is used when the agent stops traffic. On a
+     * `crossroads` the minor road gets the opposite of the light the agent picks.
+     */
+    pub scenario: Scenario,
+    /**
+     * Simulated seconds between two decisions
+     */
+    pub decision_interval: f32,
+    pub episode_length: f32,
+    pub reward: Reward,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        let road_layout = RoadLayout::default();
+        EnvConfig {
+            scenario: Scenario {
+                demand: TrafficDemand::on_every_entry(
+                    &road_layout,
+                    ArrivalProcess::Poisson {
+                        rate: DEFAULT_ARRIVAL_RATE,
+                    },
+                ),
+                road_layout,
+                ..default()
+            },
+            decision_interval: 1.0,
+            episode_length: 120.0,
+            reward: Reward::Delay,
+        }
+    }
+}
+
+/**
+ * A Gym-style environment where the agent controls the traffic light.
+ * The action is the light the agent wants: `Green` lets traffic go, once the minor road went
+ * through its yellow and all-red, `Red` (or `Yellow`) stops it, going through the plan's yellow
+ * light first.
+ *
+ * Every episode runs headless on its own fixed timestep, so the same seed and actions always give
+ * the same observations and rewards, however fast the machine is.
+ */
+pub struct SignalControlEnv {
+    config: EnvConfig,
+    simulation: Option<HeadlessSimulation>,
+    last_metrics: SimulationMetrics,
+}
+
+impl SignalControlEnv {
+    pub fn new(config: EnvConfig) -> Self {
+        SignalControlEnv {
+            config,
+            simulation: None,
+            last_metrics: SimulationMetrics::default(),
+        }
+    }
+
+    /**
+     * Starts a new episode of the scenario, `seed` draws its arrivals and vehicles
+     */
+    pub fn reset(&mut self, seed: u64) -> Observation {
+        let signal_plan = self.config.scenario.signal_plan;
+        let (initial_light, _) = signal_plan.initial_state();
+        let mut simulation = HeadlessSimulation::new(signal_plan, seed);
+        simulation.set_scenario(self.config.scenario.clone());
+        simulation.set_signal_control(SignalControl::External(initial_light));
+        self.last_metrics = simulation.metrics();
+        let observation = observe(&mut simulation);
+        self.simulation = Some(simulation);
+        observation
+    }
+
+    /**
+     * Panics if called before `reset`
+     */
+    pub fn step(&mut self, action: Action) -> (Observation, f32, bool) {
+        let simulation = self
+            .simulation
+            .as_mut()
+            .expect("`reset` must be called before `step`");
+        simulation.set_signal_control(SignalControl::External(action.light()));
+        let metrics = simulation.run_for(self.config.decision_interval);
+        let observation = observe(simulation);
+        let reward = self
+            .config
+            .reward
+            .compute(&observation, &metrics.since(&self.last_metrics));
+        self.last_metrics = metrics;
+        let done = metrics.elapsed >= self.config.episode_length;
+        (observation, reward, done)
+    }
+}
+
+fn observe(simulation: &mut HeadlessSimulation) -> Observation {
+    let world = simulation.world_mut();
+    let (current_light, light_change_timer) = world
+        .query_filtered::<(&CurrentLight, &LightChangeTimer), With<TrafficLight>>()
+        .single(world);
+    let light = current_light.0;
+    let time_in_light = light_change_timer.time_in_light();
+    let road_layout = world.resource::<RoadLayout>().clone();
+    // (lane, distance to the light, velocity)
+    let mut approaching_cars = world
+        .query_filtered::<(&Transform, &Velocity, &OnLane), With<Car>>()
+        .iter(world)
+        .filter_map(|(car_transform, velocity, on_lane)| {
+            let lane = &road_layout.lanes[on_lane.0];
+            let distance =
+                lane.distance_ahead(lane.position_of(car_transform.translation), lane.junction?)?;
+            Some((on_lane.0, distance, velocity.0))
+        })
+        .collect::<Vec<(usize, f32, f32)>>();
+    approaching_cars.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let mut queue_lengths = vec![0; road_layout.lanes.len()];
+    for (lane, _, velocity) in approaching_cars.iter() {
+        if *velocity == 0.0 {
+            queue_lengths[*lane] += 1;
+        }
+    }
+    Observation {
+        light,
+        time_in_light,
+        queue_lengths,
+        speeds: approaching_cars
+            .into_iter()
+            .map(|(_, _, velocity)| velocity)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(reward: Reward) -> SignalControlEnv {
+        SignalControlEnv::new(EnvConfig {
+            reward,
+            ..default()
+        })
+    }
+
+    /**
+     * Green for `green` steps then red until the episode is done, every step's observation and
+     * reward
+     */
+    fn episode(env: &mut SignalControlEnv, seed: u64, green: usize) -> Vec<(Observation, f32)> {
+        env.reset(seed);
+        let mut steps = Vec::new();
+        for step in 0.. {
+            let action = if step < green {
+                Action::Green
+            } else {
+                Action::Red
+            };
+            let (observation, reward, done) = env.step(action);
+            steps.push((observation, reward));
+            if done {
+                return steps;
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn episodes_with_the_same_seed_are_identical() {
+        let mut env = env(Reward::Delay);
+        let first = episode(&mut env, 5, 30);
+        assert_eq!(first.len(), 120);
+        assert_eq!(episode(&mut env, 5, 30), first);
+        assert_ne!(episode(&mut env, 6, 30), first);
+    }
+
+    #[test]
+    fn steps_ask_for_the_actions_light() {
+        let mut env = env(Reward::Delay);
+        assert_eq!(env.reset(0).light, Light::RedLight);
+        // Before the minor road's green, only its all-red (a second) has to end
+        assert_eq!(env.step(Action::Green).0.light, Light::GreenLight);
+        assert_eq!(env.step(Action::Green).0.light, Light::GreenLight);
+        // Stopping goes through the plan's yellow (a second) first
+        let (observation, _, _) = env.step(Action::Red);
+        assert_eq!(observation.light, Light::YellowLight);
+        assert!(observation.time_in_light < 1.0);
+        assert_eq!(env.step(Action::Red).0.light, Light::RedLight);
+        assert_eq!(env.step(Action::Yellow).0.light, Light::RedLight);
+        // The minor road had its green by now, so it needs its yellow and all-red (two seconds)
+        assert_eq!(env.step(Action::Green).0.light, Light::RedLight);
+        assert_eq!(env.step(Action::Green).0.light, Light::RedLight);
+        assert_eq!(env.step(Action::Green).0.light, Light::GreenLight);
+        assert_eq!(env.step(Action::Yellow).0.light, Light::YellowLight);
+    }
+
+    #[test]
+    fn rewards_have_their_sign() {
+        let rewards = |reward: Reward, green: usize| {
+            episode(&mut env(reward), 0, green)
+                .into_iter()
+                .map(|(_, reward)| reward)
+                .collect::<Vec<f32>>()
+        };
+        // Penalties are never positive, and the red light costs something
+        for reward in [Reward::Delay, Reward::Stops, Reward::QueueLength] {
+            let rewards = rewards(reward, 0);
+            assert!(rewards.iter().all(|reward| *reward <= 0.0), "{:?}", reward);
+            assert!(rewards.iter().any(|reward| *reward < 0.0), "{:?}", reward);
+        }
+        let throughput = rewards(Reward::Throughput, 120);
+        assert!(throughput.iter().all(|reward| *reward >= 0.0));
+        assert!(throughput.iter().any(|reward| *reward > 0.0));
+        assert!(rewards(Reward::Throughput, 0)
+            .iter()
+            .all(|reward| *reward == 0.0));
+        let custom = rewards(Reward::Custom(|_, metrics| metrics.elapsed), 0);
+        assert!(custom.iter().all(|reward| (reward - 1.0).abs() < 0.02));
+    }
+}
@@ -0,0 +1,131 @@
+use bevy::prelude::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+
+/**
+ * What a random stream is drawn for. Every user of randomness has its own stream, so e.g. adding a
+ * source or a vehicle class doesn't change the draws of the others.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngStream {
+    /**
+     * The classes of the cars queued when the simulation starts
+     */
+    InitialQueue,
+    /**
+     * The arrivals, destinations and classes of the source on a lane
+     */
+    Source(usize),
+}
+
+impl RngStream {
+    fn id(&self) -> u64 {
+        match self {
+            RngStream::InitialQueue => 0,
+            RngStream::Source(lane) => (1 << 32) | *lane as u64,
+        }
+    }
+}
+
+/**
+ * A stream's generator: `StdRng`'s algorithm, but with a position in the stream that can be saved
+ * and restored (see `SimulationSnapshot`)
+ */
+pub type StreamRng = ChaCha12Rng;
+
+/**
+ * All the simulation's randomness comes from here. Runs with the same seed (and scenario) draw the
+ * same numbers, so they're reproducible bit for bit.
+ */
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimulationRng {
+    seed: u64,
+}
+
+/**
+ * SplitMix64's finalizer, it turns similar seeds (e.g. consecutive ones) into unrelated ones
+ */
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl SimulationRng {
+    pub fn new(seed: u64) -> Self {
+        SimulationRng { seed }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /**
+     * A generator for `stream`, it starts over every time (e.g. when the simulation is reset)
+     */
+    pub fn stream(&self, stream: RngStream) -> StreamRng {
+        StreamRng::seed_from_u64(mix(self.seed ^ mix(stream.id())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car_fleet::{
+            car::{VehicleClass, VehicleClasses},
+            source::{ArrivalProcess, TrafficDemand},
+        },
+        headless::HeadlessSimulation,
+        road::RoadLayout,
+        scenario::Scenario,
+        trajectories::{Trajectories, TrajectoryPoint, VehicleSummary},
+    };
+
+    /**
+     * A minute of Poisson arrivals on both roads of the crossroads, with cars and trucks
+     */
+    fn run(seed: u64) -> (Vec<TrajectoryPoint>, Vec<VehicleSummary>) {
+        let road_layout = RoadLayout::crossroads();
+        let scenario = Scenario {
+            demand: TrafficDemand::on_every_entry(
+                &road_layout,
+                ArrivalProcess::Poisson { rate: 0.3 },
+            ),
+            vehicle_classes: VehicleClasses(vec![
+                VehicleClass {
+                    share: 3.0,
+                    ..default()
+                },
+                VehicleClass {
+                    name: "truck".to_string(),
+                    share: 1.0,
+                    reaction_time: 0.8,
+                    max_velocity: 0.07,
+                    acceleration: 0.0015,
+                    deceleration: 0.008,
+                },
+            ]),
+            road_layout,
+            ..default()
+        };
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, seed);
+        simulation.set_scenario(scenario);
+        simulation.record_trajectories(Trajectories::new(None));
+        simulation.run_until(60.0);
+        let trajectories = simulation.trajectories().unwrap();
+        (trajectories.points.clone(), trajectories.summaries.clone())
+    }
+
+    #[test]
+    fn runs_with_the_same_seed_are_identical() {
+        let (points, summaries) = run(7);
+        // More cars than the initial queues, so the sources spawned some
+        assert!(summaries.len() > 12);
+        assert!(summaries.iter().any(|summary| summary.reached_sink));
+        // Compared with `==` rather than `assert_eq!`, which would print every point
+        assert!((points.clone(), summaries) == run(7));
+        assert!(points != run(8).0);
+    }
+}
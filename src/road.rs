@@ -0,0 +1,14 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+pub use traffic_core::road::*;
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OnLane(pub usize);
+
+/**
+ * The lanes the car still has to drive through, in order
+ */
+#[derive(Component, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Route(pub VecDeque<usize>);
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    car_fleet::{
+        car::Car,
+        source::{ArrivalProcess, SourceDemand, TrafficDemand},
+    },
+    cli::Flags,
+    headless::{self, HeadlessSimulation},
+    road::{OnLane, RoadLayout},
+    sweep::SweepRange,
+    traffic_light::SignalPlan,
+};
+
+/*
+The headway (in seconds) cars arrive at on the measured entry, far shorter than any entry can
+discharge at so it always has a queue
+ */
+const SATURATING_HEADWAY: f32 = 0.5;
+
+pub struct CapacityConfig {
+    pub legs: usize,
+    pub radius: f32,
+    /**
+     * The Poisson arrivals on each of the other legs, in vehicles per hour, every one a run with
+     * its own circulating flow past the measured entry
+     */
+    pub demands: Vec<f32>,
+    /**
+     * Simulated before measuring, for the queue and the circulating traffic to build up
+     */
+    pub warm_up: f32,
+    pub duration: f32,
+    pub seed: u64,
+    pub threads: usize,
+}
+
+impl CapacityConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let flags = Flags::parse(
+            args,
+            &[
+                "legs", "radius", "demand", "warm-up", "duration", "seed", "threads",
+            ],
+        )?;
+        let config = CapacityConfig {
+            legs: flags.get("legs", 4)?,
+            radius: flags.get("radius", 15.0)?,
+            demands: flags
+                .get("demand", SweepRange(vec![0.0, 200.0, 400.0, 600.0, 800.0]))?
+                .0,
+            warm_up: flags.get("warm-up", 60.0)?,
+            duration: flags.get("duration", 600.0)?,
+            seed: flags.get("seed", 0)?,
+            threads: flags.get("threads", headless::default_threads())?,
+        };
+        if config.legs < 2 {
+            return Err("a roundabout needs at least 2 legs".to_string());
+        }
+        if config.radius <= 2.0 {
+            return Err("the radius must be over 2".to_string());
+        }
+        if config.demands.iter().any(|demand| *demand < 0.0) {
+            return Err("'--demand' can't be negative".to_string());
+        }
+        if config.warm_up < 0.0 || config.duration <= 0.0 {
+            return Err(
+                "'--warm-up' can't be negative and '--duration' must be positive".to_string(),
+            );
+        }
+        Ok(config)
+    }
+}
+
+/**
+ * What a run measured on its saturated entry, in vehicles per hour
+ */
+struct Measurement {
+    leg: usize,
+    demand: f32,
+    circulating_flow: f32,
+    entry_flow: f32,
+}
+
+/**
+ * Counts the cars on the circulating lane driving past `position`, from one call to the next
+ */
+struct CirculatingCounter {
+    lane: usize,
+    position: f32,
+    last_positions: HashMap<Entity, f32>,
+    passed: usize,
+}
+
+impl CirculatingCounter {
+    fn update(&mut self, world: &mut World) {
+        let lane = world.resource::<RoadLayout>().lanes[self.lane].clone();
+        let positions = world
+            .query_filtered::<(Entity, &Transform, &OnLane), With<Car>>()
+            .iter(world)
+            .filter(|(_, _, on_lane)| on_lane.0 == self.lane)
+            .map(|(entity, transform, _)| (entity, lane.position_of(transform.translation)))
+            .collect::<HashMap<Entity, f32>>();
+        for (entity, position) in positions.iter() {
+            let Some(last_position) = self.last_positions.get(entity) else {
+                continue;
+            };
+            let driven = lane.distance_ahead(*last_position, *position);
+            let to_counted = lane.distance_ahead(*last_position, self.position);
+            if let (Some(driven), Some(to_counted)) = (driven, to_counted) {
+                if driven > 0.0 && to_counted < driven {
+                    self.passed += 1;
+                }
+            }
+        }
+        self.last_positions = positions;
+    }
+}
+
+/**
+ * Saturates the entry of `leg` while Poisson arrivals at `demand` on every other leg drive around
+ * the roundabout, and measures the flow circulating past the entry and the flow the entry
+ * discharges at meanwhile (its capacity under that circulating flow)
+ */
+fn measure(
+    config: &CapacityConfig,
+    road_layout: &RoadLayout,
+    leg: usize,
+    demand: f32,
+) -> Measurement {
+    let entries = (0..road_layout.lanes.len())
+        .filter_map(|lane| road_layout.roundabout_leg(lane).map(|leg| (leg, lane)))
+        .collect::<HashMap<usize, usize>>();
+    let measured = entries[&leg];
+    let mut sources = vec![SourceDemand {
+        lane: measured,
+        arrivals: ArrivalProcess::Uniform {
+            headway: SATURATING_HEADWAY,
+        },
+        destinations: Vec::new(),
+    }];
+    if demand > 0.0 {
+        sources.extend(
+            entries
+                .iter()
+                .filter(|(other_leg, _)| **other_leg != leg)
+                .map(|(_, lane)| SourceDemand {
+                    lane: *lane,
+                    arrivals: ArrivalProcess::Poisson {
+                        rate: demand / 3600.0,
+                    },
+                    destinations: Vec::new(),
+                }),
+        );
+    }
+    sources.sort_by_key(|source| source.lane);
+    let merge = road_layout.lanes[measured].connections[0];
+    let mut counter = CirculatingCounter {
+        lane: merge.lane,
+        position: merge.position,
+        last_positions: HashMap::new(),
+        passed: 0,
+    };
+
+    let mut simulation = HeadlessSimulation::new(SignalPlan::default(), config.seed);
+    simulation.set_road_layout(road_layout.clone());
+    simulation.set_demand(TrafficDemand {
+        sources,
+        ..default()
+    });
+    simulation.run_until(config.warm_up);
+    let end = config.warm_up + config.duration;
+    while simulation.metrics().elapsed < end {
+        simulation.step();
+        counter.update(simulation.world_mut());
+    }
+    let measuring_from = simulation.metrics().elapsed - config.duration;
+    let entered = simulation
+        .junction_entries()
+        .0
+        .get(measured)
+        .map_or(0, |entries| {
+            entries
+                .iter()
+                .filter(|time| **time > measuring_from)
+                .count()
+        });
+    Measurement {
+        leg,
+        demand,
+        circulating_flow: 3600.0 * counter.passed as f32 / config.duration,
+        entry_flow: 3600.0 * entered as f32 / config.duration,
+    }
+}
+
+/**
+ * Measures every leg's entry capacity against a range of circulating flows: for every leg and
+ * demand, a run saturates the leg's entry while the other legs' arrivals circulate past it
+ */
+pub fn run(config: &CapacityConfig) {
+    let road_layout = RoadLayout::roundabout(config.legs, config.radius);
+    let jobs = (0..config.legs)
+        .flat_map(|leg| config.demands.iter().map(move |demand| (leg, *demand)))
+        .collect::<Vec<(usize, f32)>>();
+    let measurements = headless::run_in_parallel(&jobs, config.threads, |(leg, demand)| {
+        measure(config, &road_layout, *leg, *demand)
+    });
+    println!(
+        "{:<4} | {:>15} {:>20} {:>16}",
+        "leg", "demand (veh/h)", "circulating (veh/h)", "capacity (veh/h)"
+    );
+    for measurement in measurements {
+        println!(
+            "{:<4} | {:>15.0} {:>20.0} {:>16.0}",
+            measurement.leg,
+            measurement.demand,
+            measurement.circulating_flow,
+            measurement.entry_flow
+        );
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashSet, VecDeque},
+    str::FromStr,
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    car_fleet::car::{Car, Velocity, MAX_VELOCITY},
+    cli::Flags,
+    road::{OnLane, RoadLayout, Route},
+};
+
+/*
+The slowest a lane is considered to be when routing with the current travel times, so a standing
+queue makes a lane expensive instead of impassable
+ */
+const MIN_ROUTING_VELOCITY: f32 = MAX_VELOCITY * 0.05;
+
+/**
+ * What a lane costs to drive through when looking for the shortest route
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TravelTimes {
+    /**
+     * Every lane driven at MAX_VELOCITY
+     */
+    #[default]
+    FreeFlow,
+    /**
+     * Every lane driven at the average velocity of the cars on it right now
+     */
+    Current,
+}
+
+impl FromStr for TravelTimes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free-flow" => Ok(TravelTimes::FreeFlow),
+            "current" => Ok(TravelTimes::Current),
+            _ => Err("expected one of: free-flow, current".to_string()),
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    pub travel_times: TravelTimes,
+    /**
+     * Every this many seconds the cars that have a destination look for a faster route, never if
+     * `None`
+     */
+    #[serde(deserialize_with = "traffic_core::validate::optional_positive")]
+    pub reroute_interval: Option<f32>,
+}
+
+impl RoutingConfig {
+    pub fn from_flags(flags: &Flags) -> Result<Self, String> {
+        let reroute_interval = flags.get_optional::<f32>("reroute")?;
+        if reroute_interval.is_some_and(|interval| interval <= 0.0) {
+            return Err("'--reroute' must be positive".to_string());
+        }
+        Ok(RoutingConfig {
+            travel_times: flags.get("travel-times", TravelTimes::default())?,
+            reroute_interval,
+        })
+    }
+}
+
+/**
+ * Where the car is going, a lane it leaves the road on
+ */
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Destination(pub usize);
+
+/**
+ * The velocity (per tick) every lane is driven at
+ */
+pub fn lane_velocities<'a>(
+    road_layout: &RoadLayout,
+    travel_times: TravelTimes,
+    cars: impl Iterator<Item = (&'a OnLane, &'a Velocity)>,
+) -> Vec<f32> {
+    let mut velocities = vec![MAX_VELOCITY; road_layout.lanes.len()];
+    if travel_times == TravelTimes::FreeFlow {
+        return velocities;
+    }
+    let mut totals = vec![(0.0, 0); road_layout.lanes.len()];
+    for (on_lane, velocity) in cars {
+        totals[on_lane.0].0 += velocity.0;
+        totals[on_lane.0].1 += 1;
+    }
+    for (lane, (total, count)) in totals.into_iter().enumerate() {
+        if count > 0 {
+            velocities[lane] = (total / count as f32).max(MIN_ROUTING_VELOCITY);
+        }
+    }
+    velocities
+}
+
+struct Candidate {
+    /**
+     * In ticks
+     */
+    cost: f32,
+    lane: usize,
+    position: f32,
+    route: Vec<usize>,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    /**
+     * Reversed, so the heap pops the cheapest candidate first
+     */
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+/**
+ * The fastest lanes (after the current one) from `position` of `lane` to `destination`, with
+ * Dijkstra over the lanes' connections. A lane can be entered at several positions (a roundabout
+ * at every entry), so what's searched is (lane, position entered at).
+ */
+pub fn shortest_route(
+    road_layout: &RoadLayout,
+    lane: usize,
+    position: f32,
+    destination: usize,
+    velocities: &[f32],
+) -> Option<VecDeque<usize>> {
+    let mut visited = HashSet::new();
+    let mut candidates = BinaryHeap::from([Candidate {
+        cost: 0.0,
+        lane,
+        position,
+        route: Vec::new(),
+    }]);
+    while let Some(candidate) = candidates.pop() {
+        if candidate.lane == destination {
+            return Some(candidate.route.into());
+        }
+        if !visited.insert((candidate.lane, candidate.position.to_bits())) {
+            continue;
+        }
+        let current_lane = &road_layout.lanes[candidate.lane];
+        for connection in current_lane.connections.iter() {
+            let Some(distance) = current_lane.distance_ahead(candidate.position, connection.at)
+            else {
+                continue;
+            };
+            let mut route = candidate.route.clone();
+            route.push(connection.lane);
+            candidates.push(Candidate {
+                cost: candidate.cost + distance / velocities[candidate.lane],
+                lane: connection.lane,
+                position: connection.position,
+                route,
+            });
+        }
+    }
+    None
+}
+
+/**
+ * Origin-destination demand: `trips[i][j]` cars per hour enter on `origins[i]` and leave on
+ * `destinations[j]`
+ */
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OdMatrix {
+    pub origins: Vec<usize>,
+    pub destinations: Vec<usize>,
+    pub trips: Vec<Vec<f32>>,
+}
+
+fn parse_cell<T: FromStr>(path: &str, line: usize, cell: &str) -> Result<T, String> {
+    cell.trim()
+        .parse()
+        .map_err(|_| format!("{}:{}: '{}' isn't a number", path, line + 1, cell.trim()))
+}
+
+impl OdMatrix {
+    /**
+     * A CSV whose header row has the destination lanes after a first label column, then one row
+     * per origin lane with its number of trips per hour to every destination:
+     * ```text
+     * origin,0,1
+     * 0,400,100
+     * 1,50,200
+     * ```
+     */
+    pub fn from_csv(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read '{}': {}", path, e))?;
+        let mut lines = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        let (header_line, header) = lines.next().ok_or(format!("'{}' is empty", path))?;
+        let mut od_matrix = OdMatrix {
+            destinations: header
+                .split(',')
+                .skip(1)
+                .map(|cell| parse_cell(path, header_line, cell))
+                .collect::<Result<_, _>>()?,
+            ..Default::default()
+        };
+        for (line, row) in lines {
+            let mut cells = row.split(',');
+            od_matrix
+                .origins
+                .push(parse_cell(path, line, cells.next().unwrap_or_default())?);
+            let trips = cells
+                .map(|cell| parse_cell(path, line, cell))
+                .collect::<Result<Vec<f32>, _>>()?;
+            if trips.len() != od_matrix.destinations.len() {
+                return Err(format!(
+                    "{}:{}: expected {} destinations, found {}",
+                    path,
+                    line + 1,
+                    od_matrix.destinations.len(),
+                    trips.len()
+                ));
+            }
+            od_matrix.trips.push(trips);
+        }
+        Ok(od_matrix)
+    }
+
+    /**
+     * Checks there's a row of trips per origin and destination, the lanes exist and every trip
+     * has a route on `road_layout`
+     */
+    pub fn validate(&self, road_layout: &RoadLayout) -> Result<(), String> {
+        if self.trips.len() != self.origins.len()
+            || self
+                .trips
+                .iter()
+                .any(|trips| trips.len() != self.destinations.len())
+        {
+            return Err(format!(
+                "expected {} rows of {} trips, one per origin and destination",
+                self.origins.len(),
+                self.destinations.len()
+            ));
+        }
+        let lanes = road_layout.lanes.len();
+        if let Some(lane) = self
+            .origins
+            .iter()
+            .chain(self.destinations.iter())
+            .find(|lane| **lane >= lanes)
+        {
+            return Err(format!("lane {} doesn't exist, there are {}", lane, lanes));
+        }
+        // Every origin becomes a source, and a lane has one at most
+        if let Some(origin) =
+            self.origins.iter().enumerate().find_map(|(index, origin)| {
+                self.origins[..index].contains(origin).then_some(origin)
+            })
+        {
+            return Err(format!("lane {} is listed twice in the origins", origin));
+        }
+        let velocities = vec![MAX_VELOCITY; lanes];
+        for (origin, trips) in self.origins.iter().zip(self.trips.iter()) {
+            for (destination, trips) in self.destinations.iter().zip(trips.iter()) {
+                let entry = road_layout.lanes[*origin].junction.unwrap_or_default();
+                if *trips > 0.0
+                    && shortest_route(road_layout, *origin, entry, *destination, &velocities)
+                        .is_none()
+                {
+                    return Err(format!(
+                        "no route from lane {} to lane {}",
+                        origin, destination
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for OdMatrix {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        OdMatrix::from_csv(path)
+    }
+}
+
+/**
+ * Seconds since the cars were last rerouted
+ */
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SinceReroute(pub f32);
+
+/**
+ * Cars with a destination switch to the currently fastest route every `reroute_interval`
+ */
+pub fn reroute(
+    routing_config: Res<RoutingConfig>,
+    road_layout: Res<RoadLayout>,
+    time: Res<Time>,
+    mut since_reroute: ResMut<SinceReroute>,
+    mut car_q: Query<(&Transform, &OnLane, &Destination, &mut Route), With<Car>>,
+    velocity_q: Query<(&OnLane, &Velocity), With<Car>>,
+) {
+    let Some(reroute_interval) = routing_config.reroute_interval else {
+        return;
+    };
+    since_reroute.0 += time.delta_seconds();
+    if since_reroute.0 < reroute_interval {
+        return;
+    }
+    since_reroute.0 = 0.0;
+    let velocities = lane_velocities(&road_layout, routing_config.travel_times, velocity_q.iter());
+    for (transform, on_lane, destination, mut route) in car_q.iter_mut() {
+        let position = road_layout.lanes[on_lane.0].position_of(transform.translation);
+        let new_route = shortest_route(
+            &road_layout,
+            on_lane.0,
+            position,
+            destination.0,
+            &velocities,
+        );
+        if let Some(new_route) = new_route {
+            if route.0 != new_route {
+                route.0 = new_route;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::road::{Connection, Lane, Priority};
+
+    /**
+     * Lane 0 splits into lanes 1 and 2 (as long as each other) at its junction, and both lead to
+     * lane 3
+     */
+    fn two_routes() -> RoadLayout {
+        let mut lanes = vec![Lane::straight(Vec3::ZERO, Vec3::X, Priority::Major)];
+        for z in [-5.0, 5.0] {
+            let mut lane = Lane::straight(Vec3::new(20.0, 0.0, z), Vec3::X, Priority::Major);
+            lane.connections.push(Connection {
+                at: 0.0,
+                lane: 3,
+                position: 0.0,
+            });
+            lanes.push(lane);
+        }
+        lanes.push(Lane::straight(
+            Vec3::new(40.0, 0.0, 0.0),
+            Vec3::X,
+            Priority::Major,
+        ));
+        for lane in [1, 2] {
+            lanes[0].connections.push(Connection {
+                at: 0.0,
+                lane,
+                position: -20.0,
+            });
+        }
+        RoadLayout { lanes }
+    }
+
+    fn route(lanes: &[usize]) -> Option<VecDeque<usize>> {
+        Some(lanes.iter().copied().collect())
+    }
+
+    #[test]
+    fn routes_through_the_crossroads() {
+        let layout = RoadLayout::crossroads();
+        let velocities = [MAX_VELOCITY; 2];
+        assert_eq!(
+            shortest_route(&layout, 0, -50.0, 1, &velocities),
+            route(&[1])
+        );
+        assert_eq!(
+            shortest_route(&layout, 0, -50.0, 0, &velocities),
+            route(&[])
+        );
+        // Past the junction, there's no turning back
+        assert_eq!(shortest_route(&layout, 0, 10.0, 1, &velocities), None);
+    }
+
+    #[test]
+    fn routes_around_the_roundabout() {
+        let layout = RoadLayout::roundabout(4, 20.0);
+        let velocities = vec![MAX_VELOCITY; layout.lanes.len()];
+        // Leg 0's entry is lane 1, then every leg's exit is 2 + 2 * leg
+        for leg in 0..4 {
+            assert_eq!(
+                shortest_route(&layout, 1, 0.0, 2 + 2 * leg, &velocities),
+                route(&[0, 2 + 2 * leg])
+            );
+        }
+        // Entries only lead into the roundabout
+        assert_eq!(shortest_route(&layout, 1, 0.0, 3, &velocities), None);
+    }
+
+    #[test]
+    fn routes_take_the_fastest_lanes() {
+        let layout = two_routes();
+        let mut velocities = [MAX_VELOCITY; 4];
+        velocities[1] = MAX_VELOCITY / 2.0;
+        assert_eq!(
+            shortest_route(&layout, 0, -10.0, 3, &velocities),
+            route(&[2, 3])
+        );
+        velocities[1] = MAX_VELOCITY;
+        velocities[2] = MIN_ROUTING_VELOCITY;
+        assert_eq!(
+            shortest_route(&layout, 0, -10.0, 3, &velocities),
+            route(&[1, 3])
+        );
+    }
+
+    #[test]
+    fn reroutes_to_a_faster_route() {
+        let mut world = World::new();
+        world.insert_resource(two_routes());
+        world.insert_resource(RoutingConfig {
+            travel_times: TravelTimes::Current,
+            reroute_interval: Some(1.0),
+        });
+        world.insert_resource(Time::<()>::default());
+        world.insert_resource(SinceReroute(1.0));
+        let car = world
+            .spawn((
+                Car,
+                Transform::from_translation(Vec3::new(-10.0, 0.0, 0.0)),
+                OnLane(0),
+                Velocity(MAX_VELOCITY),
+                Destination(3),
+                Route(VecDeque::from([2, 3])),
+            ))
+            .id();
+        // A queue on lane 2
+        world.spawn((
+            Car,
+            Transform::from_translation(Vec3::new(25.0, 0.0, 5.0)),
+            OnLane(2),
+            Velocity(0.0),
+        ));
+        world.run_system_once(reroute);
+        assert_eq!(world.get::<Route>(car).unwrap().0, VecDeque::from([1, 3]));
+        assert_eq!(world.resource::<SinceReroute>().0, 0.0);
+    }
+
+    fn od_matrix(csv: &str) -> Result<OdMatrix, String> {
+        let path = std::env::temp_dir().join(format!("od-{}.csv", std::process::id()));
+        fs::write(&path, csv).unwrap();
+        let od_matrix = OdMatrix::from_csv(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        od_matrix.map_err(|error| error.rsplit(".csv").next().unwrap().to_string())
+    }
+
+    #[test]
+    fn reads_od_matrices() {
+        assert_eq!(
+            od_matrix("origin,0,1\n0,400,100\n\n1,50,200\n"),
+            Ok(OdMatrix {
+                origins: vec![0, 1],
+                destinations: vec![0, 1],
+                trips: vec![vec![400.0, 100.0], vec![50.0, 200.0]],
+            })
+        );
+        assert_eq!(
+            od_matrix("origin,0,1\n0,400,lots\n"),
+            Err(":2: 'lots' isn't a number".to_string())
+        );
+        assert_eq!(
+            od_matrix("origin,0,x\n"),
+            Err(":1: 'x' isn't a number".to_string())
+        );
+        assert_eq!(
+            od_matrix("origin,0,1\n0,400\n"),
+            Err(":2: expected 2 destinations, found 1".to_string())
+        );
+        assert_eq!(od_matrix(""), Err("' is empty".to_string()));
+    }
+
+    #[test]
+    fn od_matrices_fit_the_layout() {
+        let layout = RoadLayout::crossroads();
+        let od_matrix = |origins: Vec<usize>, destinations: Vec<usize>| OdMatrix {
+            trips: vec![vec![100.0; destinations.len()]; origins.len()],
+            origins,
+            destinations,
+        };
+        assert_eq!(od_matrix(vec![0, 1], vec![0, 1]).validate(&layout), Ok(()));
+        assert_eq!(
+            od_matrix(vec![0, 2], vec![1]).validate(&layout),
+            Err("lane 2 doesn't exist, there are 2".to_string())
+        );
+        assert_eq!(
+            od_matrix(vec![0, 0], vec![1]).validate(&layout),
+            Err("lane 0 is listed twice in the origins".to_string())
+        );
+        let mut missing_row = od_matrix(vec![0, 1], vec![1]);
+        missing_row.trips.pop();
+        assert_eq!(
+            missing_row.validate(&layout),
+            Err("expected 2 rows of 1 trips, one per origin and destination".to_string())
+        );
+    }
+
+    #[test]
+    fn reroute_intervals_are_positive() {
+        let from_flags = |args: &[&str]| {
+            let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            RoutingConfig::from_flags(&Flags::parse(&args, &["reroute"]).unwrap())
+        };
+        assert_eq!(
+            from_flags(&["--reroute", "2.5"]).unwrap().reroute_interval,
+            Some(2.5)
+        );
+        assert!(from_flags(&["--reroute", "0"]).is_err());
+        assert!(from_flags(&["--reroute", "-1"]).is_err());
+        let from_ron =
+            |ron: &str| ron::from_str::<RoutingConfig>(ron).map(|config| config.reroute_interval);
+        assert_eq!(from_ron("(reroute_interval: Some(5.0))"), Ok(Some(5.0)));
+        assert_eq!(from_ron("()"), Ok(None));
+        assert!(from_ron("(reroute_interval: Some(0.0))").is_err());
+    }
+}
@@ -0,0 +1,844 @@
+use std::{fmt, fs, path::Path, str::FromStr, time::Duration};
+
+use bevy::{
+    asset::{
+        io::{AssetSource, Reader},
+        AssetLoader, AsyncReadExt, LoadContext,
+    },
+    ecs::system::RunSystemOnce,
+    prelude::*,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    car_fleet::{
+        self,
+        car::{Car, ModelParameters, VehicleClass, VehicleClasses, MAX_VELOCITY},
+        source::{ArrivalProcess, TrafficDemand, VehicleSink, VehicleSource},
+    },
+    cli::Flags,
+    road::{IntersectionControl, Lane, RoadLayout},
+    routing::{self, OdMatrix, RoutingConfig},
+    traffic_light::{
+        CurrentLight, FaultSchedule, Light, LightChangeTimer, ScheduledFault, SignalPlan,
+        TrafficLight,
+    },
+    ui_components::reset_simulation_button::ResetSimluation,
+};
+
+/*
+The asset source the watched scenario file is read from, rooted at the file's directory
+ */
+const SCENARIO_SOURCE: &str = "scenario";
+/*
+Editors often write a file in several steps, only the last one should be reloaded
+ */
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+/*
+The flags a scenario file replaces
+ */
+const SCENARIO_FLAGS: [&str; 6] = [
+    "layout",
+    "control",
+    "arrivals",
+    "od",
+    "travel-times",
+    "reroute",
+];
+
+pub use traffic_core::validate::{fraction, non_negative, positive};
+
+fn roundabout_legs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    let legs = usize::deserialize(deserializer)?;
+    if legs >= 2 {
+        Ok(legs)
+    } else {
+        Err(de::Error::custom(format!(
+            "expected at least 2 legs, found {}",
+            legs
+        )))
+    }
+}
+
+fn vehicle_classes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<VehicleClasses, D::Error> {
+    let classes = Vec::<VehicleClass>::deserialize(deserializer)?;
+    if classes.iter().map(|class| class.share).sum::<f32>() <= 0.0 {
+        return Err(de::Error::custom(
+            "expected at least one vehicle class with a positive share",
+        ));
+    }
+    Ok(VehicleClasses(classes))
+}
+
+/**
+ * One of the built-in layouts (see `RoadLayout`), or the lanes themselves
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+enum RoadSpec {
+    #[default]
+    Straight,
+    Crossroads,
+    Roundabout {
+        #[serde(deserialize_with = "roundabout_legs")]
+        legs: usize,
+        #[serde(deserialize_with = "positive")]
+        radius: f32,
+    },
+    Lanes(Vec<Lane>),
+}
+
+/**
+ * A scenario file as it's written, see `Scenario::load`
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ScenarioFile {
+    road: RoadSpec,
+    control: IntersectionControl,
+    signal_plan: SignalPlan,
+    faults: Vec<ScheduledFault>,
+    #[serde(deserialize_with = "vehicle_classes")]
+    vehicles: VehicleClasses,
+    demand: TrafficDemand,
+    /**
+     * Shorthands for `demand.sources`, like `--arrivals` and `--od`
+     */
+    arrivals: Option<ArrivalProcess>,
+    od_matrix: Option<OdMatrix>,
+    routing: RoutingConfig,
+    model: ModelParameters,
+}
+
+/**
+ * Everything a run is set up from: the road, how its intersection is controlled, the vehicles and
+ * where they come from, and the driver model's parameters
+ */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Scenario {
+    pub road_layout: RoadLayout,
+    pub intersection_control: IntersectionControl,
+    pub signal_plan: SignalPlan,
+    pub faults: Vec<ScheduledFault>,
+    pub vehicle_classes: VehicleClasses,
+    pub demand: TrafficDemand,
+    pub routing_config: RoutingConfig,
+    pub model_parameters: ModelParameters,
+}
+
+/**
+ * A field of a scenario file that doesn't fit the rest of it, e.g. a lane that doesn't exist.
+ * `field` is its path in the file, like `demand.sources[1].lane`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidField {
+    pub field: String,
+    pub message: String,
+}
+
+impl InvalidField {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        InvalidField {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+fn check_lane(road_layout: &RoadLayout, lane: usize, field: &str) -> Result<(), InvalidField> {
+    let lanes = road_layout.lanes.len();
+    if lane >= lanes {
+        return Err(InvalidField::new(
+            field,
+            format!("lane {} doesn't exist, there are {}", lane, lanes),
+        ));
+    }
+    Ok(())
+}
+
+impl TryFrom<ScenarioFile> for Scenario {
+    type Error = InvalidField;
+
+    /**
+     * Checks what can't be checked while parsing: that the lanes referred to exist and that the
+     * destinations can be reached
+     */
+    fn try_from(file: ScenarioFile) -> Result<Self, Self::Error> {
+        let road_layout = match file.road {
+            RoadSpec::Straight => RoadLayout::default(),
+            RoadSpec::Crossroads => RoadLayout::crossroads(),
+            RoadSpec::Roundabout { legs, radius } => RoadLayout::roundabout(legs, radius),
+            RoadSpec::Lanes(lanes) => RoadLayout { lanes },
+        };
+        if road_layout.lanes.is_empty() {
+            return Err(InvalidField::new("road", "expected at least one lane"));
+        }
+        for (index, lane) in road_layout.lanes.iter().enumerate() {
+            for (conflict_index, conflict) in lane.conflicts.iter().enumerate() {
+                let field = format!("road[{}].conflicts[{}].lane", index, conflict_index);
+                check_lane(&road_layout, conflict.lane, &field)?;
+            }
+            for (connection_index, connection) in lane.connections.iter().enumerate() {
+                let field = format!("road[{}].connections[{}].lane", index, connection_index);
+                check_lane(&road_layout, connection.lane, &field)?;
+            }
+        }
+
+        let demand = match (file.arrivals, file.od_matrix) {
+            (Some(_), Some(_)) => {
+                return Err(InvalidField::new(
+                    "od_matrix",
+                    "'arrivals' and 'od_matrix' can't be used together",
+                ))
+            }
+            (Some(_), None) | (None, Some(_)) if !file.demand.sources.is_empty() => {
+                return Err(InvalidField::new(
+                    "demand.sources",
+                    "can't be used with 'arrivals' or 'od_matrix'",
+                ))
+            }
+            (Some(arrivals), None) => TrafficDemand {
+                initial_queue: file.demand.initial_queue,
+                queue_spacing: file.demand.queue_spacing,
+                ..TrafficDemand::on_every_entry(&road_layout, arrivals)
+            },
+            (None, Some(od_matrix)) => {
+                od_matrix
+                    .validate(&road_layout)
+                    .map_err(|e| InvalidField::new("od_matrix", e))?;
+                TrafficDemand {
+                    initial_queue: file.demand.initial_queue,
+                    queue_spacing: file.demand.queue_spacing,
+                    ..TrafficDemand::from_od_matrix(&od_matrix)
+                }
+            }
+            (None, None) => file.demand,
+        };
+        let velocities = vec![MAX_VELOCITY; road_layout.lanes.len()];
+        for (index, source) in demand.sources.iter().enumerate() {
+            let field = format!("demand.sources[{}]", index);
+            check_lane(&road_layout, source.lane, &format!("{}.lane", field))?;
+            // Sources draw from their lane's random stream, so two on a lane would draw the same
+            if let Some(first) = demand.sources[..index]
+                .iter()
+                .position(|other_source| other_source.lane == source.lane)
+            {
+                return Err(InvalidField::new(
+                    format!("{}.lane", field),
+                    format!(
+                        "lane {} already has a source (demand.sources[{}])",
+                        source.lane, first
+                    ),
+                ));
+            }
+            let entry = road_layout.lanes[source.lane].junction.unwrap_or_default();
+            for (destination_index, (destination, _)) in source.destinations.iter().enumerate() {
+                let destination_field = format!("{}.destinations[{}]", field, destination_index);
+                check_lane(&road_layout, *destination, &destination_field)?;
+                let route = routing::shortest_route(
+                    &road_layout,
+                    source.lane,
+                    entry,
+                    *destination,
+                    &velocities,
+                );
+                if route.is_none() {
+                    return Err(InvalidField::new(
+                        destination_field,
+                        format!("no route from lane {} to lane {}", source.lane, destination),
+                    ));
+                }
+            }
+        }
+
+        Ok(Scenario {
+            road_layout,
+            intersection_control: file.control,
+            signal_plan: file.signal_plan,
+            faults: file.faults,
+            vehicle_classes: file.vehicles,
+            demand,
+            routing_config: file.routing,
+            model_parameters: file.model,
+        })
+    }
+}
+
+impl Scenario {
+    /**
+     * Reads a RON scenario file, every section is optional:
+     * ```text
+     * (
+     *     road: Crossroads,
+     *     control: Signal,
+     *     signal_plan: (green: 20.0, red: 15.0),
+     *     vehicles: [(name: "car", share: 0.9), (name: "truck", share: 0.1, max_velocity: 0.07)],
+     *     arrivals: Some(Poisson(rate: 0.1)),
+     *     model: (critical_gap: 5.0),
+     * )
+     * ```
+     * Errors start with `<path>:<line>:<column>:`, pointing at what's wrong.
+     */
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("couldn't read '{}': {}", path, e))?;
+        Scenario::parse(&content, path)
+    }
+
+    /**
+     * `content` is a scenario file's, `path` is only used in errors
+     */
+    pub fn parse(content: &str, path: &str) -> Result<Self, String> {
+        let file: ScenarioFile = ron::from_str(content).map_err(|e| format!("{}:{}", path, e))?;
+        Scenario::try_from(file).map_err(|e| match locate(content, &e.field) {
+            Some((line, column)) => format!("{}:{}:{}: {}", path, line, column, e),
+            None => format!("{}: {}", path, e),
+        })
+    }
+
+    /**
+     * From `--scenario`, or from the flags it replaces when there's none
+     */
+    pub fn from_flags(flags: &Flags) -> Result<Self, String> {
+        let Some(scenario) = flags.get_optional::<Scenario>("scenario")? else {
+            let road_layout = flags.get("layout", RoadLayout::default())?;
+            return Ok(Scenario {
+                intersection_control: flags.get("control", IntersectionControl::default())?,
+                demand: TrafficDemand::from_flags(flags, &road_layout)?,
+                routing_config: RoutingConfig::from_flags(flags)?,
+                road_layout,
+                ..default()
+            });
+        };
+        if let Some(flag) = SCENARIO_FLAGS.iter().find(|flag| flags.contains(flag)) {
+            return Err(format!(
+                "'--{}' can't be used with '--scenario', set it in the scenario file",
+                flag
+            ));
+        }
+        Ok(scenario)
+    }
+
+    /**
+     * Inserts the resources the simulation's systems read the scenario from
+     */
+    pub fn insert_into(self, app: &mut App) {
+        app.insert_resource(self.road_layout)
+            .insert_resource(self.intersection_control)
+            .insert_resource(self.signal_plan)
+            .insert_resource(FaultSchedule::new(self.faults))
+            .insert_resource(self.vehicle_classes)
+            .insert_resource(self.demand)
+            .insert_resource(self.routing_config)
+            .insert_resource(self.model_parameters);
+    }
+
+    /**
+     * Everything the scenario sets as RON, in a canonical form: every field, in declaration
+     * order, whatever was left out of the file or written differently in it
+     */
+    pub fn canonical_ron(&self) -> String {
+        ron::to_string(self).expect("a scenario is only numbers, strings and lists")
+    }
+
+    /**
+     * Identifies runs of the same scenario however it was given (file or flags): the FNV-1a hash
+     * of `canonical_ron`, in hex
+     */
+    pub fn fingerprint(&self) -> String {
+        let hash = self
+            .canonical_ron()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("{:016x}", hash)
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        Scenario::load(path)
+    }
+}
+
+/**
+ * Reads RON just enough to find where a value starts, see `locate`
+ */
+struct RonCursor<'a> {
+    content: &'a str,
+    offset: usize,
+}
+
+impl<'a> RonCursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.content[self.offset..]
+    }
+
+    /**
+     * Skips whitespace and comments
+     */
+    fn skip_blank(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.offset += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.offset += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                self.offset += trimmed.find("*/").map_or(trimmed.len(), |end| end + 2);
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_blank();
+        let eaten = self.rest().starts_with(c);
+        if eaten {
+            self.offset += c.len_utf8();
+        }
+        eaten
+    }
+
+    fn identifier(&mut self) -> Option<&'a str> {
+        self.skip_blank();
+        let rest = self.rest();
+        let length = rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if length == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        self.offset += length;
+        Some(&rest[..length])
+    }
+
+    /**
+     * Skips the value starting here, up to the comma or bracket after it
+     */
+    fn skip_value(&mut self) {
+        let mut depth = 0;
+        loop {
+            self.skip_blank();
+            let Some(c) = self.rest().chars().next() else {
+                return;
+            };
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' | ',' if depth == 0 => return,
+                ')' | ']' | '}' => depth -= 1,
+                '"' | '\'' => {
+                    let mut escaped = false;
+                    let length = self.rest()[1..]
+                        .find(|next: char| {
+                            let closes = next == c && !escaped;
+                            escaped = next == '\\' && !escaped;
+                            closes
+                        })
+                        .map_or(self.rest().len(), |end| end + 2);
+                    self.offset += length;
+                    continue;
+                }
+                _ => {}
+            }
+            self.offset += c.len_utf8();
+        }
+    }
+
+    /**
+     * Moves into the struct, list or map starting here (e.g. after a variant's name), and returns
+     * its opening bracket
+     */
+    fn enter(&mut self) -> Option<char> {
+        let start = self.offset;
+        if self.identifier().is_some() && !self.rest().trim_start().starts_with('(') {
+            self.offset = start;
+            return None;
+        }
+        ['(', '[', '{']
+            .into_iter()
+            .find(|bracket| self.eat(*bracket))
+    }
+
+    /**
+     * Moves to the value of the struct field `name`, through whatever wraps the struct (e.g. `Some`)
+     */
+    fn field(&mut self, name: &str) -> bool {
+        loop {
+            if self.enter() != Some('(') {
+                return false;
+            }
+            let start = self.offset;
+            let is_struct = self.identifier().is_some() && self.eat(':');
+            self.offset = start;
+            if is_struct {
+                break;
+            }
+        }
+        while let Some(key) = self.identifier() {
+            if !self.eat(':') {
+                return false;
+            }
+            if key == name {
+                self.skip_blank();
+                return true;
+            }
+            self.skip_value();
+            if !self.eat(',') {
+                return false;
+            }
+        }
+        false
+    }
+
+    /**
+     * Moves to the `index`th element of the list, through whatever wraps it (e.g. a variant)
+     */
+    fn element(&mut self, index: usize) -> bool {
+        loop {
+            match self.enter() {
+                Some('[') => break,
+                Some('(') => continue,
+                _ => return false,
+            }
+        }
+        for _ in 0..index {
+            self.skip_value();
+            if !self.eat(',') {
+                return false;
+            }
+        }
+        self.skip_blank();
+        !self.rest().starts_with(']')
+    }
+}
+
+/**
+ * The line and column (from 1) where the value at `field` (a path like `demand.sources[1].lane`)
+ * starts in the scenario file `content`, if it's written there
+ */
+fn locate(content: &str, field: &str) -> Option<(usize, usize)> {
+    let mut cursor = RonCursor { content, offset: 0 };
+    for segment in field.split('.') {
+        let mut parts = segment.split('[');
+        let name = parts.next()?;
+        if !cursor.field(name) {
+            return None;
+        }
+        for index in parts {
+            if !cursor.element(index.strip_suffix(']')?.parse().ok()?) {
+                return None;
+            }
+        }
+    }
+    let before = &content[..cursor.offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    Some((
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    ))
+}
+
+/**
+ * A scenario loaded through the asset server, so it's reloaded when its file changes
+ */
+#[derive(Asset, TypePath, Debug)]
+pub struct ScenarioAsset(pub Scenario);
+
+#[derive(Default)]
+pub struct ScenarioLoader;
+
+impl AssetLoader for ScenarioLoader {
+    type Asset = ScenarioAsset;
+    type Settings = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<ScenarioAsset, Self::Error> {
+        let mut content = String::new();
+        reader.read_to_string(&mut content).await?;
+        let path = load_context.path().display().to_string();
+        Ok(ScenarioAsset(Scenario::parse(&content, &path)?))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+/**
+ * The asset path of the scenario file being watched
+ */
+#[derive(Resource, Debug, Clone)]
+pub struct WatchedScenario(pub String);
+
+/**
+ * Keeps the watched scenario's asset loaded, dropping the handle would stop the reloads
+ */
+#[derive(Resource)]
+pub struct WatchedScenarioHandle(pub Handle<ScenarioAsset>);
+
+/**
+ * Serves the directory of the scenario file at `path` as an asset source, so the file is watched
+ * wherever it is. It has to be called before the `AssetPlugin` is added.
+ */
+pub fn watch_scenario_file(app: &mut App, path: &str) {
+    let path = Path::new(path);
+    let directory = path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_string_lossy()
+        .to_string();
+    let file_name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    app.register_asset_source(
+        SCENARIO_SOURCE,
+        AssetSource::build()
+            .with_reader(AssetSource::get_default_reader(directory.clone()))
+            .with_watcher(AssetSource::get_default_watcher(directory, RELOAD_DEBOUNCE)),
+    )
+    .insert_resource(WatchedScenario(format!(
+        "{}://{}",
+        SCENARIO_SOURCE, file_name
+    )));
+}
+
+pub fn load_watched(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    watched_scenario: Option<Res<WatchedScenario>>,
+) {
+    if let Some(watched_scenario) = watched_scenario {
+        let handle = asset_server.load(watched_scenario.0.clone());
+        commands.insert_resource(WatchedScenarioHandle(handle));
+    }
+}
+
+/**
+ * Applies the watched scenario every time its file changes. Signal timings, faults, the control,
+ * routing and the driver model change live. A different road, demand or vehicle mix rebuilds the
+ * cars and sources and resets the simulation.
+ * A file that doesn't load keeps the previous scenario (the error is logged by the asset server).
+ */
+pub fn reload(
+    mut commands: Commands,
+    mut asset_events: EventReader<AssetEvent<ScenarioAsset>>,
+    scenarios: Res<Assets<ScenarioAsset>>,
+) {
+    for asset_event in asset_events.read() {
+        let AssetEvent::Modified { id } = asset_event else {
+            continue;
+        };
+        let Some(ScenarioAsset(scenario)) = scenarios.get(*id) else {
+            continue;
+        };
+        let scenario = scenario.clone();
+        commands.add(move |world: &mut World| apply(world, scenario));
+    }
+}
+
+fn apply(world: &mut World, scenario: Scenario) {
+    let needs_rebuild = *world.resource::<RoadLayout>() != scenario.road_layout
+        || *world.resource::<TrafficDemand>() != scenario.demand
+        || *world.resource::<VehicleClasses>() != scenario.vehicle_classes;
+    let mut fault_schedule = FaultSchedule::new(scenario.faults);
+    let faults_changed = world.resource::<FaultSchedule>().faults() != fault_schedule.faults();
+
+    for mut light_change_timer in world.query::<&mut LightChangeTimer>().iter_mut(world) {
+        light_change_timer.retime(&scenario.signal_plan);
+    }
+    world.insert_resource(scenario.signal_plan);
+    if faults_changed {
+        // The new faults take over where the run is, so the light shows the one that's on by now
+        // (or recovers from the old one)
+        fault_schedule.skip_to(world.resource::<FaultSchedule>().elapsed());
+        let active_fault = fault_schedule.active_fault();
+        for (mut current_light, mut light_change_timer) in world
+            .query_filtered::<(&mut CurrentLight, &mut LightChangeTimer), With<TrafficLight>>()
+            .iter_mut(world)
+        {
+            let light = match active_fault {
+                Some(fault) => fault,
+                None if current_light.0.is_fault() => Light::RedLight,
+                None => current_light.0,
+            };
+            if current_light.0 != light {
+                current_light.0 = light;
+                light_change_timer.restart();
+            }
+        }
+        world.insert_resource(fault_schedule);
+    }
+    world.insert_resource(scenario.intersection_control);
+    world.insert_resource(scenario.routing_config);
+    world.insert_resource(scenario.model_parameters);
+    if !needs_rebuild {
+        info!("Reloaded the scenario's timings and parameters");
+        return;
+    }
+
+    world.insert_resource(scenario.road_layout);
+    world.insert_resource(scenario.demand);
+    world.insert_resource(scenario.vehicle_classes);
+    let entities = world
+        .query_filtered::<Entity, Or<(With<Car>, With<VehicleSource>, With<VehicleSink>)>>()
+        .iter(world)
+        .collect::<Vec<_>>();
+    for entity in entities {
+        despawn_with_children_recursive(world, entity);
+    }
+    // The cars are queued here, the sources respawn with the reset
+    world.run_system_once(car_fleet::setup);
+    world.send_event(ResetSimluation);
+    info!("Reloaded the scenario and rebuilt the road");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "// A crossroads (with a comment)
+(
+    road: Crossroads,
+    demand: (
+        sources: [
+            (lane: 0, arrivals: Poisson(rate: 0.1), destinations: [(0, 1.0)]),
+            (lane: 1, arrivals: Poisson(rate: 0.1), destinations: [(1, 1.0), (7, 1.0)]),
+        ],
+    ),
+    od_matrix: Some((origins: [0], destinations: [1], trips: [[10.0]])),
+)
+";
+
+    #[test]
+    fn locates_nested_fields() {
+        assert_eq!(locate(FILE, "road"), Some((3, 11)));
+        assert_eq!(locate(FILE, "demand.sources[1].lane"), Some((7, 20)));
+        assert_eq!(
+            locate(FILE, "demand.sources[1].destinations[1]"),
+            Some((7, 78))
+        );
+        assert_eq!(locate(FILE, "od_matrix"), Some((10, 16)));
+        assert_eq!(locate(FILE, "demand.sources[2]"), None);
+        assert_eq!(locate(FILE, "routing"), None);
+    }
+
+    #[test]
+    fn validation_errors_have_positions() {
+        let without_od = FILE.replace("    od_matrix", "    // od_matrix");
+        assert_eq!(
+            Scenario::parse(&without_od, "file.ron").unwrap_err(),
+            "file.ron:7:78: demand.sources[1].destinations[1]: lane 7 doesn't exist, there are 2"
+        );
+        assert_eq!(
+            Scenario::parse(FILE, "file.ron").unwrap_err(),
+            "file.ron:5:18: demand.sources: can't be used with 'arrivals' or 'od_matrix'"
+        );
+    }
+
+    #[test]
+    fn lanes_have_one_source_at_most() {
+        let twice = FILE
+            .replace("    od_matrix", "    // od_matrix")
+            .replace("(lane: 1,", "(lane: 0,")
+            .replace(", (7, 1.0)", "");
+        assert_eq!(
+            Scenario::parse(&twice, "file.ron").unwrap_err(),
+            "file.ron:7:20: demand.sources[1].lane: lane 0 already has a source (demand.sources[0])"
+        );
+    }
+
+    #[test]
+    fn reloaded_faults_take_over_mid_run() {
+        use crate::{headless::HeadlessSimulation, traffic_light::SignalFault};
+
+        let fault = |at: f32, fault: Option<SignalFault>| ScheduledFault { at, fault };
+        let light = |simulation: &mut HeadlessSimulation| {
+            let world = simulation.world_mut();
+            world
+                .query_filtered::<&CurrentLight, With<TrafficLight>>()
+                .single(world)
+                .0
+        };
+        let scenario = Scenario {
+            faults: vec![fault(10.0, Some(SignalFault::Dark))],
+            ..default()
+        };
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, 0);
+        simulation.set_scenario(scenario.clone());
+        simulation.run_until(15.0);
+        assert_eq!(light(&mut simulation), Light::Dark);
+
+        // Flashing yellow since 5s, until 30s
+        let reloaded = Scenario {
+            faults: vec![
+                fault(5.0, Some(SignalFault::FlashingYellow)),
+                fault(30.0, None),
+            ],
+            ..scenario.clone()
+        };
+        apply(simulation.world_mut(), reloaded);
+        assert_eq!(light(&mut simulation), Light::FlashingYellow);
+        simulation.run_until(29.0);
+        assert_eq!(light(&mut simulation), Light::FlashingYellow);
+        simulation.run_until(31.0);
+        assert!(!light(&mut simulation).is_fault());
+
+        // A schedule whose fault hasn't come yet recovers the light right away
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, 0);
+        simulation.set_scenario(scenario.clone());
+        simulation.run_until(15.0);
+        let later = Scenario {
+            faults: vec![fault(20.0, Some(SignalFault::FlashingRed))],
+            ..scenario
+        };
+        apply(simulation.world_mut(), later);
+        assert_eq!(light(&mut simulation), Light::RedLight);
+        simulation.run_until(19.0);
+        assert!(!light(&mut simulation).is_fault());
+        simulation.run_until(21.0);
+        assert_eq!(light(&mut simulation), Light::FlashingRed);
+    }
+
+    #[test]
+    fn fingerprints_ignore_how_the_scenario_was_written() {
+        let fingerprint = |ron: &str| Scenario::parse(ron, "file.ron").unwrap().fingerprint();
+        let crossroads = fingerprint("(road: Crossroads)");
+        assert_eq!(
+            fingerprint("(road: Crossroads, signal_plan: (green: 10.0, offset: 0))"),
+            crossroads
+        );
+        assert_eq!(
+            fingerprint("// The default plan\n(\n    road: Crossroads,\n)"),
+            crossroads
+        );
+        assert_ne!(
+            fingerprint("(road: Crossroads, signal_plan: (green: 12.0))"),
+            crossroads
+        );
+        assert_ne!(fingerprint("()"), crossroads);
+    }
+
+    #[test]
+    fn parse_errors_have_positions() {
+        let error = Scenario::parse("(signal_plan: (green: -1.0))", "file.ron").unwrap_err();
+        assert!(error.starts_with("file.ron:1:"), "{}", error);
+    }
+}
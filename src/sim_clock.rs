@@ -0,0 +1,154 @@
+use bevy::{prelude::*, time::TimeSystem};
+
+/*
+The slowest and fastest the simulation can run, relative to real time
+ */
+pub const MIN_TIME_SCALE: f32 = 0.1;
+pub const MAX_TIME_SCALE: f32 = 100.0;
+/*
+The time scales going faster or slower steps through
+ */
+const TIME_SCALES: [f32; 10] = [0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0];
+
+/**
+ * How fast simulated time passes. It drives `Time<Virtual>`, which both the fixed timestep the
+ * cars move on and the traffic light's timers follow, so pausing or scaling time affects them
+ * together.
+ */
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct SimClock {
+    paused: bool,
+    time_scale: f32,
+    pending_steps: u32,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock {
+            paused: false,
+            time_scale: 1.0,
+            pending_steps: 0,
+        }
+    }
+}
+
+impl SimClock {
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.pending_steps = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    /**
+     * Pauses, and advances the simulation by a single fixed tick
+     */
+    pub fn step(&mut self) {
+        self.paused = true;
+        self.pending_steps += 1;
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /**
+     * Clamped between `MIN_TIME_SCALE` and `MAX_TIME_SCALE`
+     */
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+    }
+
+    /**
+     * The next of the `TIME_SCALES` above the current one
+     */
+    pub fn faster(&mut self) {
+        let time_scale = TIME_SCALES
+            .into_iter()
+            .find(|time_scale| *time_scale > self.time_scale)
+            .unwrap_or(MAX_TIME_SCALE);
+        self.set_time_scale(time_scale);
+    }
+
+    /**
+     * The next of the `TIME_SCALES` below the current one
+     */
+    pub fn slower(&mut self) {
+        let time_scale = TIME_SCALES
+            .into_iter()
+            .rev()
+            .find(|time_scale| *time_scale < self.time_scale)
+            .unwrap_or(MIN_TIME_SCALE);
+        self.set_time_scale(time_scale);
+    }
+}
+
+/**
+ * Lets `SimClock` control `Time<Virtual>`
+ */
+pub struct SimClockPlugin;
+
+impl Plugin for SimClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimClock>()
+            .add_systems(First, apply.after(TimeSystem));
+    }
+}
+
+/**
+ * Runs right after the clocks are advanced, so a step is seen by this frame's fixed ticks: the paused
+ * virtual clock is moved forward by exactly one timestep, which makes exactly one tick run.
+ * Pausing and the time scale take effect from the next frame.
+ */
+pub fn apply(
+    mut sim_clock: ResMut<SimClock>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    if virtual_time.relative_speed() != sim_clock.time_scale {
+        virtual_time.set_relative_speed(sim_clock.time_scale);
+    }
+    if sim_clock.paused != virtual_time.is_paused() {
+        if sim_clock.paused {
+            virtual_time.pause();
+        } else {
+            virtual_time.unpause();
+        }
+    }
+    if sim_clock.paused && sim_clock.pending_steps > 0 {
+        sim_clock.pending_steps -= 1;
+        virtual_time.advance_by(fixed_time.timestep());
+    }
+}
+
+/**
+ * Space pauses and resumes, `.` steps a single tick, `+` and `-` run faster and slower
+ */
+pub fn keyboard_controls(keys: Res<ButtonInput<KeyCode>>, mut sim_clock: ResMut<SimClock>) {
+    if keys.just_pressed(KeyCode::Space) {
+        sim_clock.toggle_pause();
+    }
+    if keys.just_pressed(KeyCode::Period) {
+        sim_clock.step();
+    }
+    if keys.any_just_pressed([KeyCode::Equal, KeyCode::NumpadAdd]) {
+        sim_clock.faster();
+    }
+    if keys.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]) {
+        sim_clock.slower();
+    }
+}
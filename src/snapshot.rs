@@ -0,0 +1,381 @@
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use bevy::{hierarchy::despawn_with_children_recursive, prelude::*};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+use traffic_core::timer::Timer;
+
+use crate::{
+    car_fleet::{
+        car::{
+            get_car_bundle, Acceleration, Car, IsBreaking, Performance, ReactionTimer,
+            StartingLane, StoppedAtLine, VehicleClass, Velocity,
+        },
+        source::{SourceState, VehicleSource},
+    },
+    metrics::{CarTrip, CarTrips, JunctionEntries, SignalChanges, SimulationMetrics},
+    rng::SimulationRng,
+    road::{OnLane, RoadLayout, Route},
+    routing::{Destination, SinceReroute},
+    traffic_light::{
+        CurrentLight, FaultSchedule, Light, LightChangeTimer, SignalPlan, TrafficLight,
+    },
+};
+
+/**
+ * A car as it was, every component the simulation changes
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedCar {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub velocity: f32,
+    pub acceleration: f32,
+    pub reaction_timer: Timer,
+    pub is_breaking: bool,
+    pub stopped_at_line: Option<f32>,
+    pub lane: usize,
+    pub starting_lane: usize,
+    pub route: VecDeque<usize>,
+    pub performance: Performance,
+    pub destination: Option<usize>,
+    /**
+     * What the metrics have measured of it, `None` before its first tick
+     */
+    pub trip: Option<CarTrip>,
+}
+
+/**
+ * The whole state of a running simulation, to carry on from exactly where it was: the cars, the
+ * light and its timers, the sources (with how far they are into their random streams), the clock
+ * and the metrics so far. The scenario isn't part of it, a snapshot is restored into a simulation
+ * of the same road (and sources) whose other parameters may have changed.
+ *
+ * It's saved as RON.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimulationSnapshot {
+    /**
+     * The simulated time, at the end of the last tick
+     */
+    pub time: Duration,
+    pub seed: u64,
+    pub light: Light,
+    /**
+     * Retimed to the signal plan it's restored with, which only changes it if the plan changed
+     */
+    pub light_timer: LightChangeTimer,
+    pub fault_schedule_elapsed: f32,
+    pub since_reroute: f32,
+    pub cars: Vec<SavedCar>,
+    pub sources: Vec<SourceState>,
+    pub metrics: SimulationMetrics,
+    pub junction_entries: Vec<Vec<f32>>,
+    pub signal_changes: Vec<(f32, Light)>,
+}
+
+impl SimulationSnapshot {
+    /**
+     * The state of the simulation in `world`, which has started (its `Startup` has run)
+     */
+    pub fn capture(world: &mut World) -> Self {
+        let mut light_q =
+            world.query_filtered::<(&CurrentLight, &LightChangeTimer), With<TrafficLight>>();
+        let mut source_q = world.query::<&VehicleSource>();
+        let mut car_q = world.query_filtered::<(
+            Entity,
+            &Transform,
+            &Velocity,
+            &Acceleration,
+            &ReactionTimer,
+            &IsBreaking,
+            &StoppedAtLine,
+            &OnLane,
+            &StartingLane,
+            &Route,
+            &Performance,
+            Option<&Destination>,
+        ), With<Car>>();
+        let (light, light_timer) = light_q.single(world);
+        let trips = world.resource::<CarTrips>();
+        // In the query's order, which is the order the cars are restored in and the order they
+        // move in, so the metrics are summed up the same
+        let cars = car_q
+            .iter(world)
+            .map(
+                |(
+                    entity,
+                    transform,
+                    velocity,
+                    acceleration,
+                    reaction_timer,
+                    is_breaking,
+                    stopped_at_line,
+                    on_lane,
+                    starting_lane,
+                    route,
+                    performance,
+                    destination,
+                )| SavedCar {
+                    translation: transform.translation,
+                    rotation: transform.rotation,
+                    velocity: velocity.0,
+                    acceleration: acceleration.0,
+                    reaction_timer: reaction_timer.0,
+                    is_breaking: is_breaking.0,
+                    stopped_at_line: stopped_at_line.0,
+                    lane: on_lane.0,
+                    starting_lane: starting_lane.0,
+                    route: route.0.clone(),
+                    performance: *performance,
+                    destination: destination.map(|destination| destination.0),
+                    trip: trips.0.get(&entity).copied(),
+                },
+            )
+            .collect();
+        SimulationSnapshot {
+            time: world.resource::<Time<Fixed>>().elapsed(),
+            seed: world.resource::<SimulationRng>().seed(),
+            light: light.0,
+            light_timer: *light_timer,
+            fault_schedule_elapsed: world.resource::<FaultSchedule>().elapsed(),
+            since_reroute: world.resource::<SinceReroute>().0,
+            cars,
+            sources: source_q.iter(world).map(VehicleSource::state).collect(),
+            metrics: *world.resource::<SimulationMetrics>(),
+            junction_entries: world.resource::<JunctionEntries>().0.clone(),
+            signal_changes: world.resource::<SignalChanges>().0.clone(),
+        }
+    }
+
+    /**
+     * Whether the snapshot fits the road and sources in `world`
+     */
+    pub fn check(&self, world: &mut World) -> Result<(), String> {
+        let lanes = world.resource::<RoadLayout>().lanes.len();
+        for car in self.cars.iter() {
+            let used = [car.lane, car.starting_lane]
+                .into_iter()
+                .chain(car.route.iter().copied())
+                .chain(car.destination);
+            if let Some(lane) = used.into_iter().find(|lane| *lane >= lanes) {
+                return Err(format!(
+                    "the snapshot has a car using lane {}, the road has {} lanes",
+                    lane, lanes
+                ));
+            }
+        }
+        let mut source_q = world.query::<&VehicleSource>();
+        let mut source_lanes = source_q
+            .iter(world)
+            .map(|source| source.lane)
+            .collect::<Vec<usize>>();
+        source_lanes.sort();
+        let mut saved_lanes = self
+            .sources
+            .iter()
+            .map(|source| source.lane)
+            .collect::<Vec<usize>>();
+        saved_lanes.sort();
+        if source_lanes != saved_lanes {
+            return Err(format!(
+                "the snapshot has sources on lanes {:?}, the scenario on {:?}",
+                saved_lanes, source_lanes
+            ));
+        }
+        Ok(())
+    }
+
+    /**
+     * Puts the simulation in `world` (which has started) back in the snapshot's state, replacing
+     * all its cars. Nothing is changed if the snapshot doesn't fit (see `check`).
+     */
+    pub fn restore(&self, world: &mut World) -> Result<(), String> {
+        self.check(world)?;
+        set_time(world, self.time);
+        world.insert_resource(SimulationRng::new(self.seed));
+        let signal_plan = *world.resource::<SignalPlan>();
+        let mut light_q = world
+            .query_filtered::<(&mut CurrentLight, &mut LightChangeTimer), With<TrafficLight>>();
+        let (mut light, mut light_timer) = light_q.single_mut(world);
+        light.0 = self.light;
+        *light_timer = self.light_timer;
+        light_timer.retime(&signal_plan);
+        world
+            .resource_mut::<FaultSchedule>()
+            .skip_to(self.fault_schedule_elapsed);
+        world.resource_mut::<SinceReroute>().0 = self.since_reroute;
+
+        let simulation_rng = *world.resource::<SimulationRng>();
+        let mut sources = self.sources.iter().map(Some).collect::<Vec<_>>();
+        let mut source_q = world.query::<&mut VehicleSource>();
+        for mut source in source_q.iter_mut(world) {
+            let state = sources
+                .iter_mut()
+                .find(|state| state.is_some_and(|state| state.lane == source.lane))
+                .and_then(Option::take)
+                .expect("the sources were checked");
+            source.restore(state, &simulation_rng);
+        }
+
+        let cars = world
+            .query_filtered::<Entity, With<Car>>()
+            .iter(world)
+            .collect::<Vec<Entity>>();
+        for entity in cars {
+            despawn_with_children_recursive(world, entity);
+        }
+        let mut trips = Vec::new();
+        for car in self.cars.iter() {
+            let class = VehicleClass {
+                reaction_time: car.reaction_timer.duration(),
+                max_velocity: car.performance.max_velocity,
+                acceleration: car.performance.acceleration,
+                deceleration: car.performance.deceleration,
+                ..default()
+            };
+            let mut entity = world.spawn(get_car_bundle(
+                Transform::from_translation(car.translation).with_rotation(car.rotation),
+                car.lane,
+                car.route.clone(),
+                &class,
+                Some(car.velocity),
+                Some(car.acceleration),
+            ));
+            entity.insert((
+                ReactionTimer(car.reaction_timer),
+                IsBreaking(car.is_breaking),
+                StoppedAtLine(car.stopped_at_line),
+                StartingLane(car.starting_lane),
+            ));
+            if let Some(destination) = car.destination {
+                entity.insert(Destination(destination));
+            }
+            if let Some(trip) = car.trip {
+                trips.push((entity.id(), trip));
+            }
+        }
+        world.resource_mut::<CarTrips>().0 = trips.into_iter().collect();
+        *world.resource_mut::<SimulationMetrics>() = self.metrics;
+        world.resource_mut::<JunctionEntries>().0 = self.junction_entries.clone();
+        world.resource_mut::<SignalChanges>().0 = self.signal_changes.clone();
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let content =
+            ron::ser::to_string_pretty(self, PrettyConfig::default()).map_err(io::Error::other)?;
+        fs::write(path, content)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("couldn't read '{}': {}", path, e))?;
+        ron::from_str(&content).map_err(|e| format!("{}:{}", path, e))
+    }
+}
+
+impl FromStr for SimulationSnapshot {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        SimulationSnapshot::load(path)
+    }
+}
+
+/**
+ * Moves every clock to `elapsed`, backwards too, keeping the virtual clock's speed and pause
+ */
+fn set_time(world: &mut World, elapsed: Duration) {
+    let mut fixed_time = Time::<Fixed>::from_duration(world.resource::<Time<Fixed>>().timestep());
+    fixed_time.advance_to(elapsed);
+    let old_virtual_time = world.resource::<Time<Virtual>>();
+    let mut virtual_time = Time::<Virtual>::from_max_delta(old_virtual_time.max_delta());
+    virtual_time.set_relative_speed_f64(old_virtual_time.relative_speed_f64());
+    if old_virtual_time.is_paused() {
+        virtual_time.pause();
+    }
+    virtual_time.advance_to(elapsed);
+    world.insert_resource(fixed_time);
+    world.insert_resource(virtual_time);
+    world.insert_resource(virtual_time.as_generic());
+}
+
+/**
+ * Where the app saves its state when F5 is pressed
+ */
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct SnapshotFile(pub PathBuf);
+
+pub fn save_on_key(world: &mut World) {
+    if !world
+        .resource::<ButtonInput<KeyCode>>()
+        .just_pressed(KeyCode::F5)
+    {
+        return;
+    }
+    let Some(SnapshotFile(path)) = world.get_resource::<SnapshotFile>().cloned() else {
+        return;
+    };
+    match SimulationSnapshot::capture(world).save(&path) {
+        Ok(()) => info!("Saved the simulation's state to {}", path.display()),
+        Err(e) => error!(
+            "Couldn't save the simulation's state to {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car_fleet::source::{ArrivalProcess, TrafficDemand},
+        headless::HeadlessSimulation,
+        road::RoadLayout,
+        scenario::Scenario,
+    };
+
+    fn simulation(scenario: &Scenario) -> HeadlessSimulation {
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, 3);
+        simulation.set_scenario(scenario.clone());
+        simulation
+    }
+
+    #[test]
+    fn carries_on_from_a_saved_snapshot_like_an_uninterrupted_run() {
+        let road_layout = RoadLayout::crossroads();
+        let scenario = Scenario {
+            demand: TrafficDemand::on_every_entry(
+                &road_layout,
+                ArrivalProcess::Poisson { rate: 0.3 },
+            ),
+            road_layout,
+            ..default()
+        };
+        let mut uninterrupted = simulation(&scenario);
+        uninterrupted.run_until(30.0);
+        let saved = uninterrupted.snapshot();
+        let path = std::env::temp_dir().join(format!("snapshot-{}.ron", std::process::id()));
+        saved.save(&path).unwrap();
+        let loaded = SimulationSnapshot::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded, saved);
+
+        let mut restored = simulation(&scenario);
+        restored.restore(&loaded).unwrap();
+        assert_eq!(restored.snapshot(), saved);
+        let metrics = uninterrupted.run_for(30.0);
+        assert_eq!(restored.run_for(30.0), metrics);
+        assert!(metrics.throughput > 0);
+        assert_eq!(restored.snapshot(), uninterrupted.snapshot());
+    }
+}
@@ -0,0 +1,352 @@
+use std::str::FromStr;
+
+use bevy::utils::Instant;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    cli::Flags,
+    headless::{self, HeadlessSimulation},
+    metrics::{Metric, SimulationMetrics},
+    scenario::Scenario,
+    sweep::Parameter,
+};
+
+/*
+The driver parameters analysed when none are given, with the bounds they're sampled between
+ */
+const DEFAULT_INPUTS: [(Parameter, f32, f32); 5] = [
+    (Parameter::ReactionTime, 0.2, 1.5),
+    (Parameter::MaxVelocity, 0.06, 0.14),
+    (Parameter::Acceleration, 0.0015, 0.005),
+    (Parameter::Deceleration, 0.005, 0.02),
+    (Parameter::BreakDistance, 4.0, 9.0),
+];
+/*
+How many times the runs are resampled for the indices' confidence intervals
+ */
+const BOOTSTRAP_RESAMPLES: usize = 200;
+
+/**
+ * The range an input is sampled uniformly from, written `<min>:<max>`. Parsing one makes sure
+ * `min` is below `max`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl FromStr for Bounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("expected <min>:<max> with min below max, got '{}'", s);
+        let (min, max) = s.split_once(':').ok_or_else(usage)?;
+        let bounds = Bounds {
+            min: min.trim().parse().map_err(|_| usage())?,
+            max: max.trim().parse().map_err(|_| usage())?,
+        };
+        if bounds.min >= bounds.max {
+            return Err(usage());
+        }
+        Ok(bounds)
+    }
+}
+
+pub struct SobolConfig {
+    pub scenario: Scenario,
+    pub inputs: Vec<(Parameter, Bounds)>,
+    /**
+     * The base sample size N, the analysis runs N * (inputs + 2) simulations
+     */
+    pub samples: usize,
+    pub duration: f32,
+    /**
+     * Seeds both the sampling and every simulation, which all share the same random arrivals so
+     * the outputs only vary with the inputs
+     */
+    pub seed: u64,
+    pub threads: usize,
+}
+
+impl SobolConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut known_flags = vec![
+            "scenario",
+            "layout",
+            "control",
+            "arrivals",
+            "od",
+            "travel-times",
+            "reroute",
+            "inputs",
+            "samples",
+            "duration",
+            "seed",
+            "threads",
+        ];
+        known_flags.extend(DEFAULT_INPUTS.map(|(parameter, _, _)| parameter.flag()));
+        let flags = Flags::parse(args, &known_flags)?;
+        let names = flags.get_optional::<String>("inputs")?;
+        let inputs = DEFAULT_INPUTS
+            .into_iter()
+            .filter(|(parameter, _, _)| {
+                names.as_ref().is_none_or(|names| {
+                    names.split(',').any(|name| name.trim() == parameter.flag())
+                })
+            })
+            .map(|(parameter, min, max)| {
+                let bounds = flags.get(parameter.flag(), Bounds { min, max })?;
+                parameter.check(bounds.min)?;
+                parameter.check(bounds.max)?;
+                Ok((parameter, bounds))
+            })
+            .collect::<Result<Vec<(Parameter, Bounds)>, String>>()?;
+        if inputs.is_empty() {
+            return Err(format!(
+                "'--inputs' must be some of: {}",
+                DEFAULT_INPUTS
+                    .map(|(parameter, _, _)| parameter.flag())
+                    .join(",")
+            ));
+        }
+        let config = SobolConfig {
+            scenario: Scenario::from_flags(&flags)?,
+            inputs,
+            samples: flags.get("samples", 64)?,
+            duration: flags.get("duration", 120.0)?,
+            seed: flags.get("seed", 0)?,
+            threads: flags.get("threads", headless::default_threads())?,
+        };
+        if config.samples < 2 {
+            return Err("'--samples' must be at least 2".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/**
+ * First-order (`first`) and total (`total`) Sobol indices of an input for an output, with the
+ * half-widths of their 95% bootstrap confidence intervals
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Indices {
+    pub first: f64,
+    pub first_conf: f64,
+    pub total: f64,
+    pub total_conf: f64,
+}
+
+/**
+ * Saltelli (2010)'s estimator of the first-order index and Jansen's of the total one, over the
+ * runs in `sample` (indices into `a`, `b` and `ab`)
+ */
+fn estimate(a: &[f64], b: &[f64], ab: &[f64], sample: &[usize]) -> (f64, f64) {
+    let n = sample.len() as f64;
+    let outputs = sample.iter().flat_map(|j| [a[*j], b[*j]]);
+    let mean = outputs.clone().sum::<f64>() / (2.0 * n);
+    let variance = outputs.map(|y| (y - mean).powi(2)).sum::<f64>() / (2.0 * n - 1.0);
+    if variance == 0.0 {
+        return (f64::NAN, f64::NAN);
+    }
+    // Centering `b` doesn't change the estimate's expectation but makes it much less noisy
+    let first = sample
+        .iter()
+        .map(|j| (b[*j] - mean) * (ab[*j] - a[*j]))
+        .sum::<f64>()
+        / n;
+    let total = sample.iter().map(|j| (a[*j] - ab[*j]).powi(2)).sum::<f64>() / (2.0 * n);
+    (first / variance, total / variance)
+}
+
+/**
+ * The half-width of the middle 95% of `values`
+ */
+fn percentile_half_width(mut values: Vec<f64>) -> f64 {
+    values.retain(|value| value.is_finite());
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    values.sort_by(f64::total_cmp);
+    let at = |fraction: f64| values[((values.len() - 1) as f64 * fraction).round() as usize];
+    (at(0.975) - at(0.025)) / 2.0
+}
+
+fn indices(a: &[f64], b: &[f64], ab: &[f64], rng: &mut StdRng) -> Indices {
+    let n = a.len();
+    let (first, total) = estimate(a, b, ab, &(0..n).collect::<Vec<usize>>());
+    let (firsts, totals): (Vec<f64>, Vec<f64>) = (0..BOOTSTRAP_RESAMPLES)
+        .map(|_| {
+            let resample = (0..n).map(|_| rng.gen_range(0..n)).collect::<Vec<usize>>();
+            estimate(a, b, ab, &resample)
+        })
+        .unzip();
+    Indices {
+        first,
+        first_conf: percentile_half_width(firsts),
+        total,
+        total_conf: percentile_half_width(totals),
+    }
+}
+
+fn run_sample(config: &SobolConfig, values: &[f32]) -> SimulationMetrics {
+    let mut scenario = config.scenario.clone();
+    for ((parameter, _), value) in config.inputs.iter().zip(values) {
+        parameter.apply(*value, &mut scenario);
+    }
+    let mut simulation = HeadlessSimulation::new(scenario.signal_plan, config.seed);
+    simulation.set_scenario(scenario);
+    simulation.run_until(config.duration)
+}
+
+/**
+ * Variance-based global sensitivity analysis: two independent random samples of the inputs, `A`
+ * and `B`, and for every input `i` the sample `AB_i` (`A` with `B`'s values of input `i`) are run
+ * (Saltelli's scheme), and every metric's first-order and total indices are estimated from them.
+ * The first-order index is the share of the output's variance an input explains alone, the total
+ * one adds its interactions with the other inputs.
+ */
+pub fn run(config: &SobolConfig) {
+    let started = Instant::now();
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut draw = || {
+        config
+            .inputs
+            .iter()
+            .map(|(_, bounds)| rng.gen_range(bounds.min..bounds.max))
+            .collect::<Vec<f32>>()
+    };
+    let a = (0..config.samples).map(|_| draw()).collect::<Vec<_>>();
+    let b = (0..config.samples).map(|_| draw()).collect::<Vec<_>>();
+    let mut samples = a.clone();
+    samples.extend(b.iter().cloned());
+    for i in 0..config.inputs.len() {
+        samples.extend(a.iter().zip(b.iter()).map(|(a, b)| {
+            let mut ab = a.clone();
+            ab[i] = b[i];
+            ab
+        }));
+    }
+    eprintln!(
+        "running {} simulations on {} threads",
+        samples.len(),
+        config.threads
+    );
+    let results = headless::run_in_parallel(&samples, config.threads, |values| {
+        run_sample(config, values)
+    });
+
+    let n = config.samples;
+    println!(
+        "{:<10} | {:<14} | {:>7} {:>7} | {:>7} {:>7}",
+        "metric", "input", "S1", "conf", "ST", "conf"
+    );
+    for metric in Metric::ALL {
+        let outputs = results
+            .iter()
+            .map(|metrics| metric.value(metrics) as f64)
+            .collect::<Vec<f64>>();
+        let (a, rest) = outputs.split_at(n);
+        let (b, abs) = rest.split_at(n);
+        for (i, (parameter, _)) in config.inputs.iter().enumerate() {
+            let indices = indices(a, b, &abs[i * n..(i + 1) * n], &mut rng);
+            println!(
+                "{:<10} | {:<14} | {:>7.3} {:>7.3} | {:>7.3} {:>7.3}",
+                metric.name(),
+                parameter.flag(),
+                indices.first,
+                indices.first_conf,
+                indices.total,
+                indices.total_conf
+            );
+        }
+    }
+    eprintln!(
+        "ran {} simulations in {:.2}s",
+        results.len(),
+        started.elapsed().as_secs_f32()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    /**
+     * The first-order and total indices of every input of `model`, from `n` runs of Saltelli's
+     * scheme with inputs uniform in `[-PI, PI]`
+     */
+    fn analyse(model: impl Fn(&[f64]) -> f64, inputs: usize, n: usize) -> Vec<(f64, f64)> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut draw = || {
+            (0..inputs)
+                .map(|_| rng.gen_range(-PI..PI))
+                .collect::<Vec<f64>>()
+        };
+        let a = (0..n).map(|_| draw()).collect::<Vec<_>>();
+        let b = (0..n).map(|_| draw()).collect::<Vec<_>>();
+        let outputs_a = a.iter().map(|x| model(x)).collect::<Vec<f64>>();
+        let outputs_b = b.iter().map(|x| model(x)).collect::<Vec<f64>>();
+        let sample = (0..n).collect::<Vec<usize>>();
+        (0..inputs)
+            .map(|i| {
+                let outputs_ab = a
+                    .iter()
+                    .zip(b.iter())
+                    .map(|(a, b)| {
+                        let mut ab = a.clone();
+                        ab[i] = b[i];
+                        model(&ab)
+                    })
+                    .collect::<Vec<f64>>();
+                estimate(&outputs_a, &outputs_b, &outputs_ab, &sample)
+            })
+            .collect()
+    }
+
+    fn assert_indices(actual: &[(f64, f64)], expected: &[(f64, f64)], tolerance: f64) {
+        for (i, ((first, total), (expected_first, expected_total))) in
+            actual.iter().zip(expected).enumerate()
+        {
+            assert!(
+                (first - expected_first).abs() < tolerance
+                    && (total - expected_total).abs() < tolerance,
+                "input {}: got ({:.3}, {:.3}), expected ({:.3}, {:.3})",
+                i,
+                first,
+                total,
+                expected_first,
+                expected_total
+            );
+        }
+    }
+
+    #[test]
+    fn estimates_a_linear_model() {
+        // Without interactions every input's first-order and total indices are its share of
+        // the variance, 1:4:0
+        let indices = analyse(|x| x[0] + 2.0 * x[1], 3, 10_000);
+        assert_indices(&indices, &[(0.2, 0.2), (0.8, 0.8), (0.0, 0.0)], 0.02);
+    }
+
+    #[test]
+    fn estimates_the_ishigami_function() {
+        // a = 7 and b = 0.1, the indices are known analytically
+        let ishigami =
+            |x: &[f64]| x[0].sin() + 7.0 * x[1].sin().powi(2) + 0.1 * x[2].powi(4) * x[0].sin();
+        let indices = analyse(ishigami, 3, 20_000);
+        assert_indices(
+            &indices,
+            &[(0.314, 0.558), (0.442, 0.442), (0.0, 0.244)],
+            0.03,
+        );
+    }
+
+    #[test]
+    fn has_no_indices_without_variance() {
+        let (first, total) = analyse(|_| 1.0, 2, 100)[0];
+        assert!(first.is_nan() && total.is_nan());
+    }
+}
@@ -0,0 +1,414 @@
+use std::{fmt::Write as _, fs, io, path::PathBuf, str::FromStr};
+
+use bevy::utils::Instant;
+
+use crate::{
+    car_fleet::source::{ArrivalProcess, TrafficDemand},
+    cli::Flags,
+    database::{self, Database, RunMetadata},
+    headless::{self, HeadlessSimulation},
+    metrics::SimulationMetrics,
+    scenario::Scenario,
+};
+
+/**
+ * What a sweep can vary, named after its flag
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parameter {
+    /**
+     * Of every vehicle class, in seconds
+     */
+    ReactionTime,
+    /**
+     * Of the signal plan, in seconds
+     */
+    Green,
+    /**
+     * Poisson arrivals on every entry, in cars per second, replacing the scenario's demand
+     */
+    ArrivalRate,
+    /**
+     * Queued on every entry when there are no sources
+     */
+    Cars,
+    /**
+     * Of every vehicle class, per tick
+     */
+    MaxVelocity,
+    /**
+     * Of every vehicle class, per tick squared
+     */
+    Acceleration,
+    Deceleration,
+    /**
+     * The driver model's, the distance kept to the car or stop line ahead
+     */
+    BreakDistance,
+}
+
+pub const PARAMETERS: [Parameter; 8] = [
+    Parameter::ReactionTime,
+    Parameter::Green,
+    Parameter::ArrivalRate,
+    Parameter::Cars,
+    Parameter::MaxVelocity,
+    Parameter::Acceleration,
+    Parameter::Deceleration,
+    Parameter::BreakDistance,
+];
+
+impl Parameter {
+    pub fn flag(&self) -> &'static str {
+        match self {
+            Parameter::ReactionTime => "reaction-time",
+            Parameter::Green => "green",
+            Parameter::ArrivalRate => "arrival-rate",
+            Parameter::Cars => "cars",
+            Parameter::MaxVelocity => "max-velocity",
+            Parameter::Acceleration => "acceleration",
+            Parameter::Deceleration => "deceleration",
+            Parameter::BreakDistance => "break-distance",
+        }
+    }
+
+    pub fn column(&self) -> String {
+        self.flag().replace('-', "_")
+    }
+
+    pub fn check(&self, value: f32) -> Result<(), String> {
+        match self {
+            Parameter::Cars if value < 0.0 || value.fract() != 0.0 => Err(format!(
+                "'--{}' must be whole numbers of cars, got {}",
+                self.flag(),
+                value
+            )),
+            Parameter::Cars => Ok(()),
+            _ if value <= 0.0 => Err(format!(
+                "'--{}' must be positive, got {}",
+                self.flag(),
+                value
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply(&self, value: f32, scenario: &mut Scenario) {
+        match self {
+            Parameter::ReactionTime => {
+                for class in scenario.vehicle_classes.0.iter_mut() {
+                    class.reaction_time = value;
+                }
+            }
+            Parameter::Green => scenario.signal_plan.green = value,
+            Parameter::ArrivalRate => {
+                scenario.demand = TrafficDemand::on_every_entry(
+                    &scenario.road_layout,
+                    ArrivalProcess::Poisson { rate: value },
+                );
+            }
+            Parameter::Cars => scenario.demand.initial_queue = value as usize,
+            Parameter::MaxVelocity => {
+                for class in scenario.vehicle_classes.0.iter_mut() {
+                    class.max_velocity = value;
+                }
+            }
+            Parameter::Acceleration => {
+                for class in scenario.vehicle_classes.0.iter_mut() {
+                    class.acceleration = value;
+                }
+            }
+            Parameter::Deceleration => {
+                for class in scenario.vehicle_classes.0.iter_mut() {
+                    class.deceleration = value;
+                }
+            }
+            Parameter::BreakDistance => scenario.model_parameters.break_distance = value,
+        }
+    }
+}
+
+/**
+ * The values a parameter takes: `<start>:<end>:<step>` (both ends included) or a single value
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepRange(pub Vec<f32>);
+
+impl FromStr for SweepRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("expected <start>:<end>:<step> or a number, got '{}'", s);
+        let numbers = s
+            .split(':')
+            .map(|number| number.trim().parse::<f64>().map_err(|_| usage()))
+            .collect::<Result<Vec<f64>, String>>()?;
+        match numbers.as_slice() {
+            [value] => Ok(SweepRange(vec![*value as f32])),
+            [start, end, step] if *step > 0.0 && end >= start => {
+                // Counted rather than accumulated, so e.g. 0.1:2.0:0.1 ends at exactly 2.0
+                let steps = ((end - start) / step + 1e-6).floor() as usize;
+                Ok(SweepRange(
+                    (0..=steps)
+                        .map(|i| ((start + i as f64 * step) * 1e6).round() / 1e6)
+                        .map(|value| value as f32)
+                        .collect(),
+                ))
+            }
+            _ => Err(usage()),
+        }
+    }
+}
+
+pub struct SweepConfig {
+    pub scenario: Scenario,
+    pub parameters: Vec<(Parameter, Vec<f32>)>,
+    pub duration: f32,
+    pub seed: u64,
+    pub threads: usize,
+    pub output: PathBuf,
+    /**
+     * Every run is also added to this SQLite database, with its trajectories when there's a
+     * `sample_interval`
+     */
+    pub database: Option<PathBuf>,
+    pub sample_interval: Option<f32>,
+}
+
+impl SweepConfig {
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let flags = Flags::parse(
+            args,
+            &[
+                "scenario",
+                "layout",
+                "control",
+                "arrivals",
+                "od",
+                "travel-times",
+                "reroute",
+                "reaction-time",
+                "green",
+                "arrival-rate",
+                "cars",
+                "max-velocity",
+                "acceleration",
+                "deceleration",
+                "break-distance",
+                "duration",
+                "seed",
+                "threads",
+                "output",
+                "database",
+                "sample-interval",
+            ],
+        )?;
+        let scenario = Scenario::from_flags(&flags)?;
+        let mut parameters = Vec::new();
+        for parameter in PARAMETERS {
+            if let Some(range) = flags.get_optional::<SweepRange>(parameter.flag())? {
+                for value in range.0.iter() {
+                    parameter.check(*value)?;
+                }
+                parameters.push((parameter, range.0));
+            }
+        }
+        if parameters.is_empty() {
+            return Err(format!(
+                "nothing to sweep, expected at least one of: --{}",
+                PARAMETERS.map(|parameter| parameter.flag()).join(", --")
+            ));
+        }
+        let sweeps = |parameter| parameters.iter().any(|(swept, _)| *swept == parameter);
+        if sweeps(Parameter::Cars)
+            && (sweeps(Parameter::ArrivalRate) || !scenario.demand.sources.is_empty())
+        {
+            return Err("'--cars' are the initial queues, there are none with sources".to_string());
+        }
+        let config = SweepConfig {
+            scenario,
+            parameters,
+            duration: flags.get("duration", 120.0)?,
+            seed: flags.get("seed", 0)?,
+            threads: flags.get("threads", headless::default_threads())?,
+            output: flags.get("output", PathBuf::from("sweep.csv"))?,
+            database: flags.get_optional("database")?,
+            sample_interval: flags.get_optional("sample-interval")?,
+        };
+        if let Some(interval) = config.sample_interval {
+            if interval <= 0.0 {
+                return Err("'--sample-interval' must be positive".to_string());
+            }
+            if config.database.is_none() {
+                return Err("'--sample-interval' needs '--database'".to_string());
+            }
+        }
+        Ok(config)
+    }
+
+    /**
+     * Every combination of the parameters' values, the last parameter varying fastest
+     */
+    fn combinations(&self) -> Vec<Vec<f32>> {
+        self.parameters
+            .iter()
+            .fold(vec![Vec::new()], |combinations, (_, values)| {
+                combinations
+                    .iter()
+                    .flat_map(|combination| {
+                        values.iter().map(move |value| {
+                            let mut combination = combination.clone();
+                            combination.push(*value);
+                            combination
+                        })
+                    })
+                    .collect()
+            })
+    }
+}
+
+/**
+ * With a database, the run is written into it as run `id`
+ */
+fn run_combination(
+    config: &SweepConfig,
+    values: &[f32],
+    database: Option<(&Database, i64)>,
+) -> io::Result<SimulationMetrics> {
+    let mut scenario = config.scenario.clone();
+    for ((parameter, _), value) in config.parameters.iter().zip(values) {
+        parameter.apply(*value, &mut scenario);
+    }
+    let mut simulation = HeadlessSimulation::new(scenario.signal_plan, config.seed);
+    simulation.set_scenario(scenario.clone());
+    if database.is_some() {
+        simulation.record_trajectories(database::recording(config.sample_interval));
+    }
+    let metrics = simulation.run_until(config.duration);
+    if let Some((database, id)) = database {
+        let parameters = config
+            .parameters
+            .iter()
+            .zip(values)
+            .map(|((parameter, _), value)| (parameter.column(), *value))
+            .collect::<Vec<(String, f32)>>();
+        let run = RunMetadata {
+            scenario: &scenario,
+            seed: config.seed,
+            duration: config.duration,
+            parameters: &parameters,
+        };
+        database.write_run(id, &run, &simulation)?;
+    }
+    Ok(metrics)
+}
+
+/**
+ * Runs the scenario headless for every combination of the swept parameters, spread over
+ * `config.threads`, and writes a CSV row of metrics per run (and every run into the database)
+ */
+pub fn run(config: &SweepConfig) -> io::Result<()> {
+    let started = Instant::now();
+    let combinations = config.combinations();
+    let database = config.database.as_deref().map(Database::open).transpose()?;
+    // The runs are numbered in the combinations' order whichever thread finishes first
+    let first_run = database
+        .as_ref()
+        .map(Database::next_run_id)
+        .transpose()?
+        .unwrap_or_default();
+    eprintln!(
+        "running {} combinations on {} threads",
+        combinations.len(),
+        config.threads
+    );
+    let jobs = combinations.iter().enumerate().collect::<Vec<_>>();
+    let results = headless::run_in_parallel(&jobs, config.threads, |(i, values)| {
+        let run = database
+            .as_ref()
+            .map(|database| (database, first_run + *i as i64));
+        run_combination(config, values, run)
+    })
+    .into_iter()
+    .collect::<io::Result<Vec<SimulationMetrics>>>()?;
+
+    let mut csv = String::new();
+    for (parameter, _) in config.parameters.iter() {
+        write!(csv, "{},", parameter.column()).unwrap();
+    }
+    csv.push_str("seed,elapsed,delay,stops,throughput\n");
+    for (values, metrics) in combinations.iter().zip(results.iter()) {
+        for value in values {
+            write!(csv, "{},", value).unwrap();
+        }
+        writeln!(
+            csv,
+            "{},{},{},{},{}",
+            config.seed, metrics.elapsed, metrics.total_delay, metrics.stops, metrics.throughput
+        )
+        .unwrap();
+    }
+    fs::write(&config.output, csv)?;
+    eprintln!(
+        "wrote {} runs to {} in {:.2}s",
+        results.len(),
+        config.output.display(),
+        started.elapsed().as_secs_f32()
+    );
+    if let Some(path) = &config.database {
+        eprintln!(
+            "added them to {} as runs {} to {}",
+            path.display(),
+            first_run,
+            first_run + results.len() as i64 - 1
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> Result<Vec<f32>, String> {
+        s.parse::<SweepRange>().map(|range| range.0)
+    }
+
+    #[test]
+    fn parses_ranges_including_both_ends() {
+        assert_eq!(range("1:3:1"), Ok(vec![1.0, 2.0, 3.0]));
+        assert_eq!(range(" 0 : 1 : 0.25 "), Ok(vec![0.0, 0.25, 0.5, 0.75, 1.0]));
+        assert_eq!(range("2:2:1"), Ok(vec![2.0]));
+        // The end is left out when the steps overshoot it
+        assert_eq!(range("0:1:0.4"), Ok(vec![0.0, 0.4, 0.8]));
+        assert_eq!(range("-1:1:1"), Ok(vec![-1.0, 0.0, 1.0]));
+    }
+
+    #[test]
+    fn counts_steps_without_accumulating_errors() {
+        let values = range("0.1:2.0:0.1").unwrap();
+        assert_eq!(values.len(), 20);
+        assert_eq!(values[2], 0.3);
+        assert_eq!(values[19], 2.0);
+    }
+
+    #[test]
+    fn parses_a_single_value() {
+        assert_eq!(range("0.5"), Ok(vec![0.5]));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for invalid in [
+            "", "a", "1:2", "1:2:3:4", "1:2:0", "1:2:-1", "2:1:1", "0:x:1",
+        ] {
+            assert_eq!(
+                range(invalid),
+                Err(format!(
+                    "expected <start>:<end>:<step> or a number, got '{}'",
+                    invalid
+                ))
+            );
+        }
+    }
+}
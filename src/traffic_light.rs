@@ -1,26 +1,79 @@
-use core::fmt;
-
 use bevy::prelude::*;
 
-use crate::ui_components::reset_simulation_button::ResetSimluation;
+pub use traffic_core::signal::*;
+
+use crate::{
+    car_fleet::CarFleetSet, road::IntersectionControl,
+    ui_components::reset_simulation_button::ResetSimluation,
+};
+
+/**
+ * The traffic light at the intersection: its plan, faults and (when `render` is set) its model
+ */
+pub struct TrafficLightPlugin {
+    /**
+     * Loads the traffic light's model and lights its lamps, without it only the light's state is
+     * simulated
+     */
+    pub render: bool,
+}
+
+impl Default for TrafficLightPlugin {
+    fn default() -> Self {
+        TrafficLightPlugin { render: true }
+    }
+}
 
-#[derive(Debug, Clone, Copy)]
-pub enum Light {
-    RedLight,
-    GreenLight,
-    YellowLight,
+/**
+ * The traffic light's state machine, in `FixedUpdate` before the cars spawn and move, so every tick
+ * the cars see the light as it is after that tick's change. `Timers` decides the light changes
+ * (from the plan, an external controller or the fault schedule), and `Change` applies them.
+ */
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficLightSet {
+    Timers,
+    Change,
 }
 
-impl fmt::Display for Light {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Light::RedLight => write!(f, "RedLight"),
-            Light::GreenLight => write!(f, "GreenLight"),
-            Light::YellowLight => write!(f, "YellowLight"),
+impl Plugin for TrafficLightPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SignalPlan>()
+            .init_resource::<SignalControl>()
+            .init_resource::<FaultSchedule>()
+            .init_resource::<IntersectionControl>()
+            .add_event::<LightChange>()
+            .add_event::<ResetSimluation>()
+            .configure_sets(
+                FixedUpdate,
+                (TrafficLightSet::Timers, TrafficLightSet::Change)
+                    .chain()
+                    .before(CarFleetSet::Spawn),
+            )
+            .add_systems(Startup, setup)
+            .add_systems(
+                FixedUpdate,
+                (
+                    (update_event_emitter, apply_fault_schedule)
+                        .chain()
+                        .in_set(TrafficLightSet::Timers),
+                    update.in_set(TrafficLightSet::Change),
+                ),
+            )
+            .add_systems(Update, reset_simulation_listener);
+        if self.render {
+            app.add_systems(Startup, setup_scene.after(setup))
+                .add_systems(Update, (show_lamps, flash).chain());
         }
     }
 }
 
+const LAMPS: [Light; 3] = [Light::RedLight, Light::GreenLight, Light::YellowLight];
+
+/**
+ * Seconds a flashing lamp is on, and then off
+ */
+const FLASH_HALF_PERIOD: f32 = 0.5;
+
 #[derive(Component)]
 pub struct TrafficLight;
 
@@ -30,143 +83,162 @@ pub struct CurrentLight(pub Light);
 #[derive(Component)]
 pub struct HandleId(AssetId<Scene>);
 
-#[derive(Component)]
-pub struct LightChangeTimer {
-    go: Timer,
-    stop: Timer,
-    yellow: Timer,
-}
-
 #[derive(Event)]
 pub struct LightChange {
     pub light: Light,
 }
 
-pub fn setup(
+pub fn setup(mut commands: Commands, signal_plan: Res<SignalPlan>) {
+    let (light, timers) = signal_plan.initial_state();
+    commands.spawn((
+        SpatialBundle::from_transform(
+            Transform::from_xyz(0.0, 0.0, 0.0)
+                .with_rotation(Quat::from_rotation_y(std::f32::consts::PI)),
+        ),
+        TrafficLight {},
+        CurrentLight(light),
+        timers,
+    ));
+}
+
+/**
+ * The traffic light's model is only needed when rendering, so it's attached separately from `setup`
+ * (the headless simulation never runs this system).
+ */
+pub fn setup_scene(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    mut event_writer: EventWriter<LightChange>,
+    intersection_control: Res<IntersectionControl>,
+    traffic_light_q: Query<Entity, With<TrafficLight>>,
 ) {
+    // Without a signal the light only marks where the intersection is
+    if *intersection_control != IntersectionControl::Signal {
+        return;
+    }
     let asset_path = "traffic_light.gltf#Scene0";
     let scene_handle: Handle<Scene> = asset_server.load(asset_path);
-    commands.spawn((
-        SceneBundle {
-            scene: scene_handle.clone(),
-            transform: Transform::from_xyz(0.0, 0.0, 0.0)
-                .with_rotation(Quat::from_rotation_y(std::f32::consts::PI)),
-            ..Default::default()
-        },
-        TrafficLight {},
-        CurrentLight { 0: Light::RedLight },
-        HandleId {
-            0: scene_handle.id(),
-        },
-        LightChangeTimer {
-            go: Timer::from_seconds(10.0, TimerMode::Once),
-            stop: Timer::from_seconds(10.0, TimerMode::Once),
-            yellow: Timer::from_seconds(1.0, TimerMode::Once),
-        },
-    ));
-    // TODO: This event doesn't do anything, we need to wait for the asset to load
-    event_writer.send(LightChange {
-        light: Light::RedLight,
-    });
+    for traffic_light_entity in traffic_light_q.iter() {
+        commands
+            .entity(traffic_light_entity)
+            .insert((scene_handle.clone(), HandleId(scene_handle.id())));
+    }
 }
 
+/**
+ * The timers tick by the fixed timestep, like the cars, so they follow the `SimClock` and a change
+ * always happens between two ticks
+ */
 pub fn update_event_emitter(
     mut traffic_light_q: Query<(&CurrentLight, &mut LightChangeTimer), With<TrafficLight>>,
     time: Res<Time>,
+    signal_control: Res<SignalControl>,
     mut event_writer: EventWriter<LightChange>,
 ) {
-    let (current_light, mut light_change_timers) = traffic_light_q.single_mut();
-
-    match current_light.0 {
-        Light::RedLight => {
-            light_change_timers.stop.tick(time.delta());
-            if light_change_timers.stop.finished() {
-                event_writer.send(LightChange {
-                    light: Light::GreenLight,
-                });
-            }
-        }
-        Light::YellowLight => {
-            light_change_timers.yellow.tick(time.delta());
-            if light_change_timers.yellow.finished() {
-                event_writer.send(LightChange {
-                    light: Light::RedLight,
-                });
-            }
-        }
-        Light::GreenLight => {
-            light_change_timers.go.tick(time.delta());
-            if light_change_timers.go.finished() {
-                event_writer.send(LightChange {
-                    light: Light::YellowLight,
-                });
-            }
-        }
+    let (current_light, mut light_change_timer) = traffic_light_q.single_mut();
+    if let Some(light) =
+        light_change_timer.next_light(current_light.0, *signal_control, time.delta_seconds())
+    {
+        event_writer.send(LightChange { light });
     }
 }
 
 pub fn update(
     mut light_change_events: EventReader<LightChange>,
-    traffic_lights_entity_query: Query<Entity, With<TrafficLight>>,
     mut traffic_light_q: Query<(&mut CurrentLight, &mut LightChangeTimer), With<TrafficLight>>,
-    children: Query<&Children>,
-    mut child_query: Query<(&Name, &mut Visibility)>,
 ) {
-    let traffic_light_entity = traffic_lights_entity_query.single();
     let (mut current_light, mut light_change_timer) = traffic_light_q.single_mut();
     for new_light in light_change_events.read() {
-        current_light.0 = new_light.light;
-        light_change_timer.go.reset();
-        light_change_timer.yellow.reset();
-        light_change_timer.stop.reset();
-        for child_entity in children.iter_descendants(traffic_light_entity) {
-            if let Ok((entity_name, mut visible)) = child_query.get_mut(child_entity) {
-                let entity_name = entity_name.to_string();
-                if entity_name == Light::RedLight.to_string()
-                    || entity_name == Light::GreenLight.to_string()
-                    || entity_name == Light::YellowLight.to_string()
-                {
-                    if entity_name == new_light.light.to_string() {
-                        *visible = Visibility::Visible;
-                    } else {
-                        *visible = Visibility::Hidden;
-                    }
-                }
-            }
+        // The same light can be re-sent (e.g. by a fault recovering to red while red), that
+        // shouldn't restart its timer
+        if current_light.0 != new_light.light {
+            current_light.0 = new_light.light;
+            light_change_timer.restart();
         }
     }
 }
 
-pub fn on_scene_loaded(
+/**
+ * Lights the lamp of the current light in the traffic light's model, when the light changes or
+ * once the model has loaded
+ */
+pub fn show_lamps(
     mut ev_asset: EventReader<AssetEvent<Scene>>,
-    traffic_light_q: Query<(&HandleId, &CurrentLight), With<TrafficLight>>,
-    mut event_writer: EventWriter<LightChange>,
+    traffic_light_q: Query<(Entity, Ref<CurrentLight>, Option<&HandleId>), With<TrafficLight>>,
+    children: Query<&Children>,
+    mut child_query: Query<(&Name, &mut Visibility)>,
 ) {
-    for ev in ev_asset.read() {
-        match ev {
-            AssetEvent::Added { id, .. } => {
-                let (traffic_light_handle_id, current_light) = traffic_light_q.single();
-                if traffic_light_handle_id.0 == *id {
-                    event_writer.send(LightChange {
-                        light: current_light.0.clone(),
-                    });
+    let (traffic_light_entity, current_light, handle_id) = traffic_light_q.single();
+    let scene_loaded = ev_asset.read().any(|ev| {
+        matches!(ev, AssetEvent::Added { id } if handle_id.is_some_and(|handle_id| handle_id.0 == *id))
+    });
+    if !current_light.is_changed() && !scene_loaded {
+        return;
+    }
+    let lit_lamp = current_light.0.lamp().map(|lamp| lamp.to_string());
+    for child_entity in children.iter_descendants(traffic_light_entity) {
+        if let Ok((entity_name, mut visible)) = child_query.get_mut(child_entity) {
+            let entity_name = entity_name.to_string();
+            if LAMPS.iter().any(|lamp| entity_name == lamp.to_string()) {
+                if Some(&entity_name) == lit_lamp.as_ref() {
+                    *visible = Visibility::Visible;
+                } else {
+                    *visible = Visibility::Hidden;
                 }
             }
-            _ => {}
         }
     }
 }
 
+/**
+ * Blinks the lamp of a flashing light, it's only visual so the headless simulation doesn't run it
+ */
+pub fn flash(
+    traffic_light_q: Query<(Entity, &CurrentLight, &LightChangeTimer), With<TrafficLight>>,
+    children: Query<&Children>,
+    mut child_query: Query<(&Name, &mut Visibility)>,
+) {
+    let (traffic_light_entity, current_light, light_change_timer) = traffic_light_q.single();
+    // A dark light has no lamp to blink
+    let (true, Some(flashing_lamp)) = (current_light.0.is_fault(), current_light.0.lamp()) else {
+        return;
+    };
+    let flashing_lamp = flashing_lamp.to_string();
+    let lamp_on =
+        light_change_timer.time_in_light() % (2.0 * FLASH_HALF_PERIOD) < FLASH_HALF_PERIOD;
+    for child_entity in children.iter_descendants(traffic_light_entity) {
+        if let Ok((entity_name, mut visible)) = child_query.get_mut(child_entity) {
+            if entity_name.as_str() == flashing_lamp {
+                *visible = if lamp_on {
+                    Visibility::Visible
+                } else {
+                    Visibility::Hidden
+                };
+            }
+        }
+    }
+}
+
+pub fn apply_fault_schedule(
+    time: Res<Time>,
+    mut fault_schedule: ResMut<FaultSchedule>,
+    mut event_writer: EventWriter<LightChange>,
+) {
+    for light in fault_schedule.tick(time.delta_seconds()) {
+        event_writer.send(LightChange { light });
+    }
+}
+
 pub fn reset_simulation_listener(
     mut reset_simulation_event: EventReader<ResetSimluation>,
-    mut event_writer: EventWriter<LightChange>,
+    signal_plan: Res<SignalPlan>,
+    mut fault_schedule: ResMut<FaultSchedule>,
+    mut traffic_light_q: Query<(&mut CurrentLight, &mut LightChangeTimer), With<TrafficLight>>,
 ) {
     for _ in reset_simulation_event.read() {
-        event_writer.send(LightChange {
-            light: Light::RedLight,
-        });
+        let (mut current_light, mut light_change_timer) = traffic_light_q.single_mut();
+        let (light, timers) = signal_plan.initial_state();
+        current_light.0 = light;
+        *light_change_timer = timers;
+        fault_schedule.restart();
     }
 }
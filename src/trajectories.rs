@@ -0,0 +1,498 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::prelude::*;
+
+use crate::{
+    car_fleet::{
+        car::{Car, IsBreaking, Performance, Velocity},
+        CarFleetSet,
+    },
+    road::{OnLane, RoadLayout},
+};
+
+/**
+ * Records every car's trajectory once a `Trajectories` resource is inserted
+ */
+pub struct TrajectoryPlugin;
+
+impl Plugin for TrajectoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            record
+                .run_if(resource_exists::<Trajectories>)
+                .after(CarFleetSet::Movement)
+                .before(CarFleetSet::Despawn),
+        );
+    }
+}
+
+/**
+ * A car's ID in the trajectories, given in the order the cars appear (from 1, like NGSIM's) and
+ * unlike its `Entity` never reused
+ */
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VehicleId(pub u64);
+
+/**
+ * A car's state at the end of a tick. Distances are in the road's units, velocities and
+ * accelerations per second (not per tick like the cars').
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrajectoryPoint {
+    pub vehicle: VehicleId,
+    /**
+     * The tick, from 1
+     */
+    pub frame: u64,
+    /**
+     * Seconds since the start
+     */
+    pub time: f32,
+    pub x: f32,
+    pub y: f32,
+    pub lane: usize,
+    /**
+     * Along the lane
+     */
+    pub position: f32,
+    pub velocity: f32,
+    /**
+     * The change in velocity over the last tick, rather than what the driver asked for, so it's
+     * zero at the speed limit
+     */
+    pub acceleration: f32,
+    pub braking: bool,
+    /**
+     * The nearest car ahead on the same lane
+     */
+    pub leader: Option<VehicleId>,
+    /**
+     * The distance to the leader
+     */
+    pub space_headway: Option<f32>,
+}
+
+impl TrajectoryPoint {
+    /**
+     * The time it takes to cover the space headway at the current velocity, NGSIM's 9999.99 when
+     * stopped
+     */
+    pub fn time_headway(&self) -> Option<f32> {
+        self.space_headway.map(|headway| {
+            if self.velocity > 0.0 {
+                headway / self.velocity
+            } else {
+                9999.99
+            }
+        })
+    }
+}
+
+/**
+ * A car's whole trip, updated every tick. Distances are in the road's units and times in seconds
+ * since the start.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VehicleSummary {
+    pub vehicle: VehicleId,
+    pub first_seen: f32,
+    pub last_seen: f32,
+    /**
+     * Whether the car left the road at a sink, otherwise it was still on it at `last_seen`
+     */
+    pub reached_sink: bool,
+    pub first_lane: usize,
+    pub last_lane: usize,
+    pub distance: f32,
+    /**
+     * Like `SimulationMetrics`' but over the whole trip, not only up to the intersection
+     */
+    pub stops: u32,
+    pub delay: f32,
+}
+
+/**
+ * Every car's summary and the recorded points, every tick or every `sample_interval` seconds
+ */
+#[derive(Resource, Debug, Default)]
+pub struct Trajectories {
+    sample_interval: Option<f32>,
+    records_points: bool,
+    frame: u64,
+    /**
+     * Every car's velocity (per tick) at the end of the last tick
+     */
+    last_velocities: HashMap<VehicleId, f32>,
+    pub points: Vec<TrajectoryPoint>,
+    /**
+     * By vehicle, the first car's first
+     */
+    pub summaries: Vec<VehicleSummary>,
+}
+
+impl Trajectories {
+    pub fn new(sample_interval: Option<f32>) -> Self {
+        Trajectories {
+            sample_interval,
+            records_points: true,
+            ..default()
+        }
+    }
+
+    /**
+     * Gives the cars their IDs and summaries without recording any points
+     */
+    pub fn vehicles_only() -> Self {
+        Trajectories::default()
+    }
+
+    /**
+     * Writes the points as CSV with NGSIM's column names where there's one. `Preceding` is 0 and
+     * both headways are 0 without a leader, as in NGSIM.
+     */
+    pub fn write_csv(&self, path: &Path) -> io::Result<()> {
+        let mut csv = BufWriter::new(File::create(path)?);
+        writeln!(
+            csv,
+            "Vehicle_ID,Frame_ID,Global_Time,Local_X,Local_Y,Position,v_Vel,v_Acc,Braking,Lane_ID,Preceding,Space_Headway,Time_Headway"
+        )?;
+        for point in self.points.iter() {
+            writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                point.vehicle.0,
+                point.frame,
+                (point.time * 1000.0).round() as u64,
+                point.x,
+                point.y,
+                point.position,
+                point.velocity,
+                point.acceleration,
+                point.braking as u8,
+                point.lane,
+                point.leader.map_or(0, |leader| leader.0),
+                point.space_headway.unwrap_or(0.0),
+                point.time_headway().unwrap_or(0.0)
+            )?;
+        }
+        csv.flush()
+    }
+}
+
+pub fn record(
+    mut commands: Commands,
+    time: Res<Time>,
+    road_layout: Res<RoadLayout>,
+    mut trajectories: ResMut<Trajectories>,
+    car_q: Query<
+        (
+            Entity,
+            Option<&VehicleId>,
+            &Transform,
+            &Velocity,
+            &IsBreaking,
+            &OnLane,
+            &Performance,
+        ),
+        With<Car>,
+    >,
+) {
+    trajectories.frame += 1;
+    let now = time.elapsed_seconds();
+    let timestep = time.delta_seconds();
+    let mut new_cars = car_q
+        .iter()
+        .filter(|(_, vehicle, ..)| vehicle.is_none())
+        .map(|(entity, _, _, _, _, on_lane, _)| (entity, on_lane.0))
+        .collect::<Vec<(Entity, usize)>>();
+    // Sorted so the IDs don't depend on the query's order
+    new_cars.sort();
+    let mut new_ids = HashMap::new();
+    for (entity, lane) in new_cars {
+        let vehicle = VehicleId(trajectories.summaries.len() as u64 + 1);
+        trajectories.summaries.push(VehicleSummary {
+            vehicle,
+            first_seen: now,
+            last_seen: now,
+            reached_sink: false,
+            first_lane: lane,
+            last_lane: lane,
+            distance: 0.0,
+            stops: 0,
+            delay: 0.0,
+        });
+        commands.entity(entity).insert(vehicle);
+        new_ids.insert(entity, vehicle);
+    }
+
+    let cars = car_q
+        .iter()
+        .map(
+            |(entity, vehicle, transform, velocity, is_breaking, on_lane, performance)| {
+                let lane = &road_layout.lanes[on_lane.0];
+                (
+                    vehicle.copied().unwrap_or_else(|| new_ids[&entity]),
+                    transform.translation,
+                    on_lane.0,
+                    lane.position_of(transform.translation),
+                    velocity.0,
+                    is_breaking.0,
+                    performance.max_velocity,
+                )
+            },
+        )
+        .collect::<Vec<_>>();
+    let last_velocities = std::mem::replace(
+        &mut trajectories.last_velocities,
+        cars.iter()
+            .map(|(vehicle, _, _, _, velocity, _, _)| (*vehicle, *velocity))
+            .collect(),
+    );
+    // Cars are only despawned at the sinks
+    for vehicle in last_velocities.keys() {
+        if !trajectories.last_velocities.contains_key(vehicle) {
+            trajectories.summaries[vehicle.0 as usize - 1].reached_sink = true;
+        }
+    }
+    for (vehicle, _, lane, _, velocity, _, max_velocity) in cars.iter() {
+        let summary = &mut trajectories.summaries[vehicle.0 as usize - 1];
+        summary.last_seen = now;
+        summary.last_lane = *lane;
+        summary.distance += velocity;
+        summary.delay += timestep * (1.0 - velocity / max_velocity);
+        // Cars start standing still, that isn't counted as a stop
+        if *velocity == 0.0 && last_velocities.get(vehicle).is_some_and(|last| *last > 0.0) {
+            summary.stops += 1;
+        }
+    }
+
+    if !trajectories.records_points {
+        return;
+    }
+    let sample_every = trajectories
+        .sample_interval
+        .map_or(1, |interval| (interval / timestep).round().max(1.0) as u64);
+    if !trajectories.frame.is_multiple_of(sample_every) {
+        return;
+    }
+    for (vehicle, translation, lane_index, position, velocity, braking, _) in cars.iter() {
+        let lane = &road_layout.lanes[*lane_index];
+        let leader = cars
+            .iter()
+            .filter(|other| other.0 != *vehicle && other.2 == *lane_index)
+            .filter_map(|other| {
+                lane.distance_ahead(*position, other.3)
+                    .filter(|distance| *distance > 0.0)
+                    .map(|distance| (other.0, distance))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let last_velocity = last_velocities.get(vehicle).copied().unwrap_or(*velocity);
+        let point = TrajectoryPoint {
+            vehicle: *vehicle,
+            frame: trajectories.frame,
+            time: now,
+            x: translation.x,
+            y: translation.z,
+            lane: *lane_index,
+            position: *position,
+            velocity: velocity / timestep,
+            acceleration: (velocity - last_velocity) / timestep.powi(2),
+            braking: *braking,
+            leader: leader.map(|(leader, _)| leader),
+            space_headway: leader.map(|(_, distance)| distance),
+        };
+        trajectories.points.push(point);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        car_fleet::{
+            car::MAX_VELOCITY,
+            source::{ArrivalProcess, SourceDemand, TrafficDemand},
+        },
+        headless::HeadlessSimulation,
+        scenario::Scenario,
+    };
+
+    /**
+     * Cars arriving every 2s on the default road, they leave it after about 30s
+     */
+    fn recorded(trajectories: Trajectories, seconds: f32) -> Trajectories {
+        let scenario = Scenario {
+            demand: TrafficDemand {
+                sources: vec![SourceDemand {
+                    lane: 0,
+                    arrivals: ArrivalProcess::Uniform { headway: 2.0 },
+                    destinations: Vec::new(),
+                }],
+                ..default()
+            },
+            ..default()
+        };
+        let mut simulation = HeadlessSimulation::new(scenario.signal_plan, 0);
+        simulation.set_scenario(scenario);
+        simulation.record_trajectories(trajectories);
+        simulation.run_until(seconds);
+        let world = simulation.world_mut();
+        world.remove_resource::<Trajectories>().unwrap()
+    }
+
+    fn timestep() -> f32 {
+        Time::<Fixed>::default().timestep().as_secs_f32()
+    }
+
+    #[test]
+    fn vehicle_ids_stay_with_their_car() {
+        let trajectories = recorded(Trajectories::new(None), 90.0);
+        let summaries = &trajectories.summaries;
+        assert!(
+            summaries
+                .iter()
+                .filter(|summary| summary.reached_sink)
+                .count()
+                > 10
+        );
+        for (index, summary) in summaries.iter().enumerate() {
+            assert_eq!(summary.vehicle, VehicleId(index as u64 + 1));
+            let points = trajectories
+                .points
+                .iter()
+                .filter(|point| point.vehicle == summary.vehicle)
+                .collect::<Vec<_>>();
+            assert_eq!(points[0].time, summary.first_seen);
+            assert_eq!(points[points.len() - 1].time, summary.last_seen);
+            // A car despawned at the sink never comes back, not even as a newer car reusing its
+            // entity
+            for pair in points.windows(2) {
+                assert_eq!(pair[1].frame, pair[0].frame + 1);
+                assert!(pair[1].position >= pair[0].position);
+            }
+        }
+    }
+
+    #[test]
+    fn points_are_sampled_every_interval() {
+        let trajectories = recorded(Trajectories::new(Some(0.5)), 10.0);
+        let every = (0.5 / timestep()) as u64;
+        assert!(!trajectories.points.is_empty());
+        assert!(trajectories
+            .points
+            .iter()
+            .all(|point| point.frame % every == 0));
+        let frames = trajectories
+            .points
+            .iter()
+            .map(|point| point.frame)
+            .collect::<std::collections::BTreeSet<u64>>()
+            .into_iter()
+            .collect::<Vec<u64>>();
+        // From the first car's arrival, at 2s
+        assert_eq!(frames.len(), 17);
+        assert!(frames.windows(2).all(|pair| pair[1] - pair[0] == every));
+        // The summaries still follow every tick
+        let every_tick = recorded(Trajectories::new(None), 10.0);
+        assert_eq!(trajectories.summaries, every_tick.summaries);
+
+        assert!(recorded(Trajectories::vehicles_only(), 10.0)
+            .points
+            .is_empty());
+    }
+
+    #[test]
+    fn velocities_and_accelerations_are_per_second() {
+        let mut points = recorded(Trajectories::new(None), 30.0).points;
+        points.sort_by_key(|point| (point.vehicle.0, point.frame));
+        let timestep = timestep();
+        let max_velocity = MAX_VELOCITY / timestep;
+        let mut accelerating = 0;
+        for pair in points.windows(2) {
+            let (before, after) = (pair[0], pair[1]);
+            if before.vehicle != after.vehicle || before.lane != after.lane {
+                continue;
+            }
+            assert!(after.velocity <= max_velocity + 1e-3);
+            // The position moves by the velocity, and the velocity by the acceleration
+            assert!((after.position - before.position - after.velocity * timestep).abs() < 1e-3);
+            assert!(
+                (after.velocity - before.velocity - after.acceleration * timestep).abs() < 1e-3
+            );
+            if after.acceleration > 0.0 {
+                accelerating += 1;
+            }
+        }
+        assert!(accelerating > 0);
+    }
+
+    #[test]
+    fn points_know_the_car_ahead() {
+        let trajectories = recorded(Trajectories::new(None), 30.0);
+        let mut followers = 0;
+        for point in trajectories.points.iter() {
+            let same_frame = trajectories
+                .points
+                .iter()
+                .filter(|other| other.frame == point.frame && other.lane == point.lane);
+            let ahead = same_frame
+                .filter(|other| other.position > point.position)
+                .min_by(|a, b| a.position.total_cmp(&b.position));
+            match (point.leader, ahead) {
+                (None, None) => assert_eq!(point.time_headway(), None),
+                (Some(leader), Some(ahead)) => {
+                    followers += 1;
+                    assert_eq!(leader, ahead.vehicle);
+                    let headway = point.space_headway.unwrap();
+                    assert!((headway - (ahead.position - point.position)).abs() < 1e-4);
+                    let time_headway = point.time_headway().unwrap();
+                    if point.velocity > 0.0 {
+                        assert!((time_headway - headway / point.velocity).abs() < 1e-3);
+                    } else {
+                        assert_eq!(time_headway, 9999.99);
+                    }
+                }
+                (leader, ahead) => panic!("leader {:?} but {:?} ahead", leader, ahead),
+            }
+        }
+        assert!(followers > 0);
+    }
+
+    #[test]
+    fn csv_has_ngsims_columns() {
+        let trajectories = recorded(Trajectories::new(Some(1.0)), 3.0);
+        let path = std::env::temp_dir().join(format!("trajectories-{}.csv", std::process::id()));
+        trajectories.write_csv(&path).unwrap();
+        let csv = fs::read_to_string(&path);
+        fs::remove_file(&path).unwrap();
+        let csv = csv.unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some(
+                "Vehicle_ID,Frame_ID,Global_Time,Local_X,Local_Y,Position,v_Vel,v_Acc,Braking,\
+                Lane_ID,Preceding,Space_Headway,Time_Headway"
+            )
+        );
+        assert_eq!(lines.clone().count(), trajectories.points.len());
+        let first = trajectories.points[0];
+        let cells = lines.next().unwrap().split(',').collect::<Vec<&str>>();
+        assert_eq!(cells.len(), 13);
+        assert_eq!(cells[0], first.vehicle.0.to_string());
+        assert_eq!(cells[1], first.frame.to_string());
+        // Global_Time is in milliseconds, the first car arrives after 2s
+        assert_eq!(cells[2], "2000");
+        assert_eq!(cells[9], first.lane.to_string());
+        if first.leader.is_none() {
+            assert_eq!(&cells[10..], ["0", "0", "0"]);
+        }
+    }
+}
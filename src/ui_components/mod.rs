@@ -1,9 +1,73 @@
 pub mod buttons_hover_effect;
 pub mod reaction_timer_controls;
 pub mod reset_simulation_button;
+pub mod sim_clock_controls;
+pub mod timeline;
 
 use bevy::prelude::*;
 
+use crate::{
+    camera,
+    rewind::RewindPlugin,
+    sim_clock::{self, SimClockPlugin},
+    ui_components::{
+        reaction_timer_controls::ReactionTimeChanged, reset_simulation_button::ResetSimluation,
+    },
+};
+
+/**
+ * The controls panel (reset, reaction time and the sim clock, which can also be driven from the
+ * keyboard), when `camera` is set the orbiting camera and when `timeline` is set the timeline to
+ * rewind the simulation on
+ */
+pub struct SimUiPlugin {
+    pub camera: bool,
+    pub timeline: bool,
+}
+
+impl Default for SimUiPlugin {
+    fn default() -> Self {
+        SimUiPlugin {
+            camera: true,
+            timeline: true,
+        }
+    }
+}
+
+impl Plugin for SimUiPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<SimClockPlugin>() {
+            app.add_plugins(SimClockPlugin);
+        }
+        app.add_event::<ResetSimluation>()
+            .add_event::<ReactionTimeChanged>()
+            .add_systems(Startup, setup)
+            .add_systems(
+                Update,
+                (
+                    reset_simulation_button::update,
+                    reaction_timer_controls::buttons_listenerr,
+                    reaction_timer_controls::update_reaction_time_text,
+                    sim_clock_controls::buttons_listener,
+                    sim_clock_controls::update_text,
+                    sim_clock::keyboard_controls,
+                    buttons_hover_effect::update,
+                ),
+            );
+        if self.camera {
+            app.add_systems(Startup, camera::setup)
+                .add_systems(Update, (camera::update, camera::reset_simulation_listener));
+        }
+        if self.timeline {
+            if !app.is_plugin_added::<RewindPlugin>() {
+                app.add_plugins(RewindPlugin);
+            }
+            app.add_systems(Startup, timeline::setup)
+                .add_systems(Update, (timeline::scrub, timeline::update));
+        }
+    }
+}
+
 /**
  * There's a single `setup` for the UI components so they'll be in the same container
  */
@@ -34,6 +98,7 @@ pub fn setup(mut commands: Commands) {
                 .with_children(|parent| {
                     reset_simulation_button::setup(parent);
                     reaction_timer_controls::setup(parent);
+                    sim_clock_controls::setup(parent);
                 });
         });
 }
@@ -109,15 +109,12 @@ pub fn buttons_listenerr(
     mut event_writer: EventWriter<ReactionTimeChanged>,
 ) {
     for (interaction, increase_button, decrease_button) in &mut interaction_query {
-        match *interaction {
-            Interaction::Pressed => {
-                if increase_button.is_some() {
-                    event_writer.send(ReactionTimeChanged { delta: 0.1 });
-                } else if decrease_button.is_some() {
-                    event_writer.send(ReactionTimeChanged { delta: -0.1 });
-                }
+        if *interaction == Interaction::Pressed {
+            if increase_button.is_some() {
+                event_writer.send(ReactionTimeChanged { delta: 0.1 });
+            } else if decrease_button.is_some() {
+                event_writer.send(ReactionTimeChanged { delta: -0.1 });
             }
-            _ => {}
         }
     }
 }
@@ -130,8 +127,11 @@ pub fn update_reaction_time_text(
     car_reaction_time_q: Query<(&Car, &ReactionTimer)>,
     mut text_q: Query<&mut Text, With<ReactionTimeValueText>>,
 ) {
-    let (_, reaction_time) = car_reaction_time_q.iter().next().unwrap();
+    // With sources there are no cars until the first one arrives
+    let Some((_, reaction_time)) = car_reaction_time_q.iter().next() else {
+        return;
+    };
     let mut text = text_q.single_mut();
-    let reaction_time_in_seconds = reaction_time.0.duration().as_secs_f32();
+    let reaction_time_in_seconds = reaction_time.0.duration();
     text.sections[0].value = format!("Reaction Time: {:.1}s", reaction_time_in_seconds);
 }
@@ -47,11 +47,8 @@ pub fn update(
     mut event_writer: EventWriter<ResetSimluation>,
 ) {
     for (interaction, _, _) in &mut interaction_query {
-        match *interaction {
-            Interaction::Pressed => {
-                event_writer.send(ResetSimluation);
-            },
-            _ => {},
+        if *interaction == Interaction::Pressed {
+            event_writer.send(ResetSimluation);
         }
     }
 }
@@ -0,0 +1,97 @@
+use bevy::prelude::*;
+
+use crate::sim_clock::SimClock;
+
+#[derive(Component, Clone, Copy)]
+pub enum SimClockButton {
+    Slower,
+    TogglePause,
+    Step,
+    Faster,
+}
+
+#[derive(Component)]
+pub struct SimClockText;
+
+pub fn setup(parent: &mut ChildBuilder) {
+    parent
+        .spawn(TextBundle::from("").with_style(Style {
+            top: Val::Percent(10.0),
+            ..default()
+        }))
+        .insert(SimClockText);
+    parent
+        .spawn(NodeBundle {
+            style: Style {
+                border: UiRect::all(Val::Px(5.0)),
+                top: Val::Percent(12.0),
+                width: Val::Percent(100.0),
+                flex_direction: FlexDirection::Row,
+                justify_content: JustifyContent::SpaceAround,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            for (button, label) in [
+                (SimClockButton::Slower, "<<"),
+                (SimClockButton::TogglePause, "||"),
+                (SimClockButton::Step, ">|"),
+                (SimClockButton::Faster, ">>"),
+            ] {
+                parent
+                    .spawn(ButtonBundle {
+                        style: Style {
+                            width: Val::Px(45.0),
+                            height: Val::Px(45.0),
+                            border: UiRect::all(Val::Px(5.0)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        border_color: BorderColor(Color::BLACK),
+                        border_radius: BorderRadius::MAX,
+                        ..default()
+                    })
+                    .insert(button)
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            label,
+                            TextStyle {
+                                font_size: 24.0,
+                                color: Color::srgb(0.9, 0.9, 0.9),
+                                ..default()
+                            },
+                        ));
+                    });
+            }
+        });
+}
+
+pub fn buttons_listener(
+    interaction_query: Query<(&Interaction, &SimClockButton), (Changed<Interaction>, With<Button>)>,
+    mut sim_clock: ResMut<SimClock>,
+) {
+    for (interaction, button) in interaction_query.iter() {
+        if *interaction == Interaction::Pressed {
+            match button {
+                SimClockButton::Slower => sim_clock.slower(),
+                SimClockButton::TogglePause => sim_clock.toggle_pause(),
+                SimClockButton::Step => sim_clock.step(),
+                SimClockButton::Faster => sim_clock.faster(),
+            }
+        }
+    }
+}
+
+pub fn update_text(sim_clock: Res<SimClock>, mut text_q: Query<&mut Text, With<SimClockText>>) {
+    if !sim_clock.is_changed() {
+        return;
+    }
+    let mut text = text_q.single_mut();
+    text.sections[0].value = if sim_clock.is_paused() {
+        format!("Paused ({}x)", sim_clock.time_scale())
+    } else {
+        format!("Speed: {}x", sim_clock.time_scale())
+    };
+}
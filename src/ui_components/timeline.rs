@@ -0,0 +1,120 @@
+use bevy::{prelude::*, ui::RelativeCursorPosition};
+
+use crate::rewind::{self, SnapshotHistory};
+
+#[derive(Component)]
+pub struct TimelineTrack;
+
+#[derive(Component)]
+pub struct TimelineHandle;
+
+#[derive(Component)]
+pub struct TimelineText;
+
+/**
+ * A bar along the bottom of the window spanning the states kept, with a handle at the one shown
+ */
+pub fn setup(mut commands: Commands) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Percent(5.0),
+                bottom: Val::Px(20.0),
+                width: Val::Percent(70.0),
+                flex_direction: FlexDirection::Column,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from("")).insert(TimelineText);
+            parent
+                .spawn(ButtonBundle {
+                    style: Style {
+                        width: Val::Percent(100.0),
+                        height: Val::Px(20.0),
+                        border: UiRect::all(Val::Px(3.0)),
+                        ..default()
+                    },
+                    border_color: BorderColor(Color::BLACK),
+                    border_radius: BorderRadius::MAX,
+                    ..default()
+                })
+                .insert((TimelineTrack, RelativeCursorPosition::default()))
+                .with_children(|parent| {
+                    parent
+                        .spawn(NodeBundle {
+                            style: Style {
+                                position_type: PositionType::Absolute,
+                                left: Val::Percent(100.0),
+                                // Centered on where it points
+                                margin: UiRect::left(Val::Px(-4.0)),
+                                width: Val::Px(8.0),
+                                height: Val::Percent(100.0),
+                                ..default()
+                            },
+                            background_color: Color::srgb(0.9, 0.9, 0.9).into(),
+                            ..default()
+                        })
+                        .insert(TimelineHandle);
+                });
+        });
+}
+
+/**
+ * Pressing or dragging on the timeline rewinds to the state under the cursor
+ */
+pub fn scrub(
+    mut commands: Commands,
+    track_q: Query<(&Interaction, &RelativeCursorPosition), With<TimelineTrack>>,
+    history: Res<SnapshotHistory>,
+) {
+    let (interaction, cursor) = track_q.single();
+    if *interaction != Interaction::Pressed || history.is_empty() {
+        return;
+    }
+    let Some(cursor) = cursor.normalized else {
+        return;
+    };
+    let index = (cursor.x.clamp(0.0, 1.0) * (history.len() - 1) as f32).round() as usize;
+    if history.shown() != Some(index) {
+        commands.add(move |world: &mut World| rewind::rewind(world, index));
+    }
+}
+
+pub fn update(
+    history: Res<SnapshotHistory>,
+    mut handle_q: Query<&mut Style, With<TimelineHandle>>,
+    mut text_q: Query<&mut Text, With<TimelineText>>,
+) {
+    if !history.is_changed() {
+        return;
+    }
+    let mut text = text_q.single_mut();
+    let mut handle = handle_q.single_mut();
+    if history.is_empty() {
+        text.sections[0].value = String::new();
+        handle.left = Val::Percent(100.0);
+        return;
+    }
+    let last = history.len() - 1;
+    let (first_time, last_time) = (history.time_at(0), history.time_at(last));
+    text.sections[0].value = match history.shown() {
+        Some(shown) => format!(
+            "Rewound to {:.1}s ({:.1}s - {:.1}s kept), resume to carry on from here",
+            history.time_at(shown),
+            first_time,
+            last_time
+        ),
+        None => format!(
+            "{:.1}s ({:.1}s - {:.1}s kept)",
+            last_time, first_time, last_time
+        ),
+    };
+    let fraction = match (history.shown(), last) {
+        (_, 0) | (None, _) => 1.0,
+        (Some(shown), _) => shown as f32 / last as f32,
+    };
+    handle.left = Val::Percent(100.0 * fraction);
+}
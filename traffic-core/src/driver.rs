@@ -0,0 +1,836 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    road::{IntersectionControl, Lane, Priority, RoadLayout},
+    signal::Light,
+    timer::Timer,
+};
+
+/**
+ * How fast the car can go and change speed, all per tick
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::component::Component))]
+pub struct Performance {
+    pub max_velocity: f32,
+    pub acceleration: f32,
+    pub deceleration: f32,
+}
+
+/**
+ * The max velocity of the default vehicle class, what free-flowing traffic drives at
+ */
+pub const MAX_VELOCITY: f32 = 0.1;
+
+/**
+ * How drivers behave around each other and at the intersection, distances are along the lanes
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::system::Resource))]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelParameters {
+    /**
+     * The distance a car should leave before itself and the obstacle (other car/stoplight) in
+     * front of it. The car's position is its center, so this is half its length plus some offset.
+     */
+    #[serde(deserialize_with = "crate::validate::positive")]
+    pub break_distance: f32,
+    /**
+     * How far from the stop line a standing car still counts as stopped at it
+     */
+    #[serde(deserialize_with = "crate::validate::non_negative")]
+    pub stop_line_tolerance: f32,
+    /**
+     * The intersection's length past the light, a car that has to stop or yield only goes once no
+     * other car is between its stop line and this far past the light
+     */
+    #[serde(deserialize_with = "crate::validate::non_negative")]
+    pub intersection_length: f32,
+    /**
+     * When the light is flashing yellow or the car has to yield, it slows down to
+     * `caution_velocity` (a fraction of its max velocity) this close to the light
+     */
+    #[serde(deserialize_with = "crate::validate::non_negative")]
+    pub caution_distance: f32,
+    #[serde(deserialize_with = "crate::validate::fraction")]
+    pub caution_velocity: f32,
+    /**
+     * The smallest gap (in seconds until the next major road car reaches the intersection) a car
+     * that stops or yields accepts to cross in
+     */
+    #[serde(deserialize_with = "crate::validate::non_negative")]
+    pub critical_gap: f32,
+}
+
+impl Default for ModelParameters {
+    fn default() -> Self {
+        ModelParameters {
+            break_distance: 6.0,
+            stop_line_tolerance: 1.0,
+            intersection_length: 4.0,
+            caution_distance: 20.0,
+            caution_velocity: 0.5,
+            critical_gap: 4.0,
+        }
+    }
+}
+
+/**
+ * What a car knows about the others, collected before any of them moves this tick.
+ * `position` is along the car's lane.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CarSnapshot {
+    pub lane: usize,
+    pub position: f32,
+    pub velocity: f32,
+    pub stopped_at_line: Option<f32>,
+}
+
+/**
+ * The junction the lanes go through, where each lane meets it is the lane's `junction`
+ */
+pub struct Intersection<'a> {
+    pub control: IntersectionControl,
+    /**
+     * The light of the major lanes, `minor_light` is the minor lanes' one (see
+     * `LightChangeTimer::minor_light`)
+     */
+    pub light: Light,
+    pub minor_light: Light,
+    pub layout: &'a RoadLayout,
+    pub model: &'a ModelParameters,
+}
+
+impl Intersection<'_> {
+    /**
+     * Whether a car at `position` of `lane` is past its stop line for `point` and not yet
+     * `intersection_length` past it
+     */
+    fn is_inside(&self, lane: &Lane, position: f32, point: f32) -> bool {
+        lane.distance_ahead(position, point)
+            .is_some_and(|distance| distance < self.model.break_distance)
+            || lane
+                .distance_ahead(point, position)
+                .is_some_and(|distance| distance <= self.model.intersection_length)
+    }
+
+    pub fn position_on(&self, lane: usize) -> Option<f32> {
+        self.layout.lanes[lane].junction
+    }
+
+    pub fn light_on(&self, priority: Priority) -> Light {
+        match priority {
+            Priority::Minor => self.minor_light,
+            Priority::Major | Priority::Yield => self.light,
+        }
+    }
+
+    /**
+     * No car is inside the intersection, on `car`'s lane or a lane it conflicts with
+     */
+    fn is_clear(&self, car: &CarSnapshot, cars: &[CarSnapshot]) -> bool {
+        let lane = &self.layout.lanes[car.lane];
+        !cars.iter().any(|other_car| {
+            let other_lane = &self.layout.lanes[other_car.lane];
+            let point = if other_car.lane == car.lane {
+                lane.junction
+            } else {
+                lane.conflicts
+                    .iter()
+                    .find(|conflict| conflict.lane == other_car.lane)
+                    .map(|conflict| conflict.position)
+            };
+            point.is_some_and(|point| self.is_inside(other_lane, other_car.position, point))
+        })
+    }
+
+    /**
+     * Every car approaching the conflict points of `car`'s lane on a major lane is at least
+     * `critical_gap` away
+     */
+    fn has_gap(&self, car: &CarSnapshot, cars: &[CarSnapshot], timestep: f32) -> bool {
+        self.layout.lanes[car.lane]
+            .conflicts
+            .iter()
+            .filter(|conflict| self.layout.lanes[conflict.lane].priority == Priority::Major)
+            .all(|conflict| {
+                let conflict_lane = &self.layout.lanes[conflict.lane];
+                cars.iter()
+                    .filter(|other_car| other_car.lane == conflict.lane)
+                    .all(|other_car| {
+                        // Velocities are per tick, so this is in ticks until it gets to the point
+                        match conflict_lane.distance_ahead(other_car.position, conflict.position) {
+                            None => true,
+                            Some(distance) => {
+                                other_car.velocity == 0.0
+                                    || distance / other_car.velocity * timestep
+                                        >= self.model.critical_gap
+                            }
+                        }
+                    })
+            })
+    }
+
+    /**
+     * Whether a car of a conflicting lane stopped at its line before `car` (ties go to the lower
+     * lane)
+     */
+    fn has_earlier_arrival(&self, car: &CarSnapshot, cars: &[CarSnapshot]) -> bool {
+        let Some(stopped_at) = car.stopped_at_line else {
+            return false;
+        };
+        let conflicts = &self.layout.lanes[car.lane].conflicts;
+        cars.iter()
+            .filter(|other_car| {
+                conflicts
+                    .iter()
+                    .any(|conflict| conflict.lane == other_car.lane)
+            })
+            .any(|other_car| match other_car.stopped_at_line {
+                Some(other_stopped_at) => {
+                    other_stopped_at < stopped_at
+                        || (other_stopped_at == stopped_at && other_car.lane < car.lane)
+                }
+                None => false,
+            })
+    }
+}
+
+/**
+ * What the intersection asks of the cars on one of its lanes
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApproachRule {
+    Go,
+    /**
+     * Go, but slowly
+     */
+    Caution,
+    Red,
+    /**
+     * Stop at the line, then go in the order the cars stopped
+     */
+    AllWayStop,
+    /**
+     * Stop at the line, then go when there's a gap in the major lanes' traffic
+     */
+    Stop,
+    /**
+     * Only stop at the line when there's no gap in the major lanes' traffic
+     */
+    Yield,
+}
+
+/**
+ * The rule for a lane of `priority`, `light` is the one that lane sees
+ */
+pub fn approach_rule(
+    control: IntersectionControl,
+    light: Light,
+    priority: Priority,
+) -> ApproachRule {
+    match (control, priority) {
+        (_, Priority::Yield) => ApproachRule::Yield,
+        (IntersectionControl::Signal, _) => match (light, priority) {
+            (Light::Dark | Light::FlashingRed, _) => ApproachRule::AllWayStop,
+            // A major road flashing yellow means the minor road is flashing red
+            (Light::FlashingYellow, Priority::Major) => ApproachRule::Caution,
+            (Light::FlashingYellow, Priority::Minor) => ApproachRule::Stop,
+            (Light::RedLight, _) => ApproachRule::Red,
+            _ => ApproachRule::Go,
+        },
+        (IntersectionControl::AllWayStop, _) => ApproachRule::AllWayStop,
+        (IntersectionControl::TwoWayStop | IntersectionControl::Yield, Priority::Major) => {
+            ApproachRule::Go
+        }
+        (IntersectionControl::TwoWayStop, Priority::Minor) => ApproachRule::Stop,
+        (IntersectionControl::Yield, Priority::Minor) => ApproachRule::Yield,
+    }
+}
+
+/**
+ * Everything the driver model keeps about a car between ticks, `position` is along `lane`
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarState {
+    pub lane: usize,
+    pub position: f32,
+    pub velocity: f32,
+    pub acceleration: f32,
+    pub reaction_timer: Timer,
+    pub is_breaking: bool,
+    pub stopped_at_line: Option<f32>,
+    /**
+     * The lanes the car still has to drive through, in order
+     */
+    pub route: VecDeque<usize>,
+    pub performance: Performance,
+}
+
+impl CarState {
+    /**
+     * A car standing at `position` of `lane`, that takes `reaction_time` seconds to start
+     * accelerating once it stops breaking
+     */
+    pub fn new(
+        lane: usize,
+        position: f32,
+        route: VecDeque<usize>,
+        performance: Performance,
+        reaction_time: f32,
+    ) -> Self {
+        CarState {
+            lane,
+            position,
+            velocity: 0.0,
+            acceleration: 0.0,
+            reaction_timer: Timer::from_seconds(reaction_time),
+            is_breaking: false,
+            stopped_at_line: None,
+            route,
+            performance,
+        }
+    }
+
+    pub fn snapshot(&self) -> CarSnapshot {
+        CarSnapshot {
+            lane: self.lane,
+            position: self.position,
+            velocity: self.velocity,
+            stopped_at_line: self.stopped_at_line,
+        }
+    }
+
+    /**
+     * Moves the car by one tick of `delta` seconds, `now` seconds into the simulation.
+     * `cars` are all the cars as they were before any of them moved this tick.
+     */
+    pub fn step(
+        &mut self,
+        cars: &[CarSnapshot],
+        intersection: &Intersection,
+        now: f32,
+        delta: f32,
+    ) {
+        let model = intersection.model;
+        let performance = self.performance;
+        let lane = &intersection.layout.lanes[self.lane];
+        let position = self.position;
+        let intersection_position = intersection.position_on(self.lane);
+        match intersection_position {
+            Some(intersection_position) if position <= intersection_position => {
+                if self.stopped_at_line.is_none()
+                    && self.velocity == 0.0
+                    && position + model.break_distance + model.stop_line_tolerance
+                        >= intersection_position
+                {
+                    self.stopped_at_line = Some(now);
+                }
+            }
+            _ => self.stopped_at_line = None,
+        }
+        let approach_rule = approach_rule(
+            intersection.control,
+            intersection.light_on(lane.priority),
+            lane.priority,
+        );
+        let should_break = should_break(
+            &self.snapshot(),
+            cars,
+            &performance,
+            intersection,
+            approach_rule,
+            delta,
+        );
+        if should_break {
+            if !self.is_breaking {
+                self.is_breaking = true;
+                self.acceleration = -performance.deceleration
+            }
+        } else {
+            if self.is_breaking {
+                self.is_breaking = false;
+                self.reaction_timer.reset();
+            }
+            self.reaction_timer.tick(delta);
+            if self.reaction_timer.finished() {
+                self.acceleration = performance.acceleration;
+            }
+        }
+
+        let in_caution_zone = matches!(approach_rule, ApproachRule::Caution | ApproachRule::Yield)
+            && intersection_position.is_some_and(|intersection_position| {
+                position <= intersection_position
+                    && position + model.caution_distance >= intersection_position
+            });
+        let max_velocity = if in_caution_zone {
+            (self.velocity - performance.deceleration)
+                .max(performance.max_velocity * model.caution_velocity)
+        } else {
+            performance.max_velocity
+        };
+        let new_velocity = self.velocity + self.acceleration;
+        if new_velocity > max_velocity {
+            self.velocity = max_velocity;
+        } else if new_velocity < 0.0 {
+            self.velocity = 0.0;
+        } else {
+            self.velocity = new_velocity;
+        }
+
+        // Move on to the next lane of the route when the connection to it is reached this tick
+        let connection = lane.connections.iter().find_map(|connection| {
+            let distance = lane.distance_ahead(position, connection.at)?;
+            (self.route.front() == Some(&connection.lane) && distance < self.velocity)
+                .then_some((connection, distance))
+        });
+        match connection {
+            Some((connection, distance)) => {
+                let overshoot = self.velocity - distance;
+                self.route.pop_front();
+                self.lane = connection.lane;
+                self.stopped_at_line = None;
+                self.position = connection.position + overshoot;
+            }
+            None => self.position = position + self.velocity,
+        }
+    }
+}
+
+pub fn calculate_stopping_distance(current_velocity: f32, deceleration: f32) -> f32 {
+    // I'm adding  0.1 to the stopping distance to avoid it being 0
+    0.1 + current_velocity.powi(2) / (2.0 * deceleration)
+}
+
+pub fn get_car_infront_distance(
+    cars: &[CarSnapshot],
+    lane_index: usize,
+    lane: &Lane,
+    current_car_position: f32,
+) -> Option<f32> {
+    cars.iter()
+        .filter(|other_car| other_car.lane == lane_index)
+        .filter_map(|other_car| lane.distance_ahead(current_car_position, other_car.position))
+        .filter(|distance| *distance > 0.0)
+        .min_by(|a, b| a.partial_cmp(b).unwrap())
+}
+
+pub fn should_break(
+    car: &CarSnapshot,
+    other_cars: &[CarSnapshot],
+    performance: &Performance,
+    intersection: &Intersection,
+    approach_rule: ApproachRule,
+    timestep: f32,
+) -> bool {
+    let break_distance = intersection.model.break_distance;
+    let position = car.position;
+    let minimum_distance_to_stop =
+        calculate_stopping_distance(car.velocity, performance.deceleration);
+    let lane = &intersection.layout.lanes[car.lane];
+    let car_infront_distance = get_car_infront_distance(other_cars, car.lane, lane, position);
+    if let Some(car_infront_distance) = car_infront_distance {
+        if minimum_distance_to_stop + break_distance >= car_infront_distance {
+            return true;
+        }
+    }
+    let Some(intersection_position) = intersection.position_on(car.lane) else {
+        return false;
+    };
+    let before_traffic_light: bool = position + break_distance <= intersection_position;
+    if !before_traffic_light {
+        return false;
+    }
+    let reaches_stop_line =
+        position + minimum_distance_to_stop + break_distance >= intersection_position;
+    match approach_rule {
+        ApproachRule::Go | ApproachRule::Caution => false,
+        ApproachRule::Red => reaches_stop_line,
+        ApproachRule::AllWayStop => {
+            let may_go = car.stopped_at_line.is_some()
+                && intersection.is_clear(car, other_cars)
+                && !intersection.has_earlier_arrival(car, other_cars);
+            !may_go && reaches_stop_line
+        }
+        ApproachRule::Stop => {
+            let may_go = car.stopped_at_line.is_some()
+                && intersection.is_clear(car, other_cars)
+                && intersection.has_gap(car, other_cars, timestep);
+            !may_go && reaches_stop_line
+        }
+        ApproachRule::Yield => {
+            let may_go = intersection.is_clear(car, other_cars)
+                && intersection.has_gap(car, other_cars, timestep);
+            !may_go && reaches_stop_line
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTEP: f32 = 1.0 / 64.0;
+    const PERFORMANCE: Performance = Performance {
+        max_velocity: MAX_VELOCITY,
+        acceleration: 0.003,
+        deceleration: 0.01,
+    };
+
+    fn snapshot(lane: usize, position: f32, velocity: f32) -> CarSnapshot {
+        CarSnapshot {
+            lane,
+            position,
+            velocity,
+            stopped_at_line: None,
+        }
+    }
+
+    fn intersection<'a>(
+        layout: &'a RoadLayout,
+        model: &'a ModelParameters,
+        light: Light,
+    ) -> Intersection<'a> {
+        Intersection {
+            control: IntersectionControl::Signal,
+            light,
+            minor_light: Light::RedLight,
+            layout,
+            model,
+        }
+    }
+
+    /**
+     * Steps `car` alone through `ticks` ticks and returns where it was after each of them
+     */
+    fn drive(car: &mut CarState, intersection: &Intersection, ticks: usize) -> Vec<f32> {
+        (1..=ticks)
+            .map(|tick| {
+                let cars = [car.snapshot()];
+                car.step(&cars, intersection, tick as f32 * TIMESTEP, TIMESTEP);
+                car.position
+            })
+            .collect()
+    }
+
+    #[test]
+    fn breaks_for_the_red_light_once_it_reaches_the_stop_line() {
+        let layout = RoadLayout::default();
+        let model = ModelParameters::default();
+        let intersection = intersection(&layout, &model, Light::RedLight);
+        // It needs 0.6 to stop from full speed, and stops `break_distance` (6) before the light
+        let breaks_at = |position: f32, rule: ApproachRule| {
+            let car = snapshot(0, position, MAX_VELOCITY);
+            should_break(&car, &[car], &PERFORMANCE, &intersection, rule, TIMESTEP)
+        };
+        assert!(!breaks_at(-7.0, ApproachRule::Red));
+        assert!(breaks_at(-6.5, ApproachRule::Red));
+        assert!(!breaks_at(-6.5, ApproachRule::Go));
+        // Too close to stop at the line, it drives through
+        assert!(!breaks_at(-5.0, ApproachRule::Red));
+    }
+
+    #[test]
+    fn breaks_for_the_car_in_front() {
+        let layout = RoadLayout::default();
+        let model = ModelParameters::default();
+        let intersection = intersection(&layout, &model, Light::GreenLight);
+        let car_in_front = snapshot(0, -50.0, 0.0);
+        let breaks_at = |position: f32| {
+            let car = snapshot(0, position, MAX_VELOCITY);
+            should_break(
+                &car,
+                &[car, car_in_front],
+                &PERFORMANCE,
+                &intersection,
+                ApproachRule::Go,
+                TIMESTEP,
+            )
+        };
+        assert!(!breaks_at(-57.0));
+        assert!(breaks_at(-56.5));
+        // Cars behind it don't matter
+        assert!(!breaks_at(-40.0));
+    }
+
+    #[test]
+    fn starts_after_its_reaction_time() {
+        let layout = RoadLayout::default();
+        let model = ModelParameters::default();
+        let intersection = intersection(&layout, &model, Light::GreenLight);
+        // 0.4s is 25.6 ticks
+        let mut car = CarState::new(0, -50.0, VecDeque::new(), PERFORMANCE, 0.4);
+        drive(&mut car, &intersection, 25);
+        assert_eq!(car.velocity, 0.0);
+        drive(&mut car, &intersection, 1);
+        assert_eq!(car.velocity, PERFORMANCE.acceleration);
+        drive(&mut car, &intersection, 100);
+        assert_eq!(car.velocity, MAX_VELOCITY);
+    }
+
+    #[test]
+    fn stops_at_the_red_light() {
+        let layout = RoadLayout::default();
+        let model = ModelParameters::default();
+        let intersection = intersection(&layout, &model, Light::RedLight);
+        let mut car = CarState::new(0, -60.0, VecDeque::new(), PERFORMANCE, 0.4);
+        let positions = drive(&mut car, &intersection, 64 * 30);
+        assert!(positions.iter().all(|position| *position < 0.0));
+        assert_eq!(car.velocity, 0.0);
+        assert!(car.position + model.break_distance + model.stop_line_tolerance >= 0.0);
+        assert!(car.stopped_at_line.is_some());
+    }
+
+    /**
+     * A car on the crossroads' minor lane (1) stopped just before the line, at `stopped_at`
+     */
+    fn stopped_on_the_minor_lane(stopped_at: Option<f32>) -> CarSnapshot {
+        CarSnapshot {
+            stopped_at_line: stopped_at,
+            ..snapshot(1, -6.05, 0.0)
+        }
+    }
+
+    #[test]
+    fn lanes_follow_the_intersections_control() {
+        use ApproachRule::*;
+        let rule = approach_rule;
+        for light in [Light::GreenLight, Light::YellowLight] {
+            assert_eq!(
+                rule(IntersectionControl::Signal, light, Priority::Major),
+                Go
+            );
+            assert_eq!(
+                rule(IntersectionControl::Signal, light, Priority::Minor),
+                Go
+            );
+        }
+        assert_eq!(
+            rule(
+                IntersectionControl::Signal,
+                Light::RedLight,
+                Priority::Minor
+            ),
+            Red
+        );
+        assert_eq!(
+            rule(IntersectionControl::Signal, Light::Dark, Priority::Major),
+            AllWayStop
+        );
+        assert_eq!(
+            rule(
+                IntersectionControl::Signal,
+                Light::FlashingYellow,
+                Priority::Major
+            ),
+            Caution
+        );
+        assert_eq!(
+            rule(
+                IntersectionControl::Signal,
+                Light::FlashingYellow,
+                Priority::Minor
+            ),
+            Stop
+        );
+        // The light doesn't matter without a signal
+        for light in [Light::GreenLight, Light::RedLight] {
+            assert_eq!(
+                rule(IntersectionControl::TwoWayStop, light, Priority::Major),
+                Go
+            );
+            assert_eq!(
+                rule(IntersectionControl::TwoWayStop, light, Priority::Minor),
+                Stop
+            );
+            assert_eq!(
+                rule(IntersectionControl::AllWayStop, light, Priority::Major),
+                AllWayStop
+            );
+            assert_eq!(
+                rule(IntersectionControl::Yield, light, Priority::Minor),
+                Yield
+            );
+            assert_eq!(
+                rule(IntersectionControl::Signal, light, Priority::Yield),
+                Yield
+            );
+        }
+    }
+
+    #[test]
+    fn minor_lanes_see_the_minor_light() {
+        let layout = RoadLayout::crossroads();
+        let model = ModelParameters::default();
+        let intersection = Intersection {
+            minor_light: Light::GreenLight,
+            ..intersection(&layout, &model, Light::RedLight)
+        };
+        assert_eq!(intersection.light_on(Priority::Major), Light::RedLight);
+        assert_eq!(intersection.light_on(Priority::Minor), Light::GreenLight);
+        let mut major_car = CarState::new(0, -30.0, VecDeque::new(), PERFORMANCE, 0.4);
+        let mut minor_car = CarState::new(1, -30.0, VecDeque::new(), PERFORMANCE, 0.4);
+        drive(&mut major_car, &intersection, 64 * 20);
+        drive(&mut minor_car, &intersection, 64 * 20);
+        assert!(major_car.position < 0.0);
+        assert!(minor_car.position > 0.0);
+    }
+
+    #[test]
+    fn two_way_stop_minor_lanes_stop_then_wait_for_a_gap() {
+        let layout = RoadLayout::crossroads();
+        let model = ModelParameters::default();
+        let intersection = Intersection {
+            control: IntersectionControl::TwoWayStop,
+            ..intersection(&layout, &model, Light::GreenLight)
+        };
+        // Getting to the conflict point takes 6.25s from 40 away and 3.125s from 20 away
+        let breaks_with = |car: CarSnapshot, major_car: CarSnapshot| {
+            should_break(
+                &car,
+                &[car, major_car],
+                &PERFORMANCE,
+                &intersection,
+                ApproachRule::Stop,
+                TIMESTEP,
+            )
+        };
+        let far = snapshot(0, -40.0, MAX_VELOCITY);
+        let near = snapshot(0, -20.0, MAX_VELOCITY);
+        let inside = snapshot(0, 1.0, MAX_VELOCITY);
+        assert!(!breaks_with(stopped_on_the_minor_lane(Some(0.0)), far));
+        assert!(breaks_with(stopped_on_the_minor_lane(Some(0.0)), near));
+        assert!(breaks_with(stopped_on_the_minor_lane(Some(0.0)), inside));
+        // It has to have stopped first, whatever the gap
+        assert!(breaks_with(stopped_on_the_minor_lane(None), far));
+        // A stopped major car is never in the way of the gap
+        assert!(!breaks_with(
+            stopped_on_the_minor_lane(Some(0.0)),
+            snapshot(0, -20.0, 0.0)
+        ));
+    }
+
+    #[test]
+    fn gaps_are_accepted_from_the_critical_gap() {
+        let layout = RoadLayout::crossroads();
+        let breaks_with_critical_gap = |critical_gap: f32| {
+            let model = ModelParameters {
+                critical_gap,
+                ..ModelParameters::default()
+            };
+            let intersection = Intersection {
+                control: IntersectionControl::TwoWayStop,
+                ..intersection(&layout, &model, Light::GreenLight)
+            };
+            let car = stopped_on_the_minor_lane(Some(0.0));
+            // 3.125s away
+            let major_car = snapshot(0, -20.0, MAX_VELOCITY);
+            should_break(
+                &car,
+                &[car, major_car],
+                &PERFORMANCE,
+                &intersection,
+                ApproachRule::Stop,
+                TIMESTEP,
+            )
+        };
+        assert!(breaks_with_critical_gap(4.0));
+        assert!(breaks_with_critical_gap(3.2));
+        assert!(!breaks_with_critical_gap(3.1));
+        assert!(!breaks_with_critical_gap(2.0));
+    }
+
+    #[test]
+    fn all_way_stop_serves_the_cars_in_the_order_they_stopped() {
+        let layout = RoadLayout::crossroads();
+        let model = ModelParameters::default();
+        let intersection = Intersection {
+            control: IntersectionControl::AllWayStop,
+            ..intersection(&layout, &model, Light::GreenLight)
+        };
+        let stopped = |lane: usize, stopped_at: f32| CarSnapshot {
+            stopped_at_line: Some(stopped_at),
+            ..snapshot(lane, -6.05, 0.0)
+        };
+        let may_go = |car: CarSnapshot, other_car: CarSnapshot| {
+            !should_break(
+                &car,
+                &[car, other_car],
+                &PERFORMANCE,
+                &intersection,
+                ApproachRule::AllWayStop,
+                TIMESTEP,
+            )
+        };
+        assert!(may_go(stopped(1, 1.0), stopped(0, 2.0)));
+        assert!(!may_go(stopped(0, 2.0), stopped(1, 1.0)));
+        // Ties go to the lower lane
+        assert!(may_go(stopped(0, 1.0), stopped(1, 1.0)));
+        assert!(!may_go(stopped(1, 1.0), stopped(0, 1.0)));
+        // The first car waits for the intersection to clear, even when it's a later one in it
+        assert!(!may_go(stopped(1, 1.0), snapshot(0, 1.0, MAX_VELOCITY)));
+
+        // Both cars stopped, the major one first, so the minor one only goes once it has left
+        let mut major_car = CarState::new(0, -6.05, VecDeque::new(), PERFORMANCE, 0.4);
+        major_car.stopped_at_line = Some(1.0);
+        let mut minor_car = CarState::new(1, -6.05, VecDeque::new(), PERFORMANCE, 0.4);
+        minor_car.stopped_at_line = Some(2.0);
+        let mut minor_started_with_major_at = None;
+        for tick in 1..=64 * 20 {
+            let cars = [major_car.snapshot(), minor_car.snapshot()];
+            let now = 2.0 + tick as f32 * TIMESTEP;
+            major_car.step(&cars, &intersection, now, TIMESTEP);
+            minor_car.step(&cars, &intersection, now, TIMESTEP);
+            if minor_car.velocity > 0.0 && minor_started_with_major_at.is_none() {
+                minor_started_with_major_at = Some(major_car.position);
+            }
+        }
+        assert!(minor_started_with_major_at
+            .is_some_and(|position| position > model.intersection_length));
+    }
+
+    #[test]
+    fn yield_lanes_only_stop_without_a_gap() {
+        let layout = RoadLayout::crossroads();
+        let model = ModelParameters::default();
+        let intersection = Intersection {
+            control: IntersectionControl::Yield,
+            ..intersection(&layout, &model, Light::GreenLight)
+        };
+        let breaks_with = |major_car: CarSnapshot| {
+            // Moving, just close enough to the line to have to decide
+            let car = snapshot(1, -6.5, MAX_VELOCITY);
+            should_break(
+                &car,
+                &[car, major_car],
+                &PERFORMANCE,
+                &intersection,
+                ApproachRule::Yield,
+                TIMESTEP,
+            )
+        };
+        assert!(!breaks_with(snapshot(0, -40.0, MAX_VELOCITY)));
+        assert!(breaks_with(snapshot(0, -20.0, MAX_VELOCITY)));
+        // It slows down in the caution zone even when it doesn't stop
+        let mut car = CarState::new(1, -25.0, VecDeque::new(), PERFORMANCE, 0.4);
+        car.velocity = MAX_VELOCITY;
+        drive(&mut car, &intersection, 64 * 2);
+        assert_eq!(car.velocity, MAX_VELOCITY * model.caution_velocity);
+    }
+
+    #[test]
+    fn turns_onto_the_next_lane_of_its_route() {
+        let layout = RoadLayout::crossroads();
+        let model = ModelParameters::default();
+        let intersection = intersection(&layout, &model, Light::GreenLight);
+        let mut car = CarState::new(0, -20.0, VecDeque::from([1]), PERFORMANCE, 0.4);
+        car.velocity = MAX_VELOCITY;
+        drive(&mut car, &intersection, 64 * 5);
+        assert_eq!(car.lane, 1);
+        assert!(car.route.is_empty());
+        assert!(car.position > 0.0 && car.position < 20.0);
+    }
+}
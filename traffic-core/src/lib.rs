@@ -0,0 +1,14 @@
+/*!
+ * The traffic simulation without a renderer or an ECS: the road layout, the driver model, the
+ * traffic light's state machine, and a `Simulation` stepping them together.
+ * With the `bevy` feature the types the app keeps in its world are also resources and components.
+ */
+
+pub mod driver;
+pub mod road;
+pub mod signal;
+pub mod simulation;
+pub mod timer;
+pub mod validate;
+
+pub use simulation::Simulation;
@@ -0,0 +1,350 @@
+use std::{collections::VecDeque, f32::consts::TAU, str::FromStr};
+
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Priority {
+    Major,
+    Minor,
+    /**
+     * Always yields to the lanes it conflicts with, whatever the intersection's control
+     * (e.g. a roundabout's entries)
+     */
+    Yield,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LaneGeometry {
+    /**
+     * Positions are measured from `origin` in `direction`, so they're negative before the origin
+     */
+    Straight { origin: Vec3, direction: Vec3 },
+    /**
+     * A closed loop driven counter-clockwise (seen from above), positions are measured from the
+     * point in +X of `center`
+     */
+    Circle { center: Vec3, radius: f32 },
+}
+
+/**
+ * A point on another lane that this lane's cars cross or merge into at the junction
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Conflict {
+    pub lane: usize,
+    pub position: f32,
+}
+
+/**
+ * Where cars whose route continues on `lane` leave this lane: `at` this lane's position, onto
+ * `position` of `lane`
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Connection {
+    pub at: f32,
+    pub lane: usize,
+    pub position: f32,
+}
+
+/**
+ * How a lane joins a roundabout: it's the entry or the exit of a leg (numbered from 0)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoundaboutLeg {
+    Entry(usize),
+    Exit(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lane {
+    pub geometry: LaneGeometry,
+    pub priority: Priority,
+    /**
+     * Where the lane meets the junction (the stop line is a bit before it), lanes without one
+     * aren't controlled
+     */
+    #[serde(default)]
+    pub junction: Option<f32>,
+    #[serde(default)]
+    pub conflicts: Vec<Conflict>,
+    #[serde(default)]
+    pub connections: Vec<Connection>,
+    /**
+     * Set on a roundabout's entries and exits, whatever their index
+     */
+    #[serde(default)]
+    pub roundabout_leg: Option<RoundaboutLeg>,
+}
+
+/**
+ * The direction `angle` points at in the ground plane, angles grow counter-clockwise from above
+ */
+fn ground_direction(angle: f32) -> Vec3 {
+    Vec3::new(angle.cos(), 0.0, -angle.sin())
+}
+
+impl Lane {
+    pub fn straight(origin: Vec3, direction: Vec3, priority: Priority) -> Self {
+        Lane {
+            geometry: LaneGeometry::Straight { origin, direction },
+            priority,
+            junction: Some(0.0),
+            conflicts: Vec::new(),
+            connections: Vec::new(),
+            roundabout_leg: None,
+        }
+    }
+
+    pub fn position_of(&self, translation: Vec3) -> f32 {
+        match self.geometry {
+            LaneGeometry::Straight { origin, direction } => (translation - origin).dot(direction),
+            LaneGeometry::Circle { center, radius } => {
+                let offset = translation - center;
+                (-offset.z).atan2(offset.x).rem_euclid(TAU) * radius
+            }
+        }
+    }
+
+    pub fn point_at(&self, position: f32) -> Vec3 {
+        match self.geometry {
+            LaneGeometry::Straight { origin, direction } => origin + direction * position,
+            LaneGeometry::Circle { center, radius } => {
+                center + ground_direction(position / radius) * radius
+            }
+        }
+    }
+
+    /**
+     * The rotation of a car at `position`, the car's model faces +Z
+     */
+    pub fn rotation_at(&self, position: f32) -> Quat {
+        let heading = match self.geometry {
+            LaneGeometry::Straight { direction, .. } => direction,
+            LaneGeometry::Circle { radius, .. } => {
+                ground_direction(position / radius + std::f32::consts::FRAC_PI_2)
+            }
+        };
+        Quat::from_rotation_arc(Vec3::Z, heading)
+    }
+
+    /**
+     * How far ahead `to` is when driving from `from`, `None` if it's behind.
+     * On a circle everything is ahead.
+     */
+    pub fn distance_ahead(&self, from: f32, to: f32) -> Option<f32> {
+        match self.geometry {
+            LaneGeometry::Straight { .. } => Some(to - from).filter(|distance| *distance >= 0.0),
+            LaneGeometry::Circle { radius, .. } => Some((to - from).rem_euclid(TAU * radius)),
+        }
+    }
+}
+
+/**
+ * The lanes cars drive on, cars know theirs by its index
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::system::Resource))]
+pub struct RoadLayout {
+    pub lanes: Vec<Lane>,
+}
+
+impl Default for RoadLayout {
+    /**
+     * A single road along +Z, through the traffic light
+     */
+    fn default() -> Self {
+        RoadLayout {
+            lanes: vec![Lane::straight(Vec3::ZERO, Vec3::Z, Priority::Major)],
+        }
+    }
+}
+
+impl RoadLayout {
+    /**
+     * The default road crossed by a minor road along +X, cars routed onto the other road turn at
+     * the light
+     */
+    pub fn crossroads() -> Self {
+        let mut layout = RoadLayout::default();
+        layout
+            .lanes
+            .push(Lane::straight(Vec3::ZERO, Vec3::X, Priority::Minor));
+        layout.lanes[0].conflicts.push(Conflict {
+            lane: 1,
+            position: 0.0,
+        });
+        layout.lanes[1].conflicts.push(Conflict {
+            lane: 0,
+            position: 0.0,
+        });
+        for (from, to) in [(0, 1), (1, 0)] {
+            layout.lanes[from].connections.push(Connection {
+                at: 0.0,
+                lane: to,
+                position: 0.0,
+            });
+        }
+        layout
+    }
+
+    /**
+     * A single-lane roundabout around the traffic light's position with `legs` evenly spaced legs.
+     * Lane 0 is the circulating lane, then every leg has an entry lane and an exit lane.
+     * Entries yield to the circulating traffic, and each leg's exit is just before its entry.
+     */
+    pub fn roundabout(legs: usize, radius: f32) -> Self {
+        const LANE_OFFSET: f32 = 2.0;
+        let circle = Lane {
+            geometry: LaneGeometry::Circle {
+                center: Vec3::ZERO,
+                radius,
+            },
+            priority: Priority::Major,
+            junction: None,
+            conflicts: Vec::new(),
+            connections: Vec::new(),
+            roundabout_leg: None,
+        };
+        let mut layout = RoadLayout {
+            lanes: vec![circle],
+        };
+        let lane_angle_offset = (LANE_OFFSET / radius).asin();
+        for leg in 0..legs {
+            let leg_angle = TAU * leg as f32 / legs as f32;
+            let entry_angle = leg_angle + lane_angle_offset;
+            let exit_angle = leg_angle - lane_angle_offset;
+            let entry_position = entry_angle.rem_euclid(TAU) * radius;
+            let exit_position = exit_angle.rem_euclid(TAU) * radius;
+            let entry_lane = layout.lanes.len();
+            let exit_lane = entry_lane + 1;
+
+            let mut entry = Lane::straight(
+                ground_direction(entry_angle) * radius,
+                -ground_direction(leg_angle),
+                Priority::Yield,
+            );
+            entry.roundabout_leg = Some(RoundaboutLeg::Entry(leg));
+            entry.conflicts.push(Conflict {
+                lane: 0,
+                position: entry_position,
+            });
+            entry.connections.push(Connection {
+                at: 0.0,
+                lane: 0,
+                position: entry_position,
+            });
+            let mut exit = Lane::straight(
+                ground_direction(exit_angle) * radius,
+                ground_direction(leg_angle),
+                Priority::Major,
+            );
+            exit.junction = None;
+            exit.roundabout_leg = Some(RoundaboutLeg::Exit(leg));
+            layout.lanes[0].connections.push(Connection {
+                at: exit_position,
+                lane: exit_lane,
+                position: 0.0,
+            });
+            layout.lanes.push(entry);
+            layout.lanes.push(exit);
+        }
+        layout
+    }
+
+    /**
+     * Whether `lane` is the entry of a roundabout's leg, and which one
+     */
+    pub fn roundabout_leg(&self, lane: usize) -> Option<usize> {
+        match self.lanes[lane].roundabout_leg {
+            Some(RoundaboutLeg::Entry(leg)) => Some(leg),
+            _ => None,
+        }
+    }
+
+    /**
+     * The lane leaving the roundabout by `leg`
+     */
+    pub fn roundabout_exit(&self, leg: usize) -> Option<usize> {
+        self.lanes
+            .iter()
+            .position(|lane| lane.roundabout_leg == Some(RoundaboutLeg::Exit(leg)))
+    }
+
+    /**
+     * The number of legs leaving the roundabout
+     */
+    pub fn roundabout_legs(&self) -> usize {
+        self.lanes
+            .iter()
+            .filter(|lane| matches!(lane.roundabout_leg, Some(RoundaboutLeg::Exit(_))))
+            .count()
+    }
+
+    /**
+     * The lanes a car starting on `lane` drives through after it.
+     * At a roundabout the `car_index`th car of a leg takes the `car_index`th exit after its own,
+     * through the lane its entry merges into.
+     */
+    pub fn route_from(&self, lane: usize, car_index: usize) -> VecDeque<usize> {
+        let (Some(leg), Some(circulating)) = (
+            self.roundabout_leg(lane),
+            self.lanes[lane].connections.first(),
+        ) else {
+            return VecDeque::new();
+        };
+        let legs = self.roundabout_legs();
+        let exit_leg = (leg + 1 + car_index % (legs.max(2) - 1)) % legs.max(1);
+        match self.roundabout_exit(exit_leg) {
+            Some(exit) => VecDeque::from([circulating.lane, exit]),
+            None => VecDeque::new(),
+        }
+    }
+}
+
+impl FromStr for RoadLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "straight" => Ok(RoadLayout::default()),
+            "crossroads" => Ok(RoadLayout::crossroads()),
+            "roundabout" => Ok(RoadLayout::roundabout(4, 15.0)),
+            _ => Err("expected one of: straight, crossroads, roundabout".to_string()),
+        }
+    }
+}
+
+/**
+ * How the intersection is controlled. With `Signal` major lanes follow the traffic light and minor
+ * lanes get the opposite phase, the others ignore the light:
+ * - `TwoWayStop`: minor lanes stop at the line, then wait for a gap in the major lanes' traffic
+ * - `AllWayStop`: every lane stops at the line, then cars go in the order they stopped
+ * - `Yield`: minor lanes slow down and only stop if there's no gap in the major lanes' traffic
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::system::Resource))]
+pub enum IntersectionControl {
+    #[default]
+    Signal,
+    TwoWayStop,
+    AllWayStop,
+    Yield,
+}
+
+impl FromStr for IntersectionControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signal" => Ok(IntersectionControl::Signal),
+            "two-way-stop" => Ok(IntersectionControl::TwoWayStop),
+            "all-way-stop" => Ok(IntersectionControl::AllWayStop),
+            "yield" => Ok(IntersectionControl::Yield),
+            _ => Err("expected one of: signal, two-way-stop, all-way-stop, yield".to_string()),
+        }
+    }
+}
@@ -0,0 +1,570 @@
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::timer::Timer;
+
+// The lamps' names are the names of the lights' nodes in the traffic light's model
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Light {
+    RedLight,
+    GreenLight,
+    YellowLight,
+    /**
+     * Fault modes, the light stays in them until it's recovered (see `FaultSchedule`).
+     * Dark and flashing red make the intersection an all-way stop, flashing yellow means caution.
+     */
+    Dark,
+    FlashingRed,
+    FlashingYellow,
+}
+
+impl fmt::Display for Light {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Light::RedLight => write!(f, "RedLight"),
+            Light::GreenLight => write!(f, "GreenLight"),
+            Light::YellowLight => write!(f, "YellowLight"),
+            Light::Dark => write!(f, "Dark"),
+            Light::FlashingRed => write!(f, "FlashingRed"),
+            Light::FlashingYellow => write!(f, "FlashingYellow"),
+        }
+    }
+}
+
+impl Light {
+    pub fn is_fault(&self) -> bool {
+        matches!(
+            self,
+            Light::Dark | Light::FlashingRed | Light::FlashingYellow
+        )
+    }
+
+    /**
+     * The lamp that's lit (or blinking) in this light
+     */
+    pub fn lamp(&self) -> Option<Light> {
+        match self {
+            Light::RedLight | Light::FlashingRed => Some(Light::RedLight),
+            Light::GreenLight => Some(Light::GreenLight),
+            Light::YellowLight | Light::FlashingYellow => Some(Light::YellowLight),
+            Light::Dark => None,
+        }
+    }
+}
+
+/**
+ * The timers of the light being shown, all in seconds
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::component::Component))]
+pub struct LightChangeTimer {
+    go: Timer,
+    stop: Timer,
+    yellow: Timer,
+    in_light: f32,
+    #[serde(default)]
+    clearance: f32,
+    /**
+     * When an external controller asked for green during the red, the time in the red the green
+     * comes at, once the minor road has cleared
+     */
+    #[serde(default)]
+    red_ends_at: Option<f32>,
+}
+
+impl LightChangeTimer {
+    /**
+     * Seconds since the light last changed
+     */
+    pub fn time_in_light(&self) -> f32 {
+        self.in_light
+    }
+
+    /**
+     * Switches to `signal_plan`'s durations, keeping the time already spent in the current light
+     */
+    pub fn retime(&mut self, signal_plan: &SignalPlan) {
+        self.go.set_duration(signal_plan.green);
+        self.stop.set_duration(signal_plan.red);
+        self.yellow.set_duration(signal_plan.yellow);
+        self.clearance = signal_plan.clearance;
+    }
+
+    /**
+     * Starts timing a new light
+     */
+    pub fn restart(&mut self) {
+        self.go.reset();
+        self.yellow.reset();
+        self.stop.reset();
+        self.in_light = 0.0;
+        self.red_ends_at = None;
+    }
+
+    /**
+     * The light the minor road sees while the major road sees `light`. The minor road's green is
+     * inside the major road's red: after `clearance` seconds of all-red, and until its own yellow
+     * and another `clearance` of all-red before the major road's green. A faulted light is the
+     * same for both roads.
+     */
+    pub fn minor_light(&self, light: Light, signal_control: SignalControl) -> Light {
+        match light {
+            Light::RedLight => {
+                let red_ends_at = match signal_control {
+                    SignalControl::FixedTime => Some(self.stop.duration()),
+                    SignalControl::External(_) => self.red_ends_at,
+                };
+                let left = red_ends_at.map_or(f32::INFINITY, |ends_at| ends_at - self.in_light);
+                if self.in_light < self.clearance || left <= self.clearance {
+                    Light::RedLight
+                } else if left <= self.clearance + self.yellow.duration() {
+                    Light::YellowLight
+                } else {
+                    Light::GreenLight
+                }
+            }
+            Light::GreenLight | Light::YellowLight => Light::RedLight,
+            Light::Dark | Light::FlashingRed | Light::FlashingYellow => light,
+        }
+    }
+
+    /**
+     * Advances the timers of `light` by `delta` seconds, and returns the light it should change to
+     */
+    pub fn next_light(
+        &mut self,
+        light: Light,
+        signal_control: SignalControl,
+        delta: f32,
+    ) -> Option<Light> {
+        self.in_light += delta;
+
+        if let SignalControl::External(requested_light) = signal_control {
+            return match (light, requested_light) {
+                (Light::GreenLight, Light::RedLight | Light::YellowLight) => {
+                    Some(Light::YellowLight)
+                }
+                // Once asked for, the green comes after the minor road's yellow and all-red
+                (Light::RedLight, requested_light) => {
+                    if requested_light == Light::GreenLight && self.red_ends_at.is_none() {
+                        // A minor road still in its first all-red never turned green
+                        self.red_ends_at = Some(if self.in_light < self.clearance {
+                            self.clearance
+                        } else {
+                            self.in_light + self.yellow.duration() + self.clearance
+                        });
+                    }
+                    self.red_ends_at
+                        .is_some_and(|ends_at| self.in_light >= ends_at)
+                        .then_some(Light::GreenLight)
+                }
+                // Once started, the yellow light always runs its full duration
+                // (a faulted light only changes through the `FaultSchedule`)
+                (Light::YellowLight, _) => {
+                    self.yellow.tick(delta);
+                    self.yellow.finished().then_some(Light::RedLight)
+                }
+                _ => None,
+            };
+        }
+
+        match light {
+            Light::RedLight => {
+                self.stop.tick(delta);
+                self.stop.finished().then_some(Light::GreenLight)
+            }
+            Light::YellowLight => {
+                self.yellow.tick(delta);
+                self.yellow.finished().then_some(Light::RedLight)
+            }
+            Light::GreenLight => {
+                self.go.tick(delta);
+                self.go.finished().then_some(Light::YellowLight)
+            }
+            // A faulted light only changes through the `FaultSchedule`
+            Light::Dark | Light::FlashingRed | Light::FlashingYellow => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignalFault {
+    Dark,
+    FlashingRed,
+    FlashingYellow,
+}
+
+impl From<SignalFault> for Light {
+    fn from(fault: SignalFault) -> Self {
+        match fault {
+            SignalFault::Dark => Light::Dark,
+            SignalFault::FlashingRed => Light::FlashingRed,
+            SignalFault::FlashingYellow => Light::FlashingYellow,
+        }
+    }
+}
+
+/**
+ * A fault, or a recovery when `fault` is `None`, that happens `at` seconds into the simulation
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledFault {
+    #[serde(deserialize_with = "crate::validate::non_negative")]
+    pub at: f32,
+    pub fault: Option<SignalFault>,
+}
+
+/**
+ * The faults to apply to the light, ordered by time. A recovered light restarts its plan from red.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::system::Resource))]
+pub struct FaultSchedule {
+    faults: Vec<ScheduledFault>,
+    next: usize,
+    elapsed: f32,
+}
+
+impl FaultSchedule {
+    pub fn new(mut faults: Vec<ScheduledFault>) -> Self {
+        faults.sort_by(|a, b| a.at.total_cmp(&b.at));
+        FaultSchedule {
+            faults,
+            ..Default::default()
+        }
+    }
+
+    pub fn faults(&self) -> &[ScheduledFault] {
+        &self.faults
+    }
+
+    /**
+     * Seconds since the schedule started
+     */
+    pub fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+
+    /**
+     * Moves the schedule to `elapsed` seconds, the faults due by then counting as applied
+     */
+    pub fn skip_to(&mut self, elapsed: f32) {
+        self.elapsed = elapsed;
+        self.next = self.faults.partition_point(|fault| fault.at <= elapsed);
+    }
+
+    /**
+     * The fault the light is in by now, `None` before the first one or once it has recovered
+     */
+    pub fn active_fault(&self) -> Option<Light> {
+        self.faults[..self.next]
+            .last()
+            .and_then(|scheduled_fault| scheduled_fault.fault)
+            .map(Light::from)
+    }
+
+    /**
+     * Starts over from the first fault
+     */
+    pub fn restart(&mut self) {
+        self.next = 0;
+        self.elapsed = 0.0;
+    }
+
+    /**
+     * Advances the schedule by `delta` seconds, and returns the lights of the faults (and
+     * recoveries) that came due, in order
+     */
+    pub fn tick(&mut self, delta: f32) -> Vec<Light> {
+        self.elapsed += delta;
+        let mut lights = Vec::new();
+        while let Some(scheduled_fault) = self.faults.get(self.next).copied() {
+            if scheduled_fault.at > self.elapsed {
+                break;
+            }
+            self.next += 1;
+            lights.push(scheduled_fault.fault.map_or(Light::RedLight, Light::from));
+        }
+        lights
+    }
+}
+
+/**
+ * Who decides when the light changes: its own `SignalPlan` timers, or an external controller
+ * (e.g. an agent) asking for a light. A request to stop always goes through yellow first.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::system::Resource))]
+pub enum SignalControl {
+    #[default]
+    FixedTime,
+    External(Light),
+}
+
+/**
+ * The fixed-time plan the traffic light cycles through, in seconds.
+ * The cycle is Red -> Green -> Yellow -> Red, and `offset` is how far into that cycle the light
+ * starts when the simulation begins (or is reset). These are the major road's lights, the minor
+ * road's green, yellow and all-red clearances fit in the major road's red (see
+ * `LightChangeTimer::minor_light`).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "bevy", derive(bevy_ecs::system::Resource))]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalPlan {
+    #[serde(deserialize_with = "crate::validate::positive")]
+    pub green: f32,
+    #[serde(deserialize_with = "crate::validate::positive")]
+    pub yellow: f32,
+    #[serde(deserialize_with = "crate::validate::positive")]
+    pub red: f32,
+    pub offset: f32,
+    /**
+     * The all-red between one road's red and the other road's green, for the cars that entered
+     * on yellow to clear the intersection
+     */
+    #[serde(deserialize_with = "crate::validate::non_negative")]
+    pub clearance: f32,
+}
+
+impl Default for SignalPlan {
+    fn default() -> Self {
+        SignalPlan {
+            green: 10.0,
+            yellow: 1.0,
+            red: 10.0,
+            offset: 0.0,
+            clearance: 1.0,
+        }
+    }
+}
+
+impl SignalPlan {
+    pub fn cycle(&self) -> f32 {
+        self.green + self.yellow + self.red
+    }
+
+    /**
+     * Returns the light the plan is showing `offset` seconds into the cycle, along with timers
+     * that have already been ticked by the time spent in that light.
+     */
+    pub fn initial_state(&self) -> (Light, LightChangeTimer) {
+        let mut timers = LightChangeTimer {
+            go: Timer::from_seconds(self.green),
+            stop: Timer::from_seconds(self.red),
+            yellow: Timer::from_seconds(self.yellow),
+            in_light: 0.0,
+            clearance: self.clearance,
+            red_ends_at: None,
+        };
+        let mut into_cycle = self.offset.rem_euclid(self.cycle());
+        let light = if into_cycle < self.red {
+            timers.stop.tick(into_cycle);
+            Light::RedLight
+        } else if into_cycle < self.red + self.green {
+            into_cycle -= self.red;
+            timers.go.tick(into_cycle);
+            Light::GreenLight
+        } else {
+            into_cycle -= self.red + self.green;
+            timers.yellow.tick(into_cycle);
+            Light::YellowLight
+        };
+        timers.in_light = into_cycle;
+        (light, timers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTEP: f32 = 0.25;
+
+    /**
+     * Runs the light like the app does for `seconds`, and returns when it changed to which light
+     */
+    fn changes(
+        plan: SignalPlan,
+        signal_control: SignalControl,
+        mut fault_schedule: FaultSchedule,
+        seconds: f32,
+    ) -> Vec<(f32, Light)> {
+        let (mut light, mut timer) = plan.initial_state();
+        let mut changes = Vec::new();
+        for tick in 1..=(seconds / TIMESTEP) as usize {
+            let mut new_lights = Vec::from_iter(timer.next_light(light, signal_control, TIMESTEP));
+            new_lights.extend(fault_schedule.tick(TIMESTEP));
+            for new_light in new_lights {
+                if new_light != light {
+                    light = new_light;
+                    timer.restart();
+                    changes.push((tick as f32 * TIMESTEP, light));
+                }
+            }
+        }
+        changes
+    }
+
+    /**
+     * Runs the light with `signal_control(time)` for `seconds`, and returns when either road's
+     * light changed, with the major and the minor road's light
+     */
+    fn both_roads_changes(
+        plan: SignalPlan,
+        signal_control: impl Fn(f32) -> SignalControl,
+        seconds: f32,
+    ) -> Vec<(f32, Light, Light)> {
+        let (mut light, mut timer) = plan.initial_state();
+        let mut lights = (light, timer.minor_light(light, signal_control(0.0)));
+        let mut changes = Vec::new();
+        for tick in 1..=(seconds / TIMESTEP) as usize {
+            let time = tick as f32 * TIMESTEP;
+            if let Some(new_light) = timer.next_light(light, signal_control(time), TIMESTEP) {
+                light = new_light;
+                timer.restart();
+            }
+            let new_lights = (light, timer.minor_light(light, signal_control(time)));
+            if new_lights != lights {
+                lights = new_lights;
+                changes.push((time, lights.0, lights.1));
+            }
+        }
+        changes
+    }
+
+    const PLAN: SignalPlan = SignalPlan {
+        green: 2.0,
+        yellow: 1.0,
+        red: 3.0,
+        offset: 0.0,
+        clearance: 0.5,
+    };
+
+    #[test]
+    fn cycles_through_the_plan() {
+        assert_eq!(
+            changes(
+                PLAN,
+                SignalControl::FixedTime,
+                FaultSchedule::default(),
+                12.0
+            ),
+            vec![
+                (3.0, Light::GreenLight),
+                (5.0, Light::YellowLight),
+                (6.0, Light::RedLight),
+                (9.0, Light::GreenLight),
+                (11.0, Light::YellowLight),
+                (12.0, Light::RedLight),
+            ]
+        );
+    }
+
+    #[test]
+    fn starts_at_the_offset() {
+        let plan = SignalPlan {
+            offset: 4.0,
+            ..PLAN
+        };
+        let (light, timer) = plan.initial_state();
+        assert_eq!(light, Light::GreenLight);
+        assert_eq!(timer.time_in_light(), 1.0);
+        assert_eq!(
+            changes(
+                plan,
+                SignalControl::FixedTime,
+                FaultSchedule::default(),
+                2.0
+            ),
+            vec![(1.0, Light::YellowLight), (2.0, Light::RedLight)]
+        );
+    }
+
+    #[test]
+    fn holds_a_fault_and_restarts_from_red_when_recovered() {
+        let faults = FaultSchedule::new(vec![
+            ScheduledFault {
+                at: 7.0,
+                fault: None,
+            },
+            ScheduledFault {
+                at: 4.0,
+                fault: Some(SignalFault::FlashingRed),
+            },
+        ]);
+        assert_eq!(
+            changes(PLAN, SignalControl::FixedTime, faults, 12.0),
+            vec![
+                (3.0, Light::GreenLight),
+                (4.0, Light::FlashingRed),
+                (7.0, Light::RedLight),
+                (10.0, Light::GreenLight),
+                (12.0, Light::YellowLight),
+            ]
+        );
+    }
+
+    #[test]
+    fn goes_through_yellow_when_asked_to_stop() {
+        let plan = SignalPlan {
+            offset: 3.0,
+            ..PLAN
+        };
+        assert_eq!(
+            changes(
+                plan,
+                SignalControl::External(Light::RedLight),
+                FaultSchedule::default(),
+                12.0
+            ),
+            vec![(0.25, Light::YellowLight), (1.25, Light::RedLight)]
+        );
+    }
+
+    #[test]
+    fn minor_road_goes_between_the_clearances_of_the_major_red() {
+        use Light::*;
+        assert_eq!(
+            both_roads_changes(PLAN, |_| SignalControl::FixedTime, 8.75),
+            vec![
+                (0.5, RedLight, GreenLight),
+                (1.5, RedLight, YellowLight),
+                (2.5, RedLight, RedLight),
+                (3.0, GreenLight, RedLight),
+                (5.0, YellowLight, RedLight),
+                (6.0, RedLight, RedLight),
+                (6.5, RedLight, GreenLight),
+                (7.5, RedLight, YellowLight),
+                (8.5, RedLight, RedLight),
+            ]
+        );
+    }
+
+    #[test]
+    fn asking_for_green_clears_the_minor_road_first() {
+        use Light::*;
+        let green_from = |from: f32| {
+            move |time: f32| {
+                SignalControl::External(if time < from { RedLight } else { GreenLight })
+            }
+        };
+        assert_eq!(
+            both_roads_changes(PLAN, green_from(2.0), 5.0),
+            vec![
+                (0.5, RedLight, GreenLight),
+                (2.0, RedLight, YellowLight),
+                (3.0, RedLight, RedLight),
+                (3.5, GreenLight, RedLight),
+            ]
+        );
+        // Before the minor road turned green, there's only the rest of the all-red
+        assert_eq!(
+            both_roads_changes(PLAN, green_from(0.0), 5.0),
+            vec![(0.5, GreenLight, RedLight)]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    driver::{CarSnapshot, CarState, Intersection, ModelParameters},
+    road::{IntersectionControl, RoadLayout},
+    signal::{FaultSchedule, Light, LightChangeTimer, SignalControl, SignalPlan},
+};
+
+/**
+ * The cars on a road and the traffic light of its junction, advanced by `step` without any ECS.
+ * There are no sources or sinks, the cars are the ones added with `add_car`.
+ */
+pub struct Simulation {
+    pub road_layout: RoadLayout,
+    pub intersection_control: IntersectionControl,
+    pub model_parameters: ModelParameters,
+    pub signal_control: SignalControl,
+    pub fault_schedule: FaultSchedule,
+    cars: Vec<CarState>,
+    light: Light,
+    light_change_timer: LightChangeTimer,
+    elapsed: f32,
+}
+
+impl Simulation {
+    pub fn new(
+        road_layout: RoadLayout,
+        intersection_control: IntersectionControl,
+        signal_plan: SignalPlan,
+        model_parameters: ModelParameters,
+    ) -> Self {
+        let (light, light_change_timer) = signal_plan.initial_state();
+        Simulation {
+            road_layout,
+            intersection_control,
+            model_parameters,
+            signal_control: SignalControl::default(),
+            fault_schedule: FaultSchedule::default(),
+            cars: Vec::new(),
+            light,
+            light_change_timer,
+            elapsed: 0.0,
+        }
+    }
+
+    /**
+     * Returns the car's index in `cars`
+     */
+    pub fn add_car(&mut self, car: CarState) -> usize {
+        self.cars.push(car);
+        self.cars.len() - 1
+    }
+
+    pub fn cars(&self) -> &[CarState] {
+        &self.cars
+    }
+
+    pub fn light(&self) -> Light {
+        self.light
+    }
+
+    pub fn time_in_light(&self) -> f32 {
+        self.light_change_timer.time_in_light()
+    }
+
+    /**
+     * Seconds simulated so far
+     */
+    pub fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+
+    /**
+     * Advances the simulation by `delta` seconds. The light changes first, then the cars all move,
+     * seeing the new light and each other where they were, in the same order as the app's
+     * `FixedUpdate` systems.
+     * The driver model's velocities are per step, so `delta` should stay the same between steps.
+     */
+    pub fn step(&mut self, delta: f32) {
+        self.elapsed += delta;
+        let mut new_lights = Vec::from_iter(self.light_change_timer.next_light(
+            self.light,
+            self.signal_control,
+            delta,
+        ));
+        new_lights.extend(self.fault_schedule.tick(delta));
+        for new_light in new_lights {
+            if new_light != self.light {
+                self.light = new_light;
+                self.light_change_timer.restart();
+            }
+        }
+
+        let intersection = Intersection {
+            control: self.intersection_control,
+            light: self.light,
+            minor_light: self
+                .light_change_timer
+                .minor_light(self.light, self.signal_control),
+            layout: &self.road_layout,
+            model: &self.model_parameters,
+        };
+        let cars = self
+            .cars
+            .iter()
+            .map(CarState::snapshot)
+            .collect::<Vec<CarSnapshot>>();
+        for car in self.cars.iter_mut() {
+            car.step(&cars, &intersection, self.elapsed, delta);
+        }
+    }
+}
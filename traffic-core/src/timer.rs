@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+/**
+ * Counts seconds up to `duration` and stays finished once it gets there, like Bevy's one-shot
+ * `Timer`
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Timer {
+    duration: f32,
+    elapsed: f32,
+}
+
+impl Timer {
+    pub fn from_seconds(duration: f32) -> Self {
+        Timer {
+            duration,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn tick(&mut self, delta: f32) {
+        self.elapsed = (self.elapsed + delta).min(self.duration);
+    }
+
+    pub fn finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = 0.0;
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /**
+     * Keeps the time already elapsed, so the timer may be finished right away
+     */
+    pub fn set_duration(&mut self, duration: f32) {
+        self.duration = duration;
+    }
+
+    pub fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+}
@@ -0,0 +1,55 @@
+/*!
+ * Checks for `#[serde(deserialize_with = "...")]`, so a bad value is reported where it's written
+ */
+
+use serde::{de, Deserialize, Deserializer};
+
+pub fn positive<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let value = f32::deserialize(deserializer)?;
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(de::Error::custom(format!(
+            "expected a positive number, found {}",
+            value
+        )))
+    }
+}
+
+/**
+ * `positive` for an optional value, `None` is always fine
+ */
+pub fn optional_positive<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(transparent)]
+    struct Positive(#[serde(deserialize_with = "positive")] f32);
+
+    let value = Option::<Positive>::deserialize(deserializer)?;
+    Ok(value.map(|Positive(value)| value))
+}
+
+pub fn non_negative<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let value = f32::deserialize(deserializer)?;
+    if value >= 0.0 {
+        Ok(value)
+    } else {
+        Err(de::Error::custom(format!(
+            "expected a number >= 0, found {}",
+            value
+        )))
+    }
+}
+
+pub fn fraction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let value = f32::deserialize(deserializer)?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(de::Error::custom(format!(
+            "expected a number in 0..=1, found {}",
+            value
+        )))
+    }
+}